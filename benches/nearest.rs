@@ -0,0 +1,85 @@
+//! Compare finding the nearest points with a point quadtree, with
+//! scanning all the points, for 100 thousand and a million random points.
+//!
+//! Run with `cargo bench --bench nearest`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use quadtree::{Point, PointQuadTree, Region};
+
+const K: usize = 10;
+
+/// Random points in the unit square, from a xorshift generator so that
+/// every run uses the same points.
+fn random_points(count: usize, seed: u64) -> Vec<Point> {
+    let mut state = seed;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 11) as f64 / (1u64 << 53) as f64
+    };
+    (0..count).map(|_| Point::new(next(), next())).collect()
+}
+
+fn linear_nearest(points: &[Point], query: &Point) -> Option<usize> {
+    (0..points.len()).min_by(|&a, &b| {
+        points[a]
+            .distance_squared(query)
+            .total_cmp(&points[b].distance_squared(query))
+    })
+}
+
+fn linear_k_nearest(points: &[Point], query: &Point, k: usize) -> Vec<usize> {
+    let mut distances: Vec<_> = points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.distance_squared(query), i))
+        .collect();
+    let k = k.min(distances.len());
+    if k < distances.len() {
+        distances.select_nth_unstable_by(k, |a, b| a.0.total_cmp(&b.0));
+    }
+    distances.truncate(k);
+    distances.sort_by(|a, b| a.0.total_cmp(&b.0));
+    distances.into_iter().map(|(_, i)| i).collect()
+}
+
+fn nearest(c: &mut Criterion) {
+    let queries = random_points(1000, 0x2545_f491_4f6c_dd1d);
+    let mut group = c.benchmark_group("nearest");
+    // a linear scan of a million points takes milliseconds.
+    group.sample_size(20);
+    for count in [100_000, 1_000_000] {
+        let points = random_points(count, 0x9e37_79b9_7f4a_7c15);
+        let bounds = Region {
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+        };
+        let mut tree = PointQuadTree::new(bounds, 8).unwrap();
+        for (i, &point) in points.iter().enumerate() {
+            tree.insert(point, i).unwrap();
+        }
+
+        let mut query = queries.iter().cycle();
+        group.bench_with_input(BenchmarkId::new("quadtree", count), &tree, |b, tree| {
+            b.iter(|| black_box(tree.nearest(query.next().unwrap(), None)))
+        });
+        group.bench_with_input(BenchmarkId::new("linear", count), &points, |b, points| {
+            b.iter(|| black_box(linear_nearest(points, query.next().unwrap())))
+        });
+        group.bench_with_input(BenchmarkId::new("quadtree_k10", count), &tree, |b, tree| {
+            b.iter(|| black_box(tree.k_nearest(query.next().unwrap(), K, None)))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("linear_k10", count),
+            &points,
+            |b, points| b.iter(|| black_box(linear_k_nearest(points, query.next().unwrap(), K))),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, nearest);
+criterion_main!(benches);
@@ -0,0 +1,95 @@
+//! Compare computing the mean and mean squared error of image regions
+//! by copying their pixels, as the region quadtree used to do, with
+//! looking them up in a summed-area table.
+//!
+//! Run with `cargo bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use quadtree::{Image, RegionQuadTreeImage, SummedAreaTable};
+
+// The size of www/owl.jpg.
+const WIDTH: usize = 474;
+const HEIGHT: usize = 373;
+
+fn owl_sized_image_data() -> Vec<u8> {
+    let mut data = Vec::with_capacity(WIDTH * HEIGHT * 4);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            data.extend_from_slice(&[
+                (x * 255 / WIDTH) as u8,
+                (y * 255 / HEIGHT) as u8,
+                ((x * y) % 256) as u8,
+                255,
+            ]);
+        }
+    }
+    data
+}
+
+/// The regions of a full quadtree subdivision of the image, down to
+/// regions of about 8 by 8 pixels.
+fn regions() -> Vec<(usize, usize, usize, usize)> {
+    let mut regions = vec![];
+    let mut stack = vec![(0, 0, WIDTH, HEIGHT)];
+    while let Some((x, y, width, height)) = stack.pop() {
+        regions.push((x, y, width, height));
+        if width <= 8 || height <= 8 {
+            continue;
+        }
+        let (left, top) = (width / 2, height / 2);
+        stack.push((x, y, left, top));
+        stack.push((x, y + top, left, height - top));
+        stack.push((x + left, y, width - left, top));
+        stack.push((x + left, y + top, width - left, height - top));
+    }
+    regions
+}
+
+fn region_error(c: &mut Criterion) {
+    let data = owl_sized_image_data();
+    let image = Image::from_image_data(&data, WIDTH, HEIGHT).unwrap();
+    let regions = regions();
+
+    let mut group = c.benchmark_group("region_error");
+    group.bench_function("copy_pixels", |b| {
+        b.iter(|| {
+            let mut error = 0.0;
+            for &(x, y, width, height) in &regions {
+                let (_, mse) = image.copied_region_error(x, y, width, height).unwrap();
+                error += mse;
+            }
+            black_box(error)
+        })
+    });
+    group.bench_function("build_summed_area_table", |b| {
+        b.iter(|| black_box(SummedAreaTable::new(&image)))
+    });
+    // the table is built once per image, and then queried many times.
+    let table = SummedAreaTable::new(&image);
+    group.bench_function("summed_area_table", |b| {
+        b.iter(|| {
+            let mut error = 0.0;
+            for &(x, y, width, height) in &regions {
+                let mean = table.mean(x, y, width, height);
+                error += table.mse(x, y, width, height, &mean);
+            }
+            black_box(error)
+        })
+    });
+    group.finish();
+}
+
+fn subdivide_until(c: &mut Criterion) {
+    let data = owl_sized_image_data();
+    c.bench_function("subdivide_until", |b| {
+        b.iter(|| {
+            let image = Image::from_image_data(&data, WIDTH, HEIGHT).unwrap();
+            let mut tree = RegionQuadTreeImage::new(image);
+            tree.subdivide_until(black_box(100.0), 1);
+            black_box(tree.leaf_count())
+        })
+    });
+}
+
+criterion_group!(benches, region_error, subdivide_until);
+criterion_main!(benches);
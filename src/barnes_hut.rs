@@ -0,0 +1,446 @@
+//! Barnes–Hut approximation of the gravitational pull of many bodies.
+//! A quadtree over the bodies aggregates the mass and center of mass of
+//! each node from its children, like the complete quadtree aggregates
+//! pixel colors. The pull of a node that is far enough away, relative to
+//! its size, is then that of a single body with its total mass at its
+//! center of mass, which takes the cost of the pull of all bodies on all
+//! bodies from O(n^2) to O(n log n).
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::{
+    error::QuadtreeError,
+    region::{Point, Region},
+};
+
+/// Nodes this deep hold all their bodies, however many there are, so that
+/// bodies at the same position don't make the tree split forever.
+const MAX_DEPTH: usize = 32;
+
+/// A body with a position and a mass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Body {
+    pub position: Point,
+    pub mass: f64,
+}
+
+#[derive(Debug, Clone)]
+enum Contents {
+    /// The indices of the bodies in a leaf, at most one unless at MAX_DEPTH.
+    Bodies(Vec<usize>),
+    Children(Box<[Node; 4]>),
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    mass: f64,
+    center_of_mass: Point,
+    contents: Contents,
+}
+
+/// Add the pull of a body of the given mass at source on a body at point,
+/// with G = 1, to acceleration. Nothing if they are at the same position.
+fn add_pull(
+    point: &Point,
+    source: &Point,
+    mass: f64,
+    softening_squared: f64,
+    acceleration: &mut Point,
+) {
+    let (dx, dy) = (source.x - point.x, source.y - point.y);
+    let distance_squared = dx * dx + dy * dy;
+    if distance_squared == 0.0 {
+        return;
+    }
+    let scale = mass / (distance_squared + softening_squared).powf(1.5);
+    acceleration.x += dx * scale;
+    acceleration.y += dy * scale;
+}
+
+impl Node {
+    fn new(bodies: &[Body], region: &Region<f64>, depth: usize, indices: Vec<usize>) -> Node {
+        if indices.len() <= 1 || depth == MAX_DEPTH {
+            let (mass, center_of_mass) = Node::aggregate(
+                region,
+                indices
+                    .iter()
+                    .map(|&i| (bodies[i].mass, bodies[i].position)),
+            );
+            return Node {
+                mass,
+                center_of_mass,
+                contents: Contents::Bodies(indices),
+            };
+        }
+        let mut quadrant_indices: [Vec<usize>; 4] = Default::default();
+        for i in indices {
+            quadrant_indices[region.quadrant_of(&bodies[i].position)].push(i);
+        }
+        let quadrants = region.quadrants();
+        let mut quadrant_indices = quadrant_indices.into_iter();
+        let children = quadrants.map(|quadrant| {
+            let indices = quadrant_indices.next().unwrap();
+            Node::new(bodies, &quadrant, depth + 1, indices)
+        });
+        let (mass, center_of_mass) =
+            Node::aggregate(region, children.iter().map(|c| (c.mass, c.center_of_mass)));
+        Node {
+            mass,
+            center_of_mass,
+            contents: Contents::Children(Box::new(children)),
+        }
+    }
+
+    /// The total mass and the center of mass of the given masses and
+    /// positions. The center of the region if the total mass is 0.
+    fn aggregate(region: &Region<f64>, masses: impl Iterator<Item = (f64, Point)>) -> (f64, Point) {
+        let mut mass = 0.0;
+        let mut weighted = Point::new(0.0, 0.0);
+        for (m, position) in masses {
+            mass += m;
+            weighted.x += m * position.x;
+            weighted.y += m * position.y;
+        }
+        if mass == 0.0 {
+            return (mass, region.center());
+        }
+        (mass, Point::new(weighted.x / mass, weighted.y / mass))
+    }
+
+    fn pull(
+        &self,
+        tree: &BarnesHut,
+        region: &Region<f64>,
+        point: &Point,
+        theta: f64,
+        softening_squared: f64,
+        acceleration: &mut Point,
+    ) {
+        match &self.contents {
+            Contents::Bodies(indices) => {
+                for &i in indices {
+                    let body = &tree.bodies[i];
+                    add_pull(
+                        point,
+                        &body.position,
+                        body.mass,
+                        softening_squared,
+                        acceleration,
+                    );
+                }
+            }
+            Contents::Children(children) => {
+                let size = region.width.max(region.height);
+                let distance_squared = self.center_of_mass.distance_squared(point);
+                if size * size < theta * theta * distance_squared {
+                    add_pull(
+                        point,
+                        &self.center_of_mass,
+                        self.mass,
+                        softening_squared,
+                        acceleration,
+                    );
+                    return;
+                }
+                for (child, quadrant) in children.iter().zip(region.quadrants()) {
+                    child.pull(
+                        tree,
+                        &quadrant,
+                        point,
+                        theta,
+                        softening_squared,
+                        acceleration,
+                    );
+                }
+            }
+        }
+    }
+
+    fn leaf_regions(&self, region: &Region<f64>, result: &mut Vec<Region<f64>>) {
+        match &self.contents {
+            Contents::Bodies(indices) if indices.is_empty() => {}
+            Contents::Bodies(_) => result.push(region.clone()),
+            Contents::Children(children) => {
+                for (child, quadrant) in children.iter().zip(region.quadrants()) {
+                    child.leaf_regions(&quadrant, result);
+                }
+            }
+        }
+    }
+}
+
+/// A quadtree over a set of bodies, for approximating the gravitational
+/// pull of all of them on a point. Build a new one whenever the bodies move.
+#[derive(Debug, Clone)]
+pub struct BarnesHut {
+    bodies: Vec<Body>,
+    /// The smallest square that holds all the bodies.
+    bounds: Region<f64>,
+    root: Node,
+}
+
+impl BarnesHut {
+    /// Build the tree over the given bodies. An error if a position is
+    /// not finite.
+    pub fn new(bodies: Vec<Body>) -> Result<BarnesHut, QuadtreeError> {
+        let mut min = Point::new(f64::INFINITY, f64::INFINITY);
+        let mut max = Point::new(f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for Body { position: p, .. } in &bodies {
+            if !p.x.is_finite() || !p.y.is_finite() {
+                return Err(QuadtreeError::PointOutOfBounds { x: p.x, y: p.y });
+            }
+            min = Point::new(min.x.min(p.x), min.y.min(p.y));
+            max = Point::new(max.x.max(p.x), max.y.max(p.y));
+        }
+        let bounds = if bodies.is_empty() {
+            Region {
+                x: 0.0,
+                y: 0.0,
+                width: 0.0,
+                height: 0.0,
+            }
+        } else {
+            let size = (max.x - min.x).max(max.y - min.y);
+            Region {
+                x: min.x,
+                y: min.y,
+                width: size,
+                height: size,
+            }
+        };
+        let root = Node::new(&bodies, &bounds, 0, (0..bodies.len()).collect());
+        Ok(BarnesHut {
+            bodies,
+            bounds,
+            root,
+        })
+    }
+
+    pub fn bodies(&self) -> &[Body] {
+        &self.bodies
+    }
+
+    /// The smallest square that holds all the bodies.
+    pub fn bounds(&self) -> &Region<f64> {
+        &self.bounds
+    }
+
+    /// The total mass of the bodies.
+    pub fn mass(&self) -> f64 {
+        self.root.mass
+    }
+
+    /// The center of mass of the bodies.
+    pub fn center_of_mass(&self) -> Point {
+        self.root.center_of_mass
+    }
+
+    /// The acceleration of a body at the given point by the pull of all
+    /// the bodies, with a gravitational constant of 1. A node of the tree
+    /// counts as a single body if its size divided by its distance to the
+    /// point is less than theta: 0 is exact, and around 0.5 is the usual
+    /// trade-off. softening is added to every distance, which keeps the
+    /// pull of close bodies finite. Bodies at the point itself don't pull.
+    pub fn acceleration(&self, point: &Point, theta: f64, softening: f64) -> Point {
+        let mut acceleration = Point::new(0.0, 0.0);
+        self.root.pull(
+            self,
+            &self.bounds,
+            point,
+            theta,
+            softening * softening,
+            &mut acceleration,
+        );
+        acceleration
+    }
+
+    /// The acceleration of each body by the pull of the others, in the
+    /// order of bodies. See acceleration.
+    pub fn accelerations(&self, theta: f64, softening: f64) -> Vec<Point> {
+        self.bodies
+            .iter()
+            .map(|body| self.acceleration(&body.position, theta, softening))
+            .collect()
+    }
+
+    /// The regions of the leaves that hold bodies.
+    pub fn leaf_regions(&self) -> Vec<Region<f64>> {
+        let mut result = vec![];
+        self.root.leaf_regions(&self.bounds, &mut result);
+        result
+    }
+}
+
+/// A Barnes–Hut tree, exported to JavaScript as BarnesHut. Positions,
+/// accelerations and regions are flat arrays of numbers.
+#[wasm_bindgen(js_name = BarnesHut)]
+pub struct JsBarnesHut(BarnesHut);
+
+/// Public methods, exported to JavaScript.
+#[wasm_bindgen(js_class = BarnesHut)]
+impl JsBarnesHut {
+    /// Build the tree over bodies with the given x, y positions, one
+    /// pair per mass.
+    pub fn new(positions: &[f64], masses: &[f64]) -> Result<JsBarnesHut, QuadtreeError> {
+        let expected = masses.len().saturating_mul(2);
+        if positions.len() < expected {
+            return Err(QuadtreeError::BufferTooSmall {
+                expected,
+                actual: positions.len(),
+            });
+        }
+        let bodies = positions
+            .chunks_exact(2)
+            .zip(masses)
+            .map(|(p, &mass)| Body {
+                position: Point::new(p[0], p[1]),
+                mass,
+            })
+            .collect();
+        BarnesHut::new(bodies).map(JsBarnesHut)
+    }
+
+    /// The x, y acceleration of each body.
+    pub fn accelerations(&self, theta: f64, softening: f64) -> Vec<f64> {
+        self.0
+            .accelerations(theta, softening)
+            .into_iter()
+            .flat_map(|a| [a.x, a.y])
+            .collect()
+    }
+
+    /// The x, y, width and height of each leaf that holds bodies.
+    pub fn leaf_regions(&self) -> Vec<f64> {
+        self.0
+            .leaf_regions()
+            .into_iter()
+            .flat_map(|r| [r.x, r.y, r.width, r.height])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bodies at pseudo-random positions in a 100 by 100 square, with
+    /// masses from 1 to 10.
+    fn random_bodies(count: usize) -> Vec<Body> {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        (0..count)
+            .map(|_| Body {
+                position: Point::new(next() * 100.0, next() * 100.0),
+                mass: 1.0 + next() * 9.0,
+            })
+            .collect()
+    }
+
+    fn exact_accelerations(bodies: &[Body], softening: f64) -> Vec<Point> {
+        bodies
+            .iter()
+            .map(|body| {
+                let mut acceleration = Point::new(0.0, 0.0);
+                for other in bodies {
+                    add_pull(
+                        &body.position,
+                        &other.position,
+                        other.mass,
+                        softening * softening,
+                        &mut acceleration,
+                    );
+                }
+                acceleration
+            })
+            .collect()
+    }
+
+    fn length(p: &Point) -> f64 {
+        p.distance_squared(&Point::new(0.0, 0.0)).sqrt()
+    }
+
+    #[test]
+    fn aggregates() {
+        let bodies = vec![
+            Body {
+                position: Point::new(0.0, 0.0),
+                mass: 1.0,
+            },
+            Body {
+                position: Point::new(4.0, 0.0),
+                mass: 3.0,
+            },
+            Body {
+                position: Point::new(4.0, 2.0),
+                mass: 4.0,
+            },
+        ];
+        let tree = BarnesHut::new(bodies).unwrap();
+        assert_eq!(tree.mass(), 8.0);
+        assert_eq!(tree.center_of_mass(), Point::new(3.5, 1.0));
+        assert_eq!(tree.bounds().width, 4.0);
+        assert_eq!(tree.leaf_regions().len(), 3);
+    }
+
+    #[test]
+    fn theta_zero_is_exact() {
+        let bodies = random_bodies(200);
+        let tree = BarnesHut::new(bodies.clone()).unwrap();
+        let expected = exact_accelerations(&bodies, 0.1);
+        for (a, e) in tree.accelerations(0.0, 0.1).iter().zip(&expected) {
+            assert!(length(&Point::new(a.x - e.x, a.y - e.y)) <= 1e-9 * length(e));
+        }
+    }
+
+    #[test]
+    fn theta_approximates() {
+        let bodies = random_bodies(1000);
+        let tree = BarnesHut::new(bodies.clone()).unwrap();
+        let expected = exact_accelerations(&bodies, 1.0);
+        let relative_error = |theta| {
+            let mut error = 0.0;
+            let mut total = 0.0;
+            for (a, e) in tree.accelerations(theta, 1.0).iter().zip(&expected) {
+                error += length(&Point::new(a.x - e.x, a.y - e.y));
+                total += length(e);
+            }
+            error / total
+        };
+        let (coarse, fine) = (relative_error(0.5), relative_error(0.25));
+        assert!(coarse < 0.02, "relative error {coarse}");
+        assert!(fine < coarse);
+    }
+
+    #[test]
+    fn same_position() {
+        let body = Body {
+            position: Point::new(1.0, 1.0),
+            mass: 2.0,
+        };
+        let tree = BarnesHut::new(vec![body; 10]).unwrap();
+        assert_eq!(tree.mass(), 20.0);
+        assert_eq!(tree.accelerations(0.5, 0.0), vec![Point::new(0.0, 0.0); 10]);
+        let a = tree.acceleration(&Point::new(3.0, 1.0), 0.5, 0.0);
+        assert_eq!(a, Point::new(-5.0, 0.0));
+    }
+
+    #[test]
+    fn empty_and_invalid() {
+        let tree = BarnesHut::new(vec![]).unwrap();
+        assert_eq!(tree.mass(), 0.0);
+        assert_eq!(
+            tree.acceleration(&Point::new(1.0, 1.0), 0.5, 0.0),
+            Point::new(0.0, 0.0)
+        );
+        let invalid = Body {
+            position: Point::new(f64::NAN, 0.0),
+            mass: 1.0,
+        };
+        assert!(BarnesHut::new(vec![invalid]).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! Command line tool to compress and render images with quadtrees,
+//! outside of the browser. Build with `cargo build --release --features cli`.
+
+use std::{error::Error, fs, path::Path};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use quadtree::{
+    encode_apng, Image, ImageFormat, LeafModel, QuadTree, QuadtreeError, RegionQuadTreeImage, Rgba,
+    SplitStrategy, Stroke, SvgOptions, METRIC_NAMES, SPLIT_STRATEGY_NAMES,
+};
+
+#[derive(Parser)]
+#[command(about = "Compress and render images with quadtrees")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Subdivide an image with a region quadtree, and write the quadtree
+    /// in the compact binary format.
+    Compress {
+        input: String,
+        output: String,
+        #[command(flatten)]
+        subdivision: Subdivision,
+        /// Bits per color channel, from 1 to 8. Less than 8 is lossy.
+        #[arg(long, default_value_t = 8)]
+        color_bits: u8,
+        /// Delta code the leaf colors.
+        #[arg(long)]
+        delta: bool,
+    },
+    /// Render a quadtree written by compress to an image.
+    Decompress {
+        input: String,
+        output: String,
+        #[command(flatten)]
+        format: OutputFormat,
+    },
+    /// Subdivide an image with a region quadtree, and render the result.
+    Render {
+        input: String,
+        output: String,
+        #[command(flatten)]
+        subdivision: Subdivision,
+        #[command(flatten)]
+        format: OutputFormat,
+    },
+    /// Subdivide an image with a region quadtree, and write the partition
+    /// as an SVG document with a rectangle per leaf.
+    Svg {
+        input: String,
+        output: String,
+        #[command(flatten)]
+        subdivision: Subdivision,
+        /// Outline the leaves in black, with lines this wide.
+        #[arg(long, default_value_t = 0.0)]
+        stroke_width: f64,
+        /// Leave out leaves whose width and height are both less than this.
+        #[arg(long, default_value_t = 0)]
+        min_size: usize,
+    },
+    /// Subdivide an image with a region quadtree, and write an animated PNG
+    /// of the image being refined: a frame after each pass over the leaves,
+    /// or after a number of splits with --max-leaves.
+    Animate {
+        input: String,
+        output: String,
+        #[command(flatten)]
+        subdivision: Subdivision,
+        /// How long to show each frame, in milliseconds.
+        #[arg(long, default_value_t = 500)]
+        delay: u16,
+        /// With --max-leaves, the number of splits between frames.
+        #[arg(long, default_value_t = 16)]
+        splits_per_frame: usize,
+    },
+    /// Render a level of the complete quadtree of an image.
+    RenderLevel {
+        input: String,
+        output: String,
+        /// The level to render, 0 being the root.
+        #[arg(long)]
+        level: u32,
+        #[command(flatten)]
+        format: OutputFormat,
+    },
+    /// Subdivide an image with a region quadtree, and print statistics.
+    Stats {
+        input: String,
+        #[command(flatten)]
+        subdivision: Subdivision,
+    },
+}
+
+#[derive(Args)]
+struct Subdivision {
+    /// Split regions with an error above this threshold.
+    #[arg(long, default_value_t = 500.0)]
+    error: f32,
+    /// Don't cut across a width or height of at most this length: regions
+    /// with such a side aren't split into quadrants, only into halves.
+    #[arg(long, default_value_t = 1)]
+    min_length: usize,
+    /// The error metric.
+    #[arg(long, default_value = "mse", value_parser = METRIC_NAMES)]
+    metric: String,
+    /// Instead of splitting on the error threshold, split the leaf with the
+    /// largest error until there are this many leaves.
+    #[arg(long)]
+    max_leaves: Option<usize>,
+    /// Approximate each leaf by a linear gradient instead of a flat color.
+    #[arg(long)]
+    linear: bool,
+    /// Where to split leaves: into equal quadrants, quadrants cut where the
+    /// colors change, or two halves.
+    #[arg(long, default_value = "midpoint", value_parser = SPLIT_STRATEGY_NAMES)]
+    split: String,
+}
+
+impl Subdivision {
+    /// A region quadtree of the image, not yet subdivided, with the error
+    /// metric, leaf model and split strategy of the options.
+    fn tree(&self, image: Image) -> Result<RegionQuadTreeImage, QuadtreeError> {
+        let mut tree = RegionQuadTreeImage::new(image);
+        tree.set_error_metric(&self.metric)?;
+        if self.linear {
+            tree.set_leaf_model(LeafModel::Linear);
+        }
+        let strategy = SplitStrategy::by_name(&self.split)
+            .ok_or_else(|| QuadtreeError::UnknownSplitStrategy(self.split.clone()))?;
+        tree.set_split_strategy(strategy);
+        Ok(tree)
+    }
+
+    fn subdivide(&self, image: Image) -> Result<RegionQuadTreeImage, QuadtreeError> {
+        let mut tree = self.tree(image)?;
+        match self.max_leaves {
+            Some(max_leaves) => tree.subdivide_to_budget(max_leaves, 0.0, self.min_length),
+            None => tree.subdivide_until(self.error, self.min_length),
+        }
+        Ok(tree)
+    }
+
+    /// Subdivide like subdivide, and return the result image before and
+    /// during subdividing. See `RegionQuadTreeImage::subdivide_until_frames`.
+    fn subdivide_frames(
+        &self,
+        image: Image,
+        splits_per_frame: usize,
+    ) -> Result<Vec<Image>, QuadtreeError> {
+        let mut tree = self.tree(image)?;
+        Ok(match self.max_leaves {
+            Some(max_leaves) => {
+                tree.subdivide_to_budget_frames(max_leaves, 0.0, self.min_length, splits_per_frame)
+            }
+            None => tree.subdivide_until_frames(self.error, self.min_length),
+        })
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Png,
+    Ppm,
+    Pam,
+    Qoi,
+}
+
+#[derive(Args)]
+struct OutputFormat {
+    /// The format of the output image. By default, derived from
+    /// the extension of the output file.
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+}
+
+fn read_image(path: &str) -> Result<Image, Box<dyn Error>> {
+    Ok(Image::decode(&fs::read(path)?)?)
+}
+
+fn write_image(image: &Image, path: &str, format: &OutputFormat) -> Result<(), Box<dyn Error>> {
+    let format = match format.format {
+        Some(Format::Png) => ImageFormat::Png,
+        Some(Format::Ppm) => ImageFormat::Ppm,
+        Some(Format::Pam) => ImageFormat::Pam,
+        Some(Format::Qoi) => ImageFormat::Qoi,
+        None => Path::new(path)
+            .extension()
+            .and_then(|e| ImageFormat::from_extension(&e.to_string_lossy()))
+            .ok_or_else(|| format!("can't derive the image format of {path}, use --format"))?,
+    };
+    fs::write(path, image.encode(format)?)?;
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    match Cli::parse().command {
+        Command::Compress {
+            input,
+            output,
+            subdivision,
+            color_bits,
+            delta,
+        } => {
+            let tree = subdivision.subdivide(read_image(&input)?)?;
+            fs::write(&output, tree.to_bytes(color_bits, delta)?)?;
+        }
+        Command::Decompress {
+            input,
+            output,
+            format,
+        } => {
+            let bytes = fs::read(&input)?;
+            let tree =
+                RegionQuadTreeImage::from_bytes(&bytes).map_err(|e| format!("{input}: {e}"))?;
+            write_image(&tree.get_result_image(), &output, &format)?;
+        }
+        Command::Render {
+            input,
+            output,
+            subdivision,
+            format,
+        } => {
+            let tree = subdivision.subdivide(read_image(&input)?)?;
+            write_image(&tree.get_result_image(), &output, &format)?;
+        }
+        Command::Svg {
+            input,
+            output,
+            subdivision,
+            stroke_width,
+            min_size,
+        } => {
+            let tree = subdivision.subdivide(read_image(&input)?)?;
+            let stroke = (stroke_width > 0.0).then_some(Stroke {
+                color: Rgba {
+                    red: 0,
+                    green: 0,
+                    blue: 0,
+                    alpha: 255,
+                },
+                width: stroke_width,
+            });
+            fs::write(&output, tree.to_svg(&SvgOptions { stroke, min_size }))?;
+        }
+        Command::Animate {
+            input,
+            output,
+            subdivision,
+            delay,
+            splits_per_frame,
+        } => {
+            let frames = subdivision.subdivide_frames(read_image(&input)?, splits_per_frame)?;
+            fs::write(&output, encode_apng(&frames, delay)?)?;
+        }
+        Command::RenderLevel {
+            input,
+            output,
+            level,
+            format,
+        } => {
+            let tree = QuadTree::new(&read_image(&input)?);
+            write_image(&tree.image_at_level(level)?, &output, &format)?;
+        }
+        Command::Stats { input, subdivision } => {
+            let image = read_image(&input)?;
+            let (width, height) = (image.width(), image.height());
+            let tree = subdivision.subdivide(image)?;
+            let name = Path::new(&input).file_name().unwrap_or_default();
+            println!("image:          {}", name.to_string_lossy());
+            println!("size:           {width}x{height}");
+            println!("leaves:         {}", tree.leaf_count());
+            println!("total error:    {}", tree.total_error());
+            println!("raw bytes:      {}", width * height * 4);
+            println!("encoded bytes:  {}", tree.to_bytes(8, false)?.len());
+            println!("delta bytes:    {}", tree.to_bytes(8, true)?.len());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,227 @@
+//! Decoding and encoding of image files, so the quadtrees can be used
+//! outside of the browser. Uses the `image` crate, behind the `codecs` feature.
+
+use std::io::Cursor;
+
+use ::image::{
+    codecs::{
+        png::PngEncoder,
+        pnm::{PnmEncoder, PnmSubtype, SampleEncoding},
+        qoi::QoiEncoder,
+    },
+    ExtendedColorType, ImageEncoder, ImageFormat as Codec,
+};
+
+use crate::{error::QuadtreeError, image::Image};
+
+/// The formats `Image::encode` can write. `Image::decode` reads all of them,
+/// as well as JPEG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    /// Binary PPM. Has no alpha channel, so alpha is dropped.
+    Ppm,
+    /// PAM, the arbitrary map variant of PNM, with alpha.
+    Pam,
+    Qoi,
+}
+
+impl ImageFormat {
+    /// The format with the given file extension, if any.
+    pub fn from_extension(extension: &str) -> Option<ImageFormat> {
+        match extension.to_ascii_lowercase().as_str() {
+            "png" => Some(ImageFormat::Png),
+            "ppm" => Some(ImageFormat::Ppm),
+            "pam" => Some(ImageFormat::Pam),
+            "qoi" => Some(ImageFormat::Qoi),
+            _ => None,
+        }
+    }
+}
+
+impl From<::image::ImageError> for QuadtreeError {
+    fn from(error: ::image::ImageError) -> Self {
+        QuadtreeError::Codec(error.to_string())
+    }
+}
+
+impl Image {
+    /// Decode a PNG, PPM, PAM, QOI or JPEG file. The format is
+    /// derived from the contents.
+    pub fn decode(bytes: &[u8]) -> Result<Image, QuadtreeError> {
+        let rgba = ::image::load_from_memory(bytes)?.to_rgba8();
+        let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+        Image::from_image_data(rgba.as_raw(), width, height)
+    }
+
+    /// Encode this image in the given format.
+    pub fn encode(&self, format: ImageFormat) -> Result<Vec<u8>, QuadtreeError> {
+        let (width, height) = (self.width() as u32, self.height() as u32);
+        let mut rgba = vec![0; self.width() * self.height() * 4];
+        self.to_image_data(&mut rgba)?;
+
+        let mut bytes = Cursor::new(vec![]);
+        match format {
+            ImageFormat::Png => PngEncoder::new(&mut bytes).write_image(
+                &rgba,
+                width,
+                height,
+                ExtendedColorType::Rgba8,
+            )?,
+            ImageFormat::Ppm => {
+                let rgb: Vec<u8> = rgba
+                    .chunks_exact(4)
+                    .flat_map(|p| [p[0], p[1], p[2]])
+                    .collect();
+                PnmEncoder::new(&mut bytes)
+                    .with_subtype(PnmSubtype::Pixmap(SampleEncoding::Binary))
+                    .write_image(&rgb, width, height, ExtendedColorType::Rgb8)?
+            }
+            ImageFormat::Pam => PnmEncoder::new(&mut bytes)
+                .with_subtype(PnmSubtype::ArbitraryMap)
+                .write_image(&rgba, width, height, ExtendedColorType::Rgba8)?,
+            ImageFormat::Qoi => QoiEncoder::new(&mut bytes).write_image(
+                &rgba,
+                width,
+                height,
+                ExtendedColorType::Rgba8,
+            )?,
+        }
+        Ok(bytes.into_inner())
+    }
+}
+
+impl From<png::EncodingError> for QuadtreeError {
+    fn from(error: png::EncodingError) -> Self {
+        QuadtreeError::Codec(error.to_string())
+    }
+}
+
+/// Encode the frames as an animated PNG that loops forever, showing each
+/// frame for the given number of milliseconds. An error if there are no
+/// frames, or they are not all the same size as the first.
+pub fn encode_apng(frames: &[Image], frame_delay_ms: u16) -> Result<Vec<u8>, QuadtreeError> {
+    let first = frames
+        .first()
+        .ok_or_else(|| QuadtreeError::Codec("an animation needs frames".to_string()))?;
+    let (width, height) = (first.width(), first.height());
+    if let Some(index) = frames
+        .iter()
+        .position(|f| f.width() != width || f.height() != height)
+    {
+        return Err(QuadtreeError::Codec(format!(
+            "frame {index} is not the same size as the first frame"
+        )));
+    }
+
+    let mut bytes = vec![];
+    let mut encoder = png::Encoder::new(&mut bytes, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0)?;
+    encoder.set_frame_delay(frame_delay_ms, 1000)?;
+    let mut writer = encoder.write_header()?;
+    let mut rgba = vec![0; width * height * 4];
+    for frame in frames {
+        frame.to_image_data(&mut rgba)?;
+        writer.write_image_data(&rgba)?;
+    }
+    writer.finish()?;
+    Ok(bytes)
+}
+
+impl From<ImageFormat> for Codec {
+    fn from(format: ImageFormat) -> Self {
+        match format {
+            ImageFormat::Png => Codec::Png,
+            ImageFormat::Ppm | ImageFormat::Pam => Codec::Pnm,
+            ImageFormat::Qoi => Codec::Qoi,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Rgba;
+
+    fn test_image(alpha: bool) -> Image {
+        let mut pixels = vec![];
+        for i in 0..35u8 {
+            pixels.push(Rgba {
+                red: i * 7,
+                green: 255 - i,
+                blue: i % 3 * 100,
+                alpha: if alpha { 255 - i * 5 } else { 255 },
+            });
+        }
+        Image::new(7, 5, pixels)
+    }
+
+    #[test]
+    fn roundtrip() {
+        for format in [ImageFormat::Png, ImageFormat::Pam, ImageFormat::Qoi] {
+            let image = test_image(true);
+            let bytes = image.encode(format).unwrap();
+            assert_eq!(::image::guess_format(&bytes).unwrap(), format.into());
+            assert_eq!(Image::decode(&bytes).unwrap(), image, "{format:?}");
+        }
+
+        let image = test_image(false);
+        let bytes = image.encode(ImageFormat::Ppm).unwrap();
+        assert!(bytes.starts_with(b"P6"));
+        assert_eq!(Image::decode(&bytes).unwrap(), image);
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(Image::decode(&[]).is_err());
+        assert!(Image::decode(b"not an image").is_err());
+        for format in [ImageFormat::Png, ImageFormat::Ppm, ImageFormat::Qoi] {
+            let bytes = test_image(false).encode(format).unwrap();
+            let error = Image::decode(&bytes[..bytes.len() / 2]).unwrap_err();
+            assert!(!error.to_string().is_empty());
+        }
+        assert!(Image::decode(b"P6\n100000 100000\n255\n").is_err());
+    }
+
+    #[test]
+    fn apng() {
+        let mut second = test_image(true);
+        second.set_index(3, 2, Rgba::new());
+        let frames = [test_image(true), second.clone(), test_image(false)];
+        let bytes = encode_apng(&frames, 250).unwrap();
+        // viewers without animation support show the first frame.
+        assert_eq!(Image::decode(&bytes).unwrap(), frames[0]);
+
+        let mut reader = png::Decoder::new(Cursor::new(&bytes)).read_info().unwrap();
+        let control = reader.info().animation_control.unwrap();
+        assert_eq!((control.num_frames, control.num_plays), (3, 0));
+        let mut rgba = vec![0; reader.output_buffer_size().unwrap()];
+        for frame in &frames {
+            reader.next_frame(&mut rgba).unwrap();
+            let delay = reader.info().frame_control.unwrap();
+            assert_eq!((delay.delay_num, delay.delay_den), (250, 1000));
+            assert_eq!(&Image::from_image_data(&rgba, 7, 5).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn apng_invalid() {
+        assert!(encode_apng(&[], 100).is_err());
+        let frames = [test_image(true), Image::new(1, 1, vec![Rgba::new()])];
+        assert_eq!(
+            encode_apng(&frames, 100),
+            Err(QuadtreeError::Codec(
+                "frame 1 is not the same size as the first frame".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn extensions() {
+        assert_eq!(ImageFormat::from_extension("PNG"), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::from_extension("pam"), Some(ImageFormat::Pam));
+        assert_eq!(ImageFormat::from_extension("jpg"), None);
+    }
+}
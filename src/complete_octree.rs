@@ -0,0 +1,242 @@
+//! The 3D counterpart of the complete quad tree: every node averages the
+//! voxels of a cube, and has up to eight children that each average an
+//! octant. The tree is stored level by level, root first. Each level is a
+//! grid of the cubes that overlap the volume, so that a flat volume, say
+//! 512x512x64, doesn't take the memory of a 512x512x512 cube.
+
+use crate::{
+    error::QuadtreeError,
+    image::Image,
+    pixel::{self, Luma, Pixel},
+    volume::Volume,
+};
+
+/// A node in the octree. Describes the region it is applicable to by its
+/// first and last voxel, both inclusive, and the mean color of that region
+/// in data. The tree is built over a cube with a power of 2 edge, so a
+/// node at the far edges of the volume covers only the part of its cube
+/// inside the volume: voxel_count is the number of voxels it covers.
+#[derive(Debug, Clone)]
+struct OctreeNode<P: Pixel> {
+    first: [usize; 3],
+    last: [usize; 3],
+    data: P,
+    voxel_count: usize,
+}
+
+impl<P: Pixel> OctreeNode<P> {
+    /// A node consisting of a single voxel.
+    fn voxel(volume: &Volume<P>, [x, y, z]: [usize; 3]) -> OctreeNode<P> {
+        OctreeNode {
+            first: [x, y, z],
+            last: [x, y, z],
+            data: volume.get_voxel(x, y, z),
+            voxel_count: 1,
+        }
+    }
+
+    /// A node covering the given children, with the mean of their colors
+    /// weighted by the number of voxels.
+    fn branch(children: &[&OctreeNode<P>]) -> OctreeNode<P> {
+        let weighted: Vec<_> = children.iter().map(|n| (n.data, n.voxel_count)).collect();
+        OctreeNode {
+            first: [0, 1, 2].map(|axis| children.iter().map(|n| n.first[axis]).min().unwrap()),
+            last: [0, 1, 2].map(|axis| children.iter().map(|n| n.last[axis]).max().unwrap()),
+            data: pixel::weighted_mean(&weighted),
+            voxel_count: children.iter().map(|n| n.voxel_count).sum(),
+        }
+    }
+}
+
+/// The nodes at one level of the octree: a grid of lengths[0] by lengths[1]
+/// by lengths[2] nodes, x first.
+#[derive(Debug, Clone)]
+struct OctreeLevel<P: Pixel> {
+    lengths: [usize; 3],
+    nodes: Vec<OctreeNode<P>>,
+}
+
+impl<P: Pixel> OctreeLevel<P> {
+    /// The node at the given position in the grid, if the grid has it.
+    fn get(&self, [x, y, z]: [usize; 3]) -> Option<&OctreeNode<P>> {
+        let [width, height, depth] = self.lengths;
+        (x < width && y < height && z < depth).then(|| &self.nodes[x + width * (y + height * z)])
+    }
+
+    /// The level above this one, with a node for each group of up to
+    /// eight nodes of this level.
+    fn parent(&self) -> OctreeLevel<P> {
+        let lengths = self.lengths.map(|length| length.div_ceil(2));
+        let mut nodes = Vec::with_capacity(lengths.iter().product());
+        for z in 0..lengths[2] {
+            for y in 0..lengths[1] {
+                for x in 0..lengths[0] {
+                    let children: Vec<_> = (0..8)
+                        .filter_map(|octant| {
+                            self.get([
+                                2 * x + (octant >> 1 & 1),
+                                2 * y + (octant & 1),
+                                2 * z + (octant >> 2 & 1),
+                            ])
+                        })
+                        .collect();
+                    nodes.push(OctreeNode::branch(&children));
+                }
+            }
+        }
+        OctreeLevel { lengths, nodes }
+    }
+}
+
+/// A complete octree over a volume of any size.
+#[derive(Debug, Clone)]
+pub struct Octree<P: Pixel = Luma<u16>> {
+    levels: Vec<OctreeLevel<P>>,
+}
+
+impl<P: Pixel> Octree<P> {
+    /// Create a new complete octree from a volume of any size. Leaf nodes
+    /// contain a single voxel each.
+    pub fn new(volume: &Volume<P>) -> Octree<P> {
+        let lengths = [volume.width(), volume.height(), volume.depth()];
+        let mut nodes = Vec::with_capacity(lengths.iter().product());
+        for z in 0..lengths[2] {
+            for y in 0..lengths[1] {
+                for x in 0..lengths[0] {
+                    nodes.push(OctreeNode::voxel(volume, [x, y, z]));
+                }
+            }
+        }
+        let mut level = OctreeLevel { lengths, nodes };
+        let mut levels = vec![];
+        while level.lengths.iter().any(|&length| length > 1) {
+            let parent = level.parent();
+            levels.push(level);
+            level = parent;
+        }
+        levels.push(level);
+        levels.reverse();
+        Octree { levels }
+    }
+
+    /// The depth of the tree: the level of the leaves, where the root is at
+    /// level 0.
+    pub fn depth(&self) -> u32 {
+        self.levels.len() as u32 - 1
+    }
+
+    /// The nodes at the given level, or an error if the level is deeper
+    /// than the tree.
+    fn level(&self, level: u32) -> Result<&[OctreeNode<P>], QuadtreeError> {
+        match self.levels.get(level as usize) {
+            Some(nodes) => Ok(&nodes.nodes),
+            None => Err(QuadtreeError::LevelOutOfRange {
+                level,
+                depth: self.depth(),
+            }),
+        }
+    }
+
+    /// Return a new "voxelated" volume, constructed from the regions at the
+    /// given level in the tree. An error if the level is deeper than the tree.
+    pub fn volume_at_level(&self, level: u32) -> Result<Volume<P>, QuadtreeError> {
+        let nodes = self.level(level)?;
+        let [width, height, depth] = self.levels.last().unwrap().lengths;
+        let mut volume = Volume::empty(width, height, depth);
+        for node in nodes {
+            for z in node.first[2]..=node.last[2] {
+                for y in node.first[1]..=node.last[1] {
+                    for x in node.first[0]..=node.last[0] {
+                        volume.set_index(x, y, z, node.data);
+                    }
+                }
+            }
+        }
+        Ok(volume)
+    }
+
+    /// The slice at the given z of `volume_at_level`, only rendering the
+    /// nodes that intersect it. An error if the level is deeper than the
+    /// tree, or z is past the back of the volume.
+    pub fn slice_at_level(&self, level: u32, z: usize) -> Result<Image<P>, QuadtreeError> {
+        let nodes = self.level(level)?;
+        let [width, height, depth] = self.levels.last().unwrap().lengths;
+        if z >= depth {
+            return Err(QuadtreeError::SliceOutOfRange { z, depth });
+        }
+        let mut image = Image::empty(width, height);
+        for node in nodes
+            .iter()
+            .filter(|n| (n.first[2]..=n.last[2]).contains(&z))
+        {
+            for y in node.first[1]..=node.last[1] {
+                for x in node.first[0]..=node.last[0] {
+                    image.set_index(x, y, node.data);
+                }
+            }
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_volume(width: usize, height: usize, depth: usize) -> Volume<Luma<u8>> {
+        let voxels = (0..width * height * depth)
+            .map(|i| Luma((i * 37 % 256) as u8))
+            .collect();
+        Volume::from_voxels(width, height, depth, voxels).unwrap()
+    }
+
+    #[test]
+    fn levels() {
+        let volume = test_volume(5, 3, 2);
+        let octree = Octree::new(&volume);
+        assert_eq!(octree.depth(), 3);
+        let node_count: usize = octree.levels.iter().map(|l| l.nodes.len()).sum();
+        assert_eq!(node_count, 1 + 2 + 3 * 2 + 5 * 3 * 2);
+        // the deepest level is the volume itself, the root is its mean.
+        assert_eq!(octree.volume_at_level(3), Ok(volume.clone()));
+        let mean = Luma::mean(volume.voxels()).unwrap();
+        let root = octree.volume_at_level(0).unwrap();
+        assert!(root.voxels().iter().all(|&v| v == mean));
+        for level in 0..=3 {
+            let expected = octree.volume_at_level(level).unwrap();
+            for z in 0..2 {
+                assert_eq!(octree.slice_at_level(level, z), expected.slice(z));
+            }
+        }
+    }
+
+    #[test]
+    fn flat() {
+        // only the cubes that overlap the volume have nodes, not all of
+        // those in a 64x64x64 cube.
+        let volume = test_volume(64, 64, 2);
+        let octree = Octree::new(&volume);
+        assert_eq!(octree.depth(), 6);
+        let node_count: usize = octree.levels.iter().map(|l| l.nodes.len()).sum();
+        assert_eq!(node_count, 1 + 4 + 16 + 64 + 256 + 1024 + 64 * 64 * 2);
+        assert_eq!(octree.volume_at_level(6), Ok(volume.clone()));
+        let mean = Luma::mean(volume.voxels()).unwrap();
+        let root = octree.volume_at_level(0).unwrap();
+        assert!(root.voxels().iter().all(|&v| v == mean));
+        let level = octree.volume_at_level(5).unwrap();
+        assert_eq!(octree.slice_at_level(5, 1), level.slice(1));
+    }
+
+    #[test]
+    fn out_of_range() {
+        let octree = Octree::new(&test_volume(2, 2, 2));
+        assert_eq!(
+            octree.volume_at_level(2),
+            Err(QuadtreeError::LevelOutOfRange { level: 2, depth: 1 })
+        );
+        assert_eq!(
+            octree.slice_at_level(0, 2),
+            Err(QuadtreeError::SliceOutOfRange { z: 2, depth: 2 })
+        );
+    }
+}
@@ -1,122 +1,275 @@
+use std::collections::BTreeSet;
+
 use wasm_bindgen::prelude::wasm_bindgen;
 
-use crate::image::{Image, Rgba};
+use crate::{
+    error::{check_rgba_len, QuadtreeError},
+    image::{Image, JsImage, Rgba},
+    morton,
+    overlay::{self, OverlayOptions},
+    pixel::{self, Pixel},
+    region::Region,
+    region_quad_tree::LeafInfo,
+};
 
 /// A coordinate in the image.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Coordinate {
-    x: usize,
-    y: usize,
+    pub(crate) x: usize,
+    pub(crate) y: usize,
 }
 
 /// A node in the quad tree. Describes the region
 /// it is applicable to by top_left and bottom_right
 /// coordinates, both inclusive.
-/// Describes the color of its region in data.
+/// Describes the color of its region in data, and the
+/// channel-wise minimum and maximum color in its region in min and max.
+/// The tree is built over a square with a power of 2 side, so
+/// nodes can fall partially outside of the image. pixel_count
+/// is the number of image pixels the node actually covers.
 #[derive(Debug, Clone)]
-pub struct QuadTreeNode {
+pub struct QuadTreeNode<P: Pixel> {
     top_left: Coordinate,
     bottom_right: Coordinate,
-    data: Rgba,
+    data: P,
+    min: P,
+    max: P,
+    pixel_count: usize,
 }
 
-/// A complete quad tree is stored in a vector.
+/// A complete quad tree is stored in a vector, level by level,
+/// and in Morton order within each level. See the morton module.
+/// Only the nodes that cover part of the image are stored, so
+/// a thin image doesn't take the memory of the square around it:
+/// indices holds the Morton index of each of them.
 #[derive(Debug, Clone)]
-#[wasm_bindgen]
-pub struct QuadTree {
-    nodes: Vec<QuadTreeNode>,
+pub struct QuadTree<P: Pixel = Rgba> {
+    indices: Vec<usize>,
+    nodes: Vec<QuadTreeNode<P>>,
+    depth: u32,
 }
 
-impl QuadTreeNode {
-    fn empty() -> QuadTreeNode {
+impl<P: Pixel> QuadTreeNode<P> {
+    /// A node consisting of a single pixel with the given color.
+    fn leaf(x: usize, y: usize, data: P) -> QuadTreeNode<P> {
         QuadTreeNode {
-            top_left: Coordinate { x: 0, y: 0 },
-            bottom_right: Coordinate { x: 0, y: 0 },
-            data: Rgba::new(),
+            top_left: Coordinate { x, y },
+            bottom_right: Coordinate { x, y },
+            data,
+            min: data,
+            max: data,
+            pixel_count: 1,
         }
     }
 
-    /// A node consisting of a single pixel.
-    fn pixel(image: &Image, x: usize, y: usize) -> QuadTreeNode {
+    /// A node covering the given children, with the mean of their colors
+    /// weighted by the number of pixels.
+    fn branch(covered: &[&QuadTreeNode<P>]) -> QuadTreeNode<P> {
+        let weighted: Vec<_> = covered.iter().map(|n| (n.data, n.pixel_count)).collect();
         QuadTreeNode {
-            top_left: Coordinate { x, y },
-            bottom_right: Coordinate { x, y },
-            data: image.get_pixel(x, y),
+            top_left: Coordinate {
+                x: covered.iter().map(|n| n.top_left.x).min().unwrap(),
+                y: covered.iter().map(|n| n.top_left.y).min().unwrap(),
+            },
+            bottom_right: Coordinate {
+                x: covered.iter().map(|n| n.bottom_right.x).max().unwrap(),
+                y: covered.iter().map(|n| n.bottom_right.y).max().unwrap(),
+            },
+            data: pixel::weighted_mean(&weighted),
+            min: covered
+                .iter()
+                .fold(covered[0].min, |acc, n| pixel::min(&acc, &n.min)),
+            max: covered
+                .iter()
+                .fold(covered[0].max, |acc, n| pixel::max(&acc, &n.max)),
+            pixel_count: covered.iter().map(|n| n.pixel_count).sum(),
         }
     }
-}
 
-impl QuadTree {
-    /// Given the size of the image, returns the capacity of the vec necessary
-    /// to store the nodes.
-    fn get_tree_size(image_size: usize) -> (usize, usize) {
-        let mut level_size = 1;
-        let mut total_size = 1;
-        while level_size < image_size {
-            level_size *= 4;
-            total_size += level_size;
+    fn contains(&self, x: usize, y: usize) -> bool {
+        (self.top_left.x..=self.bottom_right.x).contains(&x)
+            && (self.top_left.y..=self.bottom_right.y).contains(&y)
+    }
+
+    /// Whether the region of this node overlaps the given rectangle.
+    fn intersects(&self, x: usize, y: usize, width: usize, height: usize) -> bool {
+        width > 0
+            && height > 0
+            && self.top_left.x < x.saturating_add(width)
+            && x <= self.bottom_right.x
+            && self.top_left.y < y.saturating_add(height)
+            && y <= self.bottom_right.y
+    }
+
+    /// The region of this node, within the image.
+    fn region(&self) -> Region {
+        Region {
+            x: self.top_left.x,
+            y: self.top_left.y,
+            width: self.bottom_right.x - self.top_left.x + 1,
+            height: self.bottom_right.y - self.top_left.y + 1,
         }
-        (total_size, level_size)
+    }
+
+    /// Whether all the pixels in the region of this node are within
+    /// the given tolerance of each other, in every channel.
+    fn is_uniform(&self, tolerance: f64) -> bool {
+        (0..P::CHANNELS).all(|c| self.max.channel(c) - self.min.channel(c) <= tolerance)
     }
 }
 
-/// Public methods, exported to JavaScript.
-#[wasm_bindgen]
-impl QuadTree {
-    /// Create a new complete quad tree from an image. Leaf nodes contain a single
-    /// pixel each.
-    pub fn new(image: &Image) -> QuadTree {
-        let image_size = image.get_size();
-        let (tree_size, last_level_size) = QuadTree::get_tree_size(image_size);
-        let mut tree_vec = Vec::with_capacity(tree_size);
-        tree_vec.resize(tree_size, QuadTreeNode::empty());
-
-        // fill the leaf nodes in the back of the array. Order is z-order.
-        let mut count = 0;
-        for i in (0..image.width() - 1).step_by(2).rev() {
-            for j in (0..image.height() - 1).step_by(2).rev() {
-                let branch_index = tree_size - count;
-                tree_vec[branch_index - 4] = QuadTreeNode::pixel(image, i, j);
-                tree_vec[branch_index - 3] = QuadTreeNode::pixel(image, i, j + 1);
-                tree_vec[branch_index - 2] = QuadTreeNode::pixel(image, i + 1, j);
-                tree_vec[branch_index - 1] = QuadTreeNode::pixel(image, i + 1, j + 1);
-                count += 4;
+impl<P: Pixel> QuadTree<P> {
+    /// The node with the given Morton index, if it covers part of the image.
+    fn get(&self, index: usize) -> Option<&QuadTreeNode<P>> {
+        let position = self.indices.binary_search(&index).ok()?;
+        Some(&self.nodes[position])
+    }
+
+    /// The Morton indices of the children of the node with the given index
+    /// that cover part of the image.
+    fn children(&self, index: usize) -> impl DoubleEndedIterator<Item = usize> + '_ {
+        morton::children(index).filter(|&c| self.get(c).is_some())
+    }
+
+    /// The index of the node at the given level that contains the given
+    /// point, found by walking down from the root.
+    /// None if the point is outside of the image.
+    fn node_at(&self, x: usize, y: usize, level: u32) -> Option<usize> {
+        if level > self.depth() || !self.nodes[0].contains(x, y) {
+            return None;
+        }
+        let mut index = 0;
+        for _ in 0..level {
+            index = self
+                .children(index)
+                .find(|&c| self.node_contains(c, x, y))?;
+        }
+        Some(index)
+    }
+
+    /// Whether the node with the given index contains the pixel at (x, y).
+    fn node_contains(&self, index: usize, x: usize, y: usize) -> bool {
+        self.get(index).is_some_and(|n| n.contains(x, y))
+    }
+
+    /// Replace the node with the given index, which covers part of the image.
+    fn set_node(&mut self, index: usize, node: QuadTreeNode<P>) {
+        let position = self.indices.binary_search(&index).unwrap();
+        self.nodes[position] = node;
+    }
+
+    /// Replace the leaf containing the pixel at (x, y), without updating
+    /// its ancestors. Returns the index of the leaf.
+    fn set_leaf(&mut self, x: usize, y: usize, pixel: P) -> usize {
+        let index = morton::index(Coordinate { x, y }, self.depth());
+        self.set_node(index, QuadTreeNode::leaf(x, y, pixel));
+        index
+    }
+
+    /// The node at the given index, or an error if there is no such node
+    /// or it is outside of the image.
+    fn node(&self, index: usize) -> Result<&QuadTreeNode<P>, QuadtreeError> {
+        self.get(index).ok_or(QuadtreeError::NodeOutOfRange {
+            index,
+            len: morton::level_offset(self.depth + 1),
+        })
+    }
+
+    /// Re-average all the ancestors of the given nodes. Shared ancestors
+    /// are only re-averaged once, after all of their changed descendants.
+    fn update_ancestors(&mut self, indices: impl IntoIterator<Item = usize>) {
+        let mut ancestors = BTreeSet::new();
+        for index in indices {
+            let mut current = index;
+            while let Some(parent) = morton::parent(current) {
+                if !ancestors.insert(parent) {
+                    break;
+                }
+                current = parent;
             }
         }
+        // parents have a lower index than their children.
+        for &index in ancestors.iter().rev() {
+            let children: Vec<_> = self.children(index).map(|c| self.get(c).unwrap()).collect();
+            let branch = QuadTreeNode::branch(&children);
+            self.set_node(index, branch);
+        }
+    }
 
-        // fill the branch nodes back to front.
-        for i in (0..tree_size - last_level_size).rev() {
-            let chunk: Vec<_> = tree_vec[4 * i + 1..=4 * i + 4]
-                .iter()
-                .map(|n| n.data)
+    /// Change the color of the given pixels, as (x, y, color), and re-average
+    /// the regions that contain them. An error if any of the pixels is outside
+    /// of the image, in which case the tree is unchanged.
+    pub fn set_pixels(&mut self, pixels: &[(usize, usize, P)]) -> Result<(), QuadtreeError> {
+        if let Some(&(x, y, _)) = pixels
+            .iter()
+            .find(|&&(x, y, _)| !self.nodes[0].contains(x, y))
+        {
+            return Err(QuadtreeError::PixelOutOfBounds { x, y });
+        }
+        let leaves: Vec<_> = pixels
+            .iter()
+            .map(|&(x, y, pixel)| self.set_leaf(x, y, pixel))
+            .collect();
+        self.update_ancestors(leaves);
+        Ok(())
+    }
+
+    /// Create a new complete quad tree from an image of any size. Leaf nodes
+    /// contain a single pixel each.
+    pub fn new(image: &Image<P>) -> QuadTree<P> {
+        let (width, height) = (image.width(), image.height());
+        let mut depth = 0;
+        while 1 << depth < width.max(height) {
+            depth += 1;
+        }
+
+        // the leaves, and then each level above them, as Morton codes within
+        // the level and nodes, in Morton order.
+        let mut codes: Vec<_> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| morton::encode(Coordinate { x, y })))
+            .collect();
+        codes.sort_unstable();
+        let leaves = codes.into_iter().map(|code| {
+            let Coordinate { x, y } = morton::decode(code);
+            (code, QuadTreeNode::leaf(x, y, image.get_pixel(x, y)))
+        });
+        let mut levels = vec![leaves.collect::<Vec<_>>()];
+        for _ in 0..depth {
+            let children = levels.last().unwrap();
+            let parents = children
+                .chunk_by(|(a, _), (b, _)| a >> 2 == b >> 2)
+                .map(|siblings| {
+                    let nodes: Vec<_> = siblings.iter().map(|(_, node)| node).collect();
+                    (siblings[0].0 >> 2, QuadTreeNode::branch(&nodes))
+                })
                 .collect();
-            let s = Rgba::mean(&chunk);
-            tree_vec[i] = QuadTreeNode {
-                top_left: tree_vec[4 * i + 1].top_left,
-                bottom_right: tree_vec[4 * i + 4].bottom_right,
-                data: s,
-            };
+            levels.push(parents);
         }
 
-        QuadTree { nodes: tree_vec }
+        let mut tree = QuadTree {
+            indices: vec![],
+            nodes: vec![],
+            depth,
+        };
+        for (level, nodes) in levels.into_iter().rev().enumerate() {
+            let offset = morton::level_offset(level as u32);
+            for (code, node) in nodes {
+                tree.indices.push(offset + code);
+                tree.nodes.push(node);
+            }
+        }
+        tree
     }
 
     /// Return a new "pixelated" image, constructed from the regions at the
-    /// given level in the tree.
-    pub fn image_at_level(&self, level: u32) -> Image {
+    /// given level in the tree. An error if the level is deeper than the tree.
+    pub fn image_at_level(&self, level: u32) -> Result<Image<P>, QuadtreeError> {
+        let nodes = self.level(level)?;
         let width = self.nodes[0].bottom_right.x - self.nodes[0].top_left.x + 1;
         let height = self.nodes[0].bottom_right.y - self.nodes[0].top_left.y + 1;
         let mut image = Image::empty(width, height);
 
-        let mut index = 0;
-        let mut level_size = 1;
-        for _ in 0..level {
-            index += level_size;
-            level_size *= 4;
-        }
-
-        for i in index..index + level_size {
-            let node = &self.nodes[i];
+        for node in nodes {
             for x in node.top_left.x..=node.bottom_right.x {
                 for y in node.top_left.y..=node.bottom_right.y {
                     image.set_index(x, y, node.data);
@@ -124,20 +277,336 @@ impl QuadTree {
             }
         }
 
-        image
+        Ok(image)
+    }
+
+    /// The nodes at the given level, or an error if the level is deeper
+    /// than the tree.
+    fn level(&self, level: u32) -> Result<&[QuadTreeNode<P>], QuadtreeError> {
+        if level > self.depth() {
+            return Err(QuadtreeError::LevelOutOfRange {
+                level,
+                depth: self.depth(),
+            });
+        }
+        let start = self
+            .indices
+            .partition_point(|&i| i < morton::level_offset(level));
+        let end = self
+            .indices
+            .partition_point(|&i| i < morton::level_offset(level + 1));
+        Ok(&self.nodes[start..end])
+    }
+
+    /// The region and averaged color of each node at the given level that
+    /// covers part of the image, in Morton order. An error if the level is
+    /// deeper than the tree.
+    pub fn level_nodes(
+        &self,
+        level: u32,
+    ) -> Result<impl Iterator<Item = (Region, P)> + '_, QuadtreeError> {
+        Ok(self.level(level)?.iter().map(|n| (n.region(), n.data)))
+    }
+
+    /// The number of nodes that cover part of the image, at all levels.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The number of levels below the root. Level depth holds
+    /// the single pixel leaves.
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// The averaged color of the region at the given level that
+    /// contains the pixel at (x, y), or undefined if the pixel is outside
+    /// of the image or the level is deeper than the tree.
+    pub fn color_at(&self, x: usize, y: usize, level: u32) -> Option<P> {
+        self.node_at(x, y, level).map(|i| self.get(i).unwrap().data)
+    }
+
+    /// The indices of all the nodes at the given level whose region
+    /// overlaps the given rectangle, in Morton order.
+    pub fn nodes_in_rect(
+        &self,
+        level: u32,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Vec<usize> {
+        let mut result = vec![];
+        if level > self.depth() {
+            return result;
+        }
+        let mut stack = vec![(0, 0)];
+        while let Some((index, node_level)) = stack.pop() {
+            if !self
+                .get(index)
+                .is_some_and(|n| n.intersects(x, y, width, height))
+            {
+                continue;
+            }
+            if node_level == level {
+                result.push(index);
+            } else {
+                stack.extend(self.children(index).rev().map(|c| (c, node_level + 1)));
+            }
+        }
+        result
+    }
+
+    /// The index of the largest node containing the pixel at (x, y) whose
+    /// pixels are all within tolerance of each other in every channel,
+    /// or undefined if the pixel is outside of the image.
+    pub fn uniform_node_at(&self, x: usize, y: usize, tolerance: f64) -> Option<usize> {
+        if !self.nodes[0].contains(x, y) {
+            return None;
+        }
+        let mut index = 0;
+        while !self.get(index).unwrap().is_uniform(tolerance) {
+            index = self
+                .children(index)
+                .find(|&c| self.node_contains(c, x, y))?;
+        }
+        Some(index)
+    }
+
+    /// Change the color of the pixel at (x, y), and re-average the regions
+    /// that contain it. An error if the pixel is outside of the image.
+    pub fn set_pixel(&mut self, x: usize, y: usize, pixel: P) -> Result<(), QuadtreeError> {
+        self.set_pixels(&[(x, y, pixel)])
+    }
+
+    /// The averaged color of the node at the given index.
+    pub fn node_color(&self, index: usize) -> Result<P, QuadtreeError> {
+        Ok(self.node(index)?.data)
+    }
+
+    /// The region of the node at the given index, as x, y, width and height.
+    pub fn node_region(&self, index: usize) -> Result<Vec<usize>, QuadtreeError> {
+        let Region {
+            x,
+            y,
+            width,
+            height,
+        } = self.node(index)?.region();
+        Ok(vec![x, y, width, height])
+    }
+}
+
+impl QuadTree {
+    /// Change the color of the pixels at the given coordinates, given as
+    /// x, y pairs, to the colors in image_data_rgba, in the same order.
+    /// An error if there are fewer colors than coordinates, or any of the
+    /// pixels is outside of the image.
+    pub fn set_pixels_rgba(
+        &mut self,
+        coordinates: &[usize],
+        image_data_rgba: &[u8],
+    ) -> Result<(), QuadtreeError> {
+        check_rgba_len(coordinates.len() / 2, image_data_rgba)?;
+        let pixels: Vec<_> = coordinates
+            .chunks_exact(2)
+            .zip(image_data_rgba.chunks_exact(4))
+            .map(|(c, p)| (c[0], c[1], Rgba::from_slice(p)))
+            .collect();
+        self.set_pixels(&pixels)
+    }
+
+    /// Change the color of the pixels in the given rectangle to the colors in
+    /// image_data_rgba, which is laid out like the data of a canvas ImageData
+    /// of the rectangle's width and height. An error if the data is too short,
+    /// or the rectangle is not inside the image.
+    pub fn update_region(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        image_data_rgba: &[u8],
+    ) -> Result<(), QuadtreeError> {
+        if x.checked_add(width).is_none() || y.checked_add(height).is_none() {
+            return Err(QuadtreeError::RegionOutOfBounds {
+                x,
+                y,
+                width,
+                height,
+            });
+        }
+        check_rgba_len(width.saturating_mul(height), image_data_rgba)?;
+        let mut pixels = Vec::with_capacity(width * height);
+        for j in 0..height {
+            for i in 0..width {
+                let index = (j * width + i) * 4;
+                let pixel = Rgba::from_slice(&image_data_rgba[index..index + 4]);
+                pixels.push((x + i, y + j, pixel));
+            }
+        }
+        self.set_pixels(&pixels)
+    }
+
+    /// A debug rendering of the largest nodes whose pixels are all within
+    /// tolerance of each other in every channel, as the leaves of a region
+    /// quadtree would be. The depth of a leaf is its level, and its error
+    /// the largest difference between two of its pixels in any channel.
+    pub fn overlay_image(&self, tolerance: f64, options: &OverlayOptions) -> Image {
+        let mut leaves = vec![];
+        let mut stack = vec![(0, 0)];
+        while let Some((index, level)) = stack.pop() {
+            let node = self.get(index).unwrap();
+            if !node.is_uniform(tolerance) {
+                stack.extend(self.children(index).rev().map(|c| (c, level + 1)));
+                continue;
+            }
+            leaves.push(LeafInfo {
+                region: node.region(),
+                mean: node.data,
+                depth: level,
+                error: (0..Rgba::CHANNELS)
+                    .map(|c| node.max.channel(c) - node.min.channel(c))
+                    .fold(0.0, f64::max) as f32,
+            });
+        }
+        let root = &self.nodes[0];
+        overlay::render(
+            root.bottom_right.x + 1,
+            root.bottom_right.y + 1,
+            &leaves,
+            options,
+        )
+    }
+}
+
+/// A complete quad tree of an RGBA image, exported to JavaScript as QuadTree.
+#[wasm_bindgen(js_name = QuadTree)]
+pub struct JsQuadTree(QuadTree);
+
+/// Public methods, exported to JavaScript.
+#[wasm_bindgen(js_class = QuadTree)]
+impl JsQuadTree {
+    pub fn new(image: &JsImage) -> JsQuadTree {
+        JsQuadTree(QuadTree::new(&image.0))
+    }
+
+    pub fn image_at_level(&self, level: u32) -> Result<JsImage, QuadtreeError> {
+        self.0.image_at_level(level).map(JsImage)
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.0.depth()
+    }
+
+    pub fn color_at(&self, x: usize, y: usize, level: u32) -> Option<Rgba> {
+        self.0.color_at(x, y, level)
+    }
+
+    pub fn nodes_in_rect(
+        &self,
+        level: u32,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Vec<usize> {
+        self.0.nodes_in_rect(level, x, y, width, height)
+    }
+
+    pub fn uniform_node_at(&self, x: usize, y: usize, tolerance: u8) -> Option<usize> {
+        self.0.uniform_node_at(x, y, tolerance as f64)
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, pixel: Rgba) -> Result<(), QuadtreeError> {
+        self.0.set_pixel(x, y, pixel)
+    }
+
+    pub fn set_pixels_rgba(
+        &mut self,
+        coordinates: &[usize],
+        image_data_rgba: &[u8],
+    ) -> Result<(), QuadtreeError> {
+        self.0.set_pixels_rgba(coordinates, image_data_rgba)
+    }
+
+    pub fn update_region(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        image_data_rgba: &[u8],
+    ) -> Result<(), QuadtreeError> {
+        self.0.update_region(x, y, width, height, image_data_rgba)
+    }
+
+    pub fn node_color(&self, index: usize) -> Result<Rgba, QuadtreeError> {
+        self.0.node_color(index)
+    }
+
+    pub fn node_region(&self, index: usize) -> Result<Vec<usize>, QuadtreeError> {
+        self.0.node_region(index)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.0.node_count()
+    }
+
+    /// The nodes at the given level that cover part of the image, in Morton
+    /// order, as five numbers each: x, y, width, height and the averaged
+    /// color packed as 0xRRGGBBAA.
+    pub fn level_nodes(&self, level: u32) -> Result<Vec<u32>, QuadtreeError> {
+        Ok(self
+            .0
+            .level_nodes(level)?
+            .flat_map(|(region, color)| {
+                [region.x, region.y, region.width, region.height]
+                    .map(|n| n as u32)
+                    .into_iter()
+                    .chain([color.to_u32()])
+            })
+            .collect())
+    }
+
+    /// A debug rendering of the largest nodes that are uniform within
+    /// tolerance, colored by "mean", "depth" or "error", and outlined in the
+    /// given RGBA color if border_thickness is greater than 0.
+    pub fn overlay_image(
+        &self,
+        tolerance: u8,
+        coloring: &str,
+        border_rgba: &[u8],
+        border_thickness: usize,
+    ) -> Result<JsImage, QuadtreeError> {
+        let options = OverlayOptions::from_js(coloring, border_rgba, border_thickness)?;
+        Ok(JsImage(self.0.overlay_image(tolerance as f64, &options)))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pixel::Luma;
 
     #[test]
     fn tree_size() {
-        assert_eq!(QuadTree::get_tree_size(1), (1, 1));
-        assert_eq!(QuadTree::get_tree_size(4), (5, 4));
-        assert_eq!(QuadTree::get_tree_size(9), (21, 16));
-        assert_eq!(QuadTree::get_tree_size(16), (21, 16));
+        // (width, height, depth, nodes that cover part of the image).
+        for (width, height, depth, node_count) in [
+            (1, 1, 0, 1),
+            (2, 2, 1, 5),
+            (3, 3, 2, 9 + 4 + 1),
+            (4, 4, 2, 21),
+            (2, 8, 3, 16 + 4 + 2 + 1),
+            (5, 1, 3, 5 + 3 + 2 + 1),
+            // a thin image only has the nodes along it, not the 22 million
+            // of the 4096 by 4096 square around it.
+            (4096, 1, 12, 2 * 4096 - 1),
+        ] {
+            let tree = QuadTree::new(&test_image(width, height));
+            assert_eq!(tree.depth(), depth);
+            assert_eq!(tree.node_count(), node_count);
+            assert_eq!(tree.nodes.len(), node_count);
+        }
     }
 
     fn test_image_4x4() -> Image {
@@ -179,7 +648,7 @@ mod tests {
         let image = test_image_4x4();
         let tree = QuadTree::new(&image);
 
-        let level_image = tree.image_at_level(0);
+        let level_image = tree.image_at_level(0).unwrap();
         assert_eq!(level_image.width(), 4);
         assert_eq!(level_image.height(), 4);
         for x in 0..4 {
@@ -196,7 +665,7 @@ mod tests {
             }
         }
 
-        let level_image = tree.image_at_level(1);
+        let level_image = tree.image_at_level(1).unwrap();
         for x in 0..2 {
             for y in 0..2 {
                 assert_eq!(
@@ -247,11 +716,397 @@ mod tests {
         }
     }
 
+    fn test_image(width: usize, height: usize) -> Image {
+        let mut pixels = vec![];
+        for i in 0..width * height {
+            let c = (i % 251) as u8;
+            pixels.push(Rgba {
+                red: c,
+                green: c,
+                blue: c,
+                alpha: 255,
+            });
+        }
+
+        Image::new(width, height, pixels)
+    }
+
+    #[test]
+    fn arbitrary_size() {
+        for (width, height) in [(1, 1), (3, 5), (7, 2), (10, 10), (37, 13)] {
+            let image = test_image(width, height);
+            let tree = QuadTree::new(&image);
+            assert_eq!(tree.nodes[0].top_left, Coordinate { x: 0, y: 0 });
+            assert_eq!(
+                tree.nodes[0].bottom_right,
+                Coordinate {
+                    x: width - 1,
+                    y: height - 1
+                }
+            );
+            assert_eq!(tree.nodes[0].pixel_count, width * height);
+
+            let mut depth = 0;
+            while (1 << depth) < width.max(height) {
+                depth += 1;
+            }
+            let level_image = tree.image_at_level(depth).unwrap();
+            assert_eq!(level_image.width(), width);
+            assert_eq!(level_image.height(), height);
+            for x in 0..width {
+                for y in 0..height {
+                    assert_eq!(level_image.get_pixel(x, y), image.get_pixel(x, y));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn partial_nodes_weighted_mean() {
+        // 3x1 image: the leaf level is 4x4, of which only 3 leaves are in the image.
+        let image = Image::new(
+            3,
+            1,
+            vec![
+                Rgba {
+                    red: 30,
+                    green: 30,
+                    blue: 30,
+                    alpha: 30,
+                },
+                Rgba {
+                    red: 60,
+                    green: 60,
+                    blue: 60,
+                    alpha: 60,
+                },
+                Rgba {
+                    red: 90,
+                    green: 90,
+                    blue: 90,
+                    alpha: 90,
+                },
+            ],
+        );
+        let tree = QuadTree::new(&image);
+        assert_eq!(
+            tree.nodes[0].data,
+            Rgba {
+                red: 60,
+                green: 60,
+                blue: 60,
+                alpha: 60
+            }
+        );
+        let level_image = tree.image_at_level(0).unwrap();
+        assert_eq!(level_image.width(), 3);
+        assert_eq!(level_image.height(), 1);
+    }
+
+    /// Check that every branch node covers exactly its four children,
+    /// which are its top left, bottom left, top right and bottom right quadrant.
+    fn assert_children_cover_quadrants(tree: &QuadTree) {
+        for (i, node) in tree.nodes.iter().enumerate() {
+            let children = morton::children(i);
+            if *children.end() >= tree.nodes.len() {
+                assert_eq!(node.top_left, node.bottom_right);
+                continue;
+            }
+            let side = node.bottom_right.x - node.top_left.x + 1;
+            let half = side / 2;
+            assert_eq!(node.bottom_right.y - node.top_left.y + 1, side);
+            for (quadrant, child) in children.enumerate() {
+                let child = &tree.nodes[child];
+                let top_left = Coordinate {
+                    x: node.top_left.x + (quadrant >> 1) * half,
+                    y: node.top_left.y + (quadrant & 1) * half,
+                };
+                assert_eq!(child.top_left, top_left);
+                assert_eq!(
+                    child.bottom_right,
+                    Coordinate {
+                        x: top_left.x + half - 1,
+                        y: top_left.y + half - 1
+                    }
+                );
+            }
+            let pixel_count: usize = morton::children(i).map(|c| tree.nodes[c].pixel_count).sum();
+            assert_eq!(node.pixel_count, pixel_count);
+        }
+    }
+
+    #[test]
+    fn morton_order_covers_children() {
+        for side in [8, 16, 256] {
+            let tree = QuadTree::new(&test_image(side, side));
+            assert_children_cover_quadrants(&tree);
+        }
+    }
+
+    #[test]
+    fn morton_order_leaves() {
+        let image = test_image(16, 16);
+        let tree = QuadTree::new(&image);
+        for x in 0..16 {
+            for y in 0..16 {
+                let leaf = &tree.nodes[morton::index(Coordinate { x, y }, 4)];
+                assert_eq!(leaf.top_left, Coordinate { x, y });
+                assert_eq!(leaf.data, image.get_pixel(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn point_query() {
+        let image = test_image_4x4();
+        let tree = QuadTree::new(&image);
+        assert_eq!(tree.depth(), 2);
+        assert_eq!(tree.color_at(3, 1, 0).unwrap().red, 8);
+        assert_eq!(tree.color_at(3, 1, 1).unwrap().red, 5);
+        assert_eq!(tree.color_at(3, 1, 2), Some(image.get_pixel(3, 1)));
+        assert_eq!(tree.color_at(4, 1, 0), None);
+        assert_eq!(tree.color_at(0, 0, 3), None);
+
+        let image = test_image(5, 3);
+        let tree = QuadTree::new(&image);
+        assert_eq!(tree.depth(), 3);
+        for x in 0..5 {
+            for y in 0..3 {
+                assert_eq!(tree.color_at(x, y, 3), Some(image.get_pixel(x, y)));
+            }
+        }
+        assert_eq!(tree.color_at(5, 0, 3), None);
+    }
+
+    #[test]
+    fn rect_query() {
+        let tree = QuadTree::new(&test_image(8, 8));
+        assert_eq!(tree.nodes_in_rect(0, 7, 7, 1, 1), vec![0]);
+        assert_eq!(tree.nodes_in_rect(1, 0, 0, 8, 8), vec![1, 2, 3, 4]);
+        assert_eq!(tree.nodes_in_rect(1, 3, 3, 2, 1), vec![1, 3]);
+        assert_eq!(tree.nodes_in_rect(1, 3, 3, 0, 1), Vec::<usize>::new());
+        assert_eq!(tree.nodes_in_rect(2, 8, 0, 4, 4), Vec::<usize>::new());
+        assert_eq!(tree.nodes_in_rect(0, 7, 7, usize::MAX, usize::MAX), vec![0]);
+
+        let nodes = tree.nodes_in_rect(3, 2, 1, 3, 2);
+        assert_eq!(nodes.len(), 6);
+        for index in nodes {
+            let region = tree.node_region(index).unwrap();
+            assert_eq!(region[2..], [1, 1]);
+            assert!((2..5).contains(&region[0]));
+            assert!((1..3).contains(&region[1]));
+        }
+    }
+
+    #[test]
+    fn uniform_query() {
+        let mut pixels = vec![Rgba::new(); 16];
+        pixels[15] = Rgba {
+            red: 10,
+            green: 0,
+            blue: 0,
+            alpha: 0,
+        };
+        let tree = QuadTree::new(&Image::new(4, 4, pixels));
+        assert_eq!(tree.uniform_node_at(0, 0, 0.0), Some(1));
+        assert_eq!(tree.uniform_node_at(2, 2, 0.0), Some(17));
+        assert_eq!(tree.uniform_node_at(3, 3, 0.0), Some(20));
+        assert_eq!(tree.uniform_node_at(3, 3, 10.0), Some(0));
+        assert_eq!(tree.uniform_node_at(4, 0, 10.0), None);
+        assert_eq!(tree.node_region(17), Ok(vec![2, 2, 1, 1]));
+    }
+
+    #[test]
+    fn level_nodes() {
+        let image = test_image(37, 13);
+        let tree = QuadTree::new(&image);
+        let mut node_count = 0;
+        for level in 0..=tree.depth() {
+            let nodes: Vec<_> = tree.level_nodes(level).unwrap().collect();
+            node_count += nodes.len();
+            // the nodes at every level cover the image exactly once.
+            let area: usize = nodes.iter().map(|(r, _)| r.width * r.height).sum();
+            assert_eq!(area, 37 * 13);
+            for (region, color) in nodes {
+                assert_eq!(tree.color_at(region.x, region.y, level), Some(color));
+            }
+        }
+        assert_eq!(tree.node_count(), node_count);
+        assert!(matches!(
+            tree.level_nodes(tree.depth() + 1),
+            Err(QuadtreeError::LevelOutOfRange { .. })
+        ));
+
+        let color = tree.color_at(0, 0, 1).unwrap();
+        let flat = JsQuadTree(tree).level_nodes(1).unwrap();
+        assert_eq!(flat.len(), 5 * 2);
+        assert_eq!(flat[..4], [0, 0, 32, 13]);
+        assert_eq!(
+            flat[4],
+            u32::from_be_bytes([color.red, color.green, color.blue, color.alpha])
+        );
+    }
+
+    #[test]
+    fn overlay() {
+        let mut pixels = vec![Rgba::new(); 16];
+        pixels[15] = Rgba {
+            red: 10,
+            green: 0,
+            blue: 0,
+            alpha: 0,
+        };
+        pixels[0].red = 4;
+        let tree = QuadTree::new(&Image::new(4, 4, pixels));
+        let options = OverlayOptions::default();
+        assert_eq!(
+            tree.overlay_image(0.0, &options),
+            tree.image_at_level(2).unwrap()
+        );
+        assert_eq!(
+            tree.overlay_image(10.0, &options),
+            tree.image_at_level(0).unwrap()
+        );
+
+        // two uniform quadrants at level 1, and eight pixels at level 2.
+        let depth = OverlayOptions {
+            border: None,
+            coloring: overlay::LeafColoring::Depth,
+        };
+        let image = tree.overlay_image(0.0, &depth);
+        assert_eq!(image.get_pixel(2, 0), Rgba::from_slice(&[0, 255, 0, 255]));
+        assert_eq!(image.get_pixel(3, 3), Rgba::from_slice(&[255, 0, 0, 255]));
+
+        // the top left quadrant is uniform within the tolerance, but not
+        // exactly, the bottom right one is split into pixels.
+        let error = OverlayOptions {
+            border: None,
+            coloring: overlay::LeafColoring::Error,
+        };
+        let image = tree.overlay_image(9.0, &error);
+        assert_eq!(image.get_pixel(0, 0), Rgba::from_slice(&[255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(3, 3), Rgba::from_slice(&[0, 0, 255, 255]));
+
+        // an image that is not a power of 2 is covered by partial nodes.
+        let image = test_image(37, 13);
+        let tree = QuadTree::new(&image);
+        assert_eq!(tree.overlay_image(0.0, &options), image);
+    }
+
+    /// Check that every node is the same as in a tree built from scratch.
+    fn assert_same_tree<P: Pixel>(tree: &QuadTree<P>, expected: &QuadTree<P>) {
+        assert_eq!(tree.nodes.len(), expected.nodes.len());
+        for (node, expected) in tree.nodes.iter().zip(expected.nodes.iter()) {
+            assert_eq!(node.top_left, expected.top_left);
+            assert_eq!(node.bottom_right, expected.bottom_right);
+            assert_eq!(node.data, expected.data);
+            assert_eq!(node.min, expected.min);
+            assert_eq!(node.max, expected.max);
+            assert_eq!(node.pixel_count, expected.pixel_count);
+        }
+    }
+
+    #[test]
+    fn set_pixel() {
+        let mut image = test_image(7, 5);
+        let mut tree = QuadTree::new(&image);
+        let pixel = Rgba {
+            red: 200,
+            green: 100,
+            blue: 50,
+            alpha: 255,
+        };
+        tree.set_pixel(6, 2, pixel).unwrap();
+        image.set_index(6, 2, pixel);
+        assert_same_tree(&tree, &QuadTree::new(&image));
+        assert_eq!(tree.color_at(6, 2, tree.depth()), Some(pixel));
+    }
+
+    #[test]
+    fn set_pixel_outside() {
+        let image = test_image(7, 5);
+        let mut tree = QuadTree::new(&image);
+        assert_eq!(
+            tree.set_pixel(7, 0, Rgba::new()),
+            Err(QuadtreeError::PixelOutOfBounds { x: 7, y: 0 })
+        );
+        // the pixels inside the image aren't changed either.
+        assert!(tree.set_pixels_rgba(&[0, 0, 0, 5], &[1; 8]).is_err());
+        assert!(tree.update_region(5, 3, 3, 2, &[1; 24]).is_err());
+        assert!(tree.update_region(0, 0, 2, 2, &[1; 15]).is_err());
+        assert_eq!(
+            tree.update_region(usize::MAX, 0, 1, 1, &[1; 4]),
+            Err(QuadtreeError::RegionOutOfBounds {
+                x: usize::MAX,
+                y: 0,
+                width: 1,
+                height: 1
+            })
+        );
+        assert_same_tree(&tree, &QuadTree::new(&image));
+    }
+
+    #[test]
+    fn out_of_range() {
+        let tree = QuadTree::new(&test_image_4x4());
+        assert!(tree.image_at_level(2).is_ok());
+        assert_eq!(
+            tree.image_at_level(3),
+            Err(QuadtreeError::LevelOutOfRange { level: 3, depth: 2 })
+        );
+        assert!(tree.node_color(20).is_ok());
+        assert_eq!(
+            tree.node_color(21),
+            Err(QuadtreeError::NodeOutOfRange { index: 21, len: 21 })
+        );
+        assert!(tree.node_region(21).is_err());
+    }
+
+    #[test]
+    fn batch_updates() {
+        let mut image = test_image(9, 6);
+        let mut tree = QuadTree::new(&image);
+        let coordinates = [0, 0, 1, 0, 8, 5, 4, 3];
+        let image_data_rgba: Vec<u8> = (1..=16).collect();
+        tree.set_pixels_rgba(&coordinates, &image_data_rgba)
+            .unwrap();
+        for (c, p) in coordinates.chunks(2).zip(image_data_rgba.chunks(4)) {
+            image.set_index(c[0], c[1], Rgba::from_slice(p));
+        }
+        assert_same_tree(&tree, &QuadTree::new(&image));
+
+        let region: Vec<u8> = (0..2 * 3 * 4).map(|i| i as u8 * 10).collect();
+        tree.update_region(3, 2, 2, 3, &region).unwrap();
+        for j in 0..3 {
+            for i in 0..2 {
+                let index = (j * 2 + i) * 4;
+                image.set_index(3 + i, 2 + j, Rgba::from_slice(&region[index..index + 4]));
+            }
+        }
+        assert_same_tree(&tree, &QuadTree::new(&image));
+    }
+
+    #[test]
+    fn grayscale_16_bit() {
+        let pixels: Vec<_> = (0..15u16).map(|i| Luma(i * 4000)).collect();
+        let mut image = Image::new(5, 3, pixels);
+        let mut tree = QuadTree::new(&image);
+        assert_eq!(tree.color_at(4, 2, tree.depth()), Some(Luma(56000)));
+        // the 2x2 node at the top left spans 0 to 24000.
+        assert_eq!(tree.uniform_node_at(0, 0, 24000.0), Some(5));
+        assert_eq!(tree.uniform_node_at(0, 0, 23999.0), Some(21));
+
+        tree.set_pixel(1, 1, Luma(65535)).unwrap();
+        image.set_index(1, 1, Luma(65535));
+        assert_same_tree(&tree, &QuadTree::new(&image));
+        assert_eq!(tree.image_at_level(tree.depth()).unwrap(), image);
+    }
+
     #[test]
     fn roundtrip() {
         let image = test_image_4x4();
         let tree = QuadTree::new(&image);
-        let image2 = tree.image_at_level(2);
+        let image2 = tree.image_at_level(2).unwrap();
         let tree2 = QuadTree::new(&image2);
 
         assert_eq!(tree.nodes.len(), tree2.nodes.len());
@@ -0,0 +1,146 @@
+use std::{error::Error, fmt};
+
+use wasm_bindgen::{JsError, JsValue};
+
+/// Why an operation on an image or a quadtree failed. Exported functions
+/// that return this throw a JavaScript `Error` with its message instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuadtreeError {
+    /// An image with a width or height of 0.
+    EmptyImage,
+    /// A mean of no pixels at all.
+    NoPixels,
+    /// A buffer of RGBA bytes shorter than the pixels it should hold.
+    BufferTooSmall { expected: usize, actual: usize },
+    /// A volume with a width, height or depth of 0.
+    EmptyVolume,
+    /// Slices of a volume that are not all the same size as the first.
+    SliceSizeMismatch { index: usize },
+    /// A pixel outside of the image.
+    PixelOutOfBounds { x: usize, y: usize },
+    /// A rectangle whose far edges lie past the largest coordinate.
+    RegionOutOfBounds {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    },
+    /// A slice past the back of a volume.
+    SliceOutOfRange { z: usize, depth: usize },
+    /// Bounds of a point quadtree that are not finite or have no area.
+    InvalidBounds {
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+    },
+    /// A point outside of the bounds of a point quadtree.
+    PointOutOfBounds { x: f64, y: f64 },
+    /// A level deeper than the tree.
+    LevelOutOfRange { level: u32, depth: u32 },
+    /// A node index past the end of the tree.
+    NodeOutOfRange { index: usize, len: usize },
+    /// An error metric name that is not one of `METRIC_NAMES`.
+    UnknownErrorMetric(String),
+    /// A leaf coloring name that is not one of `LEAF_COLORING_NAMES`.
+    UnknownLeafColoring(String),
+    /// A split strategy name that is not one of `SPLIT_STRATEGY_NAMES`.
+    UnknownSplitStrategy(String),
+    /// A number of bits per color channel outside of 1 to 8.
+    InvalidColorBits(u8),
+    /// Bytes that are not a quadtree encoded with `to_bytes`.
+    MalformedEncoding,
+    /// An image file that could not be decoded or encoded.
+    #[cfg(feature = "codecs")]
+    Codec(String),
+}
+
+impl fmt::Display for QuadtreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuadtreeError::EmptyImage => write!(f, "image has no pixels"),
+            QuadtreeError::NoPixels => write!(f, "can't average no pixels"),
+            QuadtreeError::BufferTooSmall { expected, actual } => write!(
+                f,
+                "buffer of {actual} bytes is too small, expected at least {expected}"
+            ),
+            QuadtreeError::EmptyVolume => write!(f, "volume has no voxels"),
+            QuadtreeError::SliceSizeMismatch { index } => {
+                write!(f, "slice {index} is not the same size as the first slice")
+            }
+            QuadtreeError::SliceOutOfRange { z, depth } => {
+                write!(
+                    f,
+                    "slice {z} is outside of the volume, which has depth {depth}"
+                )
+            }
+            QuadtreeError::PixelOutOfBounds { x, y } => {
+                write!(f, "pixel ({x}, {y}) is outside of the image")
+            }
+            QuadtreeError::RegionOutOfBounds {
+                x,
+                y,
+                width,
+                height,
+            } => write!(
+                f,
+                "region of {width}x{height} at ({x}, {y}) is outside of the image"
+            ),
+            QuadtreeError::InvalidBounds {
+                x,
+                y,
+                width,
+                height,
+            } => write!(
+                f,
+                "bounds of {width}x{height} at ({x}, {y}) must be finite with a positive width and height"
+            ),
+            QuadtreeError::PointOutOfBounds { x, y } => {
+                write!(f, "point ({x}, {y}) is outside of the bounds of the tree")
+            }
+            QuadtreeError::LevelOutOfRange { level, depth } => {
+                write!(
+                    f,
+                    "level {level} is deeper than the tree, which has depth {depth}"
+                )
+            }
+            QuadtreeError::NodeOutOfRange { index, len } => {
+                write!(f, "node {index} is out of range, the tree has {len} nodes")
+            }
+            QuadtreeError::UnknownErrorMetric(name) => write!(f, "unknown error metric: {name}"),
+            QuadtreeError::UnknownLeafColoring(name) => write!(f, "unknown leaf coloring: {name}"),
+            QuadtreeError::UnknownSplitStrategy(name) => {
+                write!(f, "unknown split strategy: {name}")
+            }
+            QuadtreeError::InvalidColorBits(bits) => {
+                write!(f, "color bits must be between 1 and 8, not {bits}")
+            }
+            QuadtreeError::MalformedEncoding => {
+                write!(f, "bytes are not a valid quadtree encoding")
+            }
+            #[cfg(feature = "codecs")]
+            QuadtreeError::Codec(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl Error for QuadtreeError {}
+
+impl From<QuadtreeError> for JsValue {
+    fn from(error: QuadtreeError) -> Self {
+        JsError::from(error).into()
+    }
+}
+
+/// Check that the buffer holds RGBA data for at least the given
+/// number of pixels.
+pub(crate) fn check_rgba_len(pixels: usize, buffer: &[u8]) -> Result<(), QuadtreeError> {
+    let expected = pixels.saturating_mul(4);
+    if buffer.len() < expected {
+        return Err(QuadtreeError::BufferTooSmall {
+            expected,
+            actual: buffer.len(),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,503 @@
+use crate::{
+    image::{Image, Rgba},
+    linear_fit::LinearFit,
+    pixel::{Pixel, LUMA_WEIGHTS},
+    region::Region,
+    summed_area_table::SummedAreaTable,
+};
+
+/// How far the pixels in a region are from the single color
+/// that approximates them. The region quadtree splits the regions
+/// whose error is too large.
+///
+/// Errors of different metrics are on different scales, so each
+/// needs its own error threshold.
+pub trait ErrorMetric<P: Pixel = Rgba> {
+    /// The error of approximating the pixels of the image in the given region
+    /// by the given color. The summed-area table is of the same image.
+    fn error(
+        &self,
+        image: &Image<P>,
+        table: &SummedAreaTable<P>,
+        region: &Region,
+        color: &P,
+    ) -> f32;
+
+    /// The error of approximating the pixels of the image in the given region
+    /// by the given linear fit, for leaves with `LeafModel::Linear`. Defaults
+    /// to the weighted mean squared error, pixel by pixel.
+    fn fit_error(
+        &self,
+        image: &Image<P>,
+        _table: &SummedAreaTable<P>,
+        region: &Region,
+        fit: &LinearFit<P>,
+    ) -> f32 {
+        fit_mse(image, region, fit)
+    }
+}
+
+/// The names of the built-in metrics, as accepted by `by_name`.
+pub const METRIC_NAMES: [&str; 5] = ["mse", "max_deviation", "variance", "delta_e", "ssim"];
+
+/// The built-in metric with the given name, or None if there is no such metric.
+pub fn by_name(name: &str) -> Option<Box<dyn ErrorMetric>> {
+    match name {
+        "mse" => Some(Box::new(WeightedMse)),
+        "max_deviation" => Some(Box::new(MaxDeviation)),
+        "variance" => Some(Box::new(ChannelVariance)),
+        "delta_e" => Some(Box::new(DeltaE)),
+        "ssim" => Some(Box::new(Ssim)),
+        _ => None,
+    }
+}
+
+fn region_pixels<'a, P: Pixel>(
+    image: &'a Image<P>,
+    region: &'a Region,
+) -> impl Iterator<Item = P> + 'a {
+    (region.y..region.y + region.height)
+        .flat_map(move |y| (region.x..region.x + region.width).map(move |x| (x, y)))
+        .map(|(x, y)| image.get_pixel(x, y))
+}
+
+/// The pixels in the region, with their coordinates.
+fn region_positions<'a, P: Pixel>(
+    image: &'a Image<P>,
+    region: &'a Region,
+) -> impl Iterator<Item = (usize, usize, P)> + 'a {
+    (region.y..region.y + region.height)
+        .flat_map(move |y| (region.x..region.x + region.width).map(move |x| (x, y)))
+        .map(|(x, y)| (x, y, image.get_pixel(x, y)))
+}
+
+/// The weighted mean squared error of the fit, pixel by pixel.
+fn fit_mse<P: Pixel>(image: &Image<P>, region: &Region, fit: &LinearFit<P>) -> f32 {
+    let total: f64 = region_positions(image, region)
+        .map(|(x, y, p)| {
+            (0..P::CHANNELS)
+                .map(|c| P::WEIGHTS[c] * (p.channel(c) - fit.value(c, x, y)).powi(2))
+                .sum::<f64>()
+        })
+        .sum();
+    (total / (region.width * region.height) as f64) as f32
+}
+
+/// Mean squared error of the channels, weighted as in `Pixel::squared_error`.
+/// For colors, red, green and blue are weighted by how sensitive the eye
+/// is to each, and alpha is ignored. The default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WeightedMse;
+
+impl<P: Pixel> ErrorMetric<P> for WeightedMse {
+    fn error(
+        &self,
+        _image: &Image<P>,
+        table: &SummedAreaTable<P>,
+        region: &Region,
+        color: &P,
+    ) -> f32 {
+        table.mse(region.x, region.y, region.width, region.height, color)
+    }
+
+    fn fit_error(
+        &self,
+        image: &Image<P>,
+        table: &SummedAreaTable<P>,
+        region: &Region,
+        fit: &LinearFit<P>,
+    ) -> f32 {
+        match table.fit_channel_mse(region.x, region.y, region.width, region.height, fit) {
+            Some(mse) => (0..P::CHANNELS)
+                .map(|c| P::WEIGHTS[c] as f32 * mse[c])
+                .sum(),
+            None => fit_mse(image, region, fit),
+        }
+    }
+}
+
+/// The largest absolute difference between any channel of the color
+/// and of any pixel, alpha included.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MaxDeviation;
+
+impl<P: Pixel> ErrorMetric<P> for MaxDeviation {
+    fn error(
+        &self,
+        image: &Image<P>,
+        _table: &SummedAreaTable<P>,
+        region: &Region,
+        color: &P,
+    ) -> f32 {
+        region_pixels(image, region)
+            .flat_map(|p| (0..P::CHANNELS).map(move |c| (p.channel(c) - color.channel(c)).abs()))
+            .fold(0.0, f64::max) as f32
+    }
+
+    fn fit_error(
+        &self,
+        image: &Image<P>,
+        _table: &SummedAreaTable<P>,
+        region: &Region,
+        fit: &LinearFit<P>,
+    ) -> f32 {
+        region_positions(image, region)
+            .flat_map(|(x, y, p)| {
+                (0..P::CHANNELS).map(move |c| (p.channel(c) - fit.value(c, x, y)).abs())
+            })
+            .fold(0.0, f64::max) as f32
+    }
+}
+
+/// The mean squared error of each channel, alpha included and unweighted,
+/// averaged over the channels. Equal to the mean of the per-channel
+/// variances when the color is the mean color.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelVariance;
+
+impl<P: Pixel> ErrorMetric<P> for ChannelVariance {
+    fn error(
+        &self,
+        _image: &Image<P>,
+        table: &SummedAreaTable<P>,
+        region: &Region,
+        color: &P,
+    ) -> f32 {
+        let mse = table.channel_mse(region.x, region.y, region.width, region.height, color);
+        mse.iter().sum::<f32>() / P::CHANNELS as f32
+    }
+
+    fn fit_error(
+        &self,
+        image: &Image<P>,
+        table: &SummedAreaTable<P>,
+        region: &Region,
+        fit: &LinearFit<P>,
+    ) -> f32 {
+        let mse = table
+            .fit_channel_mse(region.x, region.y, region.width, region.height, fit)
+            .unwrap_or_else(|| {
+                (0..P::CHANNELS)
+                    .map(|c| {
+                        let total: f64 = region_positions(image, region)
+                            .map(|(x, y, p)| (p.channel(c) - fit.value(c, x, y)).powi(2))
+                            .sum();
+                        (total / (region.width * region.height) as f64) as f32
+                    })
+                    .collect()
+            });
+        mse.iter().sum::<f32>() / P::CHANNELS as f32
+    }
+}
+
+/// The mean perceptual difference between the color and each pixel,
+/// as CIE76 ΔE: the distance in the CIE L*a*b* color space. A ΔE of
+/// about 2.3 is just noticeable. Ignores alpha.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeltaE;
+
+impl DeltaE {
+    /// Convert an sRGB color to CIE L*a*b*, with a D65 white point.
+    fn lab(color: &Rgba) -> [f32; 3] {
+        fn linear(c: u8) -> f32 {
+            let c = c as f32 / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        }
+        fn f(t: f32) -> f32 {
+            if t > 216.0 / 24389.0 {
+                t.cbrt()
+            } else {
+                (24389.0 / 27.0 * t + 16.0) / 116.0
+            }
+        }
+        let (r, g, b) = (linear(color.red), linear(color.green), linear(color.blue));
+        let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+        let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+        [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+    }
+
+    /// The mean distance in CIE L*a*b* between the pixels in the region
+    /// and the target color at each pixel.
+    fn mean_distance(image: &Image, region: &Region, target: impl Fn(usize, usize) -> Rgba) -> f32 {
+        let mut total = 0.0;
+        for (x, y, pixel) in region_positions(image, region) {
+            let lab = DeltaE::lab(&pixel);
+            let target = DeltaE::lab(&target(x, y));
+            let distance: f32 = lab.iter().zip(target).map(|(a, b)| (a - b).powi(2)).sum();
+            total += distance.sqrt();
+        }
+        total / (region.width * region.height) as f32
+    }
+}
+
+impl ErrorMetric for DeltaE {
+    fn error(&self, image: &Image, _table: &SummedAreaTable, region: &Region, color: &Rgba) -> f32 {
+        DeltaE::mean_distance(image, region, |_, _| *color)
+    }
+
+    fn fit_error(
+        &self,
+        image: &Image,
+        _table: &SummedAreaTable,
+        region: &Region,
+        fit: &LinearFit,
+    ) -> f32 {
+        DeltaE::mean_distance(image, region, |x, y| fit.color_at(x, y))
+    }
+}
+
+/// One minus the mean structural similarity (SSIM) of the luma of the
+/// pixels and the color, over windows of at most 8 by 8 pixels. Between
+/// 0 for a region that is the color exactly, and 2 for one that is
+/// anticorrelated with it, as SSIM is between -1 and 1. Unlike the other
+/// metrics, penalizes losing texture more than small shifts in brightness.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ssim;
+
+impl Ssim {
+    const WINDOW: usize = 8;
+    const C1: f32 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f32 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    fn luma(color: &Rgba) -> f32 {
+        let channels = [color.red, color.green, color.blue];
+        (0..3)
+            .map(|c| LUMA_WEIGHTS[c] as f32 * channels[c] as f32)
+            .sum()
+    }
+
+    /// One minus the mean SSIM of the luma of the pixels in the region and
+    /// of the target color at each pixel.
+    fn dissimilarity(image: &Image, region: &Region, target: impl Fn(usize, usize) -> f32) -> f32 {
+        let mut total = 0.0;
+        let mut windows = 0;
+        for y in (region.y..region.y + region.height).step_by(Ssim::WINDOW) {
+            for x in (region.x..region.x + region.width).step_by(Ssim::WINDOW) {
+                let window = Region {
+                    x,
+                    y,
+                    width: Ssim::WINDOW.min(region.x + region.width - x),
+                    height: Ssim::WINDOW.min(region.y + region.height - y),
+                };
+                let (lumas, targets): (Vec<_>, Vec<_>) = region_positions(image, &window)
+                    .map(|(x, y, p)| (Ssim::luma(&p), target(x, y)))
+                    .unzip();
+                let len = lumas.len() as f32;
+                let mean = lumas.iter().sum::<f32>() / len;
+                let target_mean = targets.iter().sum::<f32>() / len;
+                let variance = lumas.iter().map(|l| (l - mean).powi(2)).sum::<f32>() / len;
+                let target_variance = targets
+                    .iter()
+                    .map(|t| (t - target_mean).powi(2))
+                    .sum::<f32>()
+                    / len;
+                let covariance = lumas
+                    .iter()
+                    .zip(&targets)
+                    .map(|(l, t)| (l - mean) * (t - target_mean))
+                    .sum::<f32>()
+                    / len;
+                total += (2.0 * mean * target_mean + Ssim::C1) * (2.0 * covariance + Ssim::C2)
+                    / ((mean * mean + target_mean * target_mean + Ssim::C1)
+                        * (variance + target_variance + Ssim::C2));
+                windows += 1;
+            }
+        }
+        1.0 - total / windows as f32
+    }
+}
+
+impl ErrorMetric for Ssim {
+    fn error(&self, image: &Image, _table: &SummedAreaTable, region: &Region, color: &Rgba) -> f32 {
+        // the approximation is flat, so its variance and its covariance
+        // with the pixels are 0.
+        let target = Ssim::luma(color);
+        Ssim::dissimilarity(image, region, |_, _| target)
+    }
+
+    fn fit_error(
+        &self,
+        image: &Image,
+        _table: &SummedAreaTable,
+        region: &Region,
+        fit: &LinearFit,
+    ) -> f32 {
+        Ssim::dissimilarity(image, region, |x, y| {
+            (0..3)
+                .map(|c| LUMA_WEIGHTS[c] as f32 * fit.value(c, x, y) as f32)
+                .sum()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image() -> Image {
+        let mut pixels = vec![];
+        for i in 0..16 {
+            pixels.push(Rgba {
+                red: i * 16,
+                green: 255 - i * 8,
+                blue: 100,
+                alpha: 255 - i,
+            });
+        }
+        Image::new(4, 4, pixels)
+    }
+
+    fn error(metric: &dyn ErrorMetric, image: &Image, region: &Region) -> f32 {
+        let table = SummedAreaTable::new(image);
+        let mean = table.mean(region.x, region.y, region.width, region.height);
+        metric.error(image, &table, region, &mean)
+    }
+
+    #[test]
+    fn uniform_region_has_no_error() {
+        let image = Image::new(
+            3,
+            2,
+            vec![
+                Rgba {
+                    red: 10,
+                    green: 200,
+                    blue: 30,
+                    alpha: 40
+                };
+                6
+            ],
+        );
+        let region = Region {
+            x: 0,
+            y: 0,
+            width: 3,
+            height: 2,
+        };
+        for name in METRIC_NAMES {
+            let metric = by_name(name).unwrap();
+            assert!(
+                error(metric.as_ref(), &image, &region).abs() < 1e-4,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn varied_region_has_error() {
+        let image = test_image();
+        let region = Region {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 4,
+        };
+        for name in METRIC_NAMES {
+            let metric = by_name(name).unwrap();
+            assert!(error(metric.as_ref(), &image, &region) > 0.0, "{name}");
+        }
+        assert!(by_name("psnr").is_none());
+    }
+
+    #[test]
+    fn flat_fit_error() {
+        // a fit without slopes has the same error as its mean color, with
+        // or without moments in the table.
+        let image = test_image();
+        let region = Region {
+            x: 1,
+            y: 0,
+            width: 3,
+            height: 4,
+        };
+        let table = SummedAreaTable::with_moments(&image);
+        let mut fit = table.linear_fit(1, 0, 3, 4).unwrap();
+        let mean = fit.mean();
+        fit = LinearFit::new(
+            fit.center(),
+            (0..4).map(|c| [mean.channel(c), 0.0, 0.0]).collect(),
+        );
+        for name in METRIC_NAMES {
+            let metric = by_name(name).unwrap();
+            let expected = metric.error(&image, &table, &region, &mean);
+            for table in [&table, &SummedAreaTable::new(&image)] {
+                let actual = metric.fit_error(&image, table, &region, &fit);
+                assert!(
+                    (actual - expected).abs() < 1e-3 * expected.max(1.0),
+                    "{name}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn max_deviation() {
+        let image = test_image();
+        let region = Region {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 1,
+        };
+        let table = SummedAreaTable::new(&image);
+        // reds in the first row are 0, 16, 32, 48.
+        let color = Rgba {
+            red: 0,
+            green: 255,
+            blue: 100,
+            alpha: 255,
+        };
+        assert_eq!(MaxDeviation.error(&image, &table, &region, &color), 48.0);
+    }
+
+    #[test]
+    fn variance_includes_alpha() {
+        let image = Image::new(
+            2,
+            1,
+            vec![
+                Rgba {
+                    red: 0,
+                    green: 0,
+                    blue: 0,
+                    alpha: 0,
+                },
+                Rgba {
+                    red: 0,
+                    green: 0,
+                    blue: 0,
+                    alpha: 20,
+                },
+            ],
+        );
+        let region = Region {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        assert_eq!(error(&WeightedMse, &image, &region), 0.0);
+        // alpha variance is 100, the other channels' 0.
+        assert_eq!(error(&ChannelVariance, &image, &region), 25.0);
+    }
+
+    #[test]
+    fn delta_e_lab() {
+        let white = DeltaE::lab(&Rgba {
+            red: 255,
+            green: 255,
+            blue: 255,
+            alpha: 255,
+        });
+        assert!((white[0] - 100.0).abs() < 0.1);
+        assert!(white[1].abs() < 0.1 && white[2].abs() < 0.1);
+        let black = DeltaE::lab(&Rgba::new());
+        assert!(black.iter().all(|c| c.abs() < 0.1));
+    }
+}
@@ -1,5 +1,10 @@
 use wasm_bindgen::prelude::wasm_bindgen;
 
+use crate::{
+    error::{check_rgba_len, QuadtreeError},
+    pixel::{Pixel, LUMA_WEIGHTS},
+};
+
 #[wasm_bindgen]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Rgba {
@@ -19,26 +24,23 @@ impl Rgba {
         }
     }
 
-    pub(crate) fn mean(pixels: &[Rgba]) -> Rgba {
-        let mut red: u32 = 0;
-        let mut green: u32 = 0;
-        let mut blue: u32 = 0;
-        let mut alpha: u32 = 0;
-        for pixel in pixels {
-            red += pixel.red as u32;
-            green += pixel.green as u32;
-            blue += pixel.blue as u32;
-            alpha += pixel.alpha as u32;
-        }
-        let len = pixels.len() as u32;
+    /// The color in the first four bytes of the slice, in RGBA order.
+    pub(crate) fn from_slice(rgba: &[u8]) -> Rgba {
         Rgba {
-            red: (red / len) as u8,
-            green: (green / len) as u8,
-            blue: (blue / len) as u8,
-            alpha: (alpha / len) as u8,
+            red: rgba[0],
+            green: rgba[1],
+            blue: rgba[2],
+            alpha: rgba[3],
         }
     }
 
+    /// The color packed in a single number, as 0xRRGGBBAA.
+    pub(crate) fn to_u32(self) -> u32 {
+        u32::from_be_bytes([self.red, self.green, self.blue, self.alpha])
+    }
+
+    /// The mean squared error of the pixels with respect to this color, with
+    /// the channels weighted by luma. NaN if there are no pixels.
     pub(crate) fn mse(&self, pixels: &[Rgba]) -> f32 {
         // to avoid overflows, first cast each
         // component to u32, then sum, then divide and cast back to u8.
@@ -51,26 +53,26 @@ impl Rgba {
             blue += (pixel.blue as f32 - self.blue as f32).powi(2);
         }
         let len = pixels.len() as f32;
-        let (r_mse, g_mse, b_mse) = (red / len, green / len, blue / len);
-        // these seem to be the magical constants to account for how important
-        // getting a color right is, in terms of how well typical LEDs absorb light
-        // in certain frequencies.
-        r_mse * 0.2989 + g_mse * 0.5870 + b_mse * 0.1140
+        Rgba::luma_weighted(red / len, green / len, blue / len)
+    }
+
+    /// Combine the mean squared errors of the red, green and blue channels.
+    pub(crate) fn luma_weighted(r_mse: f32, g_mse: f32, b_mse: f32) -> f32 {
+        let [r, g, b] = LUMA_WEIGHTS.map(|weight| weight as f32);
+        r_mse * r + g_mse * g + b_mse * b
     }
 }
 
-// Assume square image, with length a power of 2
-#[wasm_bindgen]
-#[derive(Debug)]
-pub struct Image {
+/// An image of any pixel type, stored row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image<P: Pixel = Rgba> {
     width: usize,
     height: usize,
-    pixels: Vec<Rgba>,
+    pixels: Vec<P>,
 }
 
-impl Image {
-    #[allow(dead_code)]
-    pub(crate) fn new(width: usize, height: usize, pixels: Vec<Rgba>) -> Image {
+impl<P: Pixel> Image<P> {
+    pub(crate) fn new(width: usize, height: usize, pixels: Vec<P>) -> Image<P> {
         Image {
             width,
             height,
@@ -78,19 +80,11 @@ impl Image {
         }
     }
 
-    pub(crate) fn empty(width: usize, height: usize) -> Image {
+    pub(crate) fn empty(width: usize, height: usize) -> Image<P> {
         Image {
             width,
             height,
-            pixels: vec![
-                Rgba {
-                    red: 0,
-                    green: 0,
-                    blue: 0,
-                    alpha: 0
-                };
-                width * height
-            ],
+            pixels: vec![P::zero(); width * height],
         }
     }
 
@@ -98,61 +92,193 @@ impl Image {
         y * self.width + x
     }
 
-    pub(crate) fn set_index(&mut self, x: usize, y: usize, pixel: Rgba) {
+    pub(crate) fn set_index(&mut self, x: usize, y: usize, pixel: P) {
         let idx = self.get_index(x, y);
         self.pixels[idx] = pixel;
     }
 
-    pub(crate) fn get_pixel(&self, x: usize, y: usize) -> Rgba {
-        self.pixels[self.get_index(x, y)]
+    /// Create an image from its pixels, row by row. An error if the
+    /// image is empty or there are fewer pixels than width times height.
+    pub fn from_pixels(
+        width: usize,
+        height: usize,
+        mut pixels: Vec<P>,
+    ) -> Result<Image<P>, QuadtreeError> {
+        if width == 0 || height == 0 {
+            return Err(QuadtreeError::EmptyImage);
+        }
+        let len = width.saturating_mul(height);
+        if pixels.len() < len {
+            return Err(QuadtreeError::BufferTooSmall {
+                expected: len,
+                actual: pixels.len(),
+            });
+        }
+        pixels.truncate(len);
+        Ok(Image::new(width, height, pixels))
     }
 
-    pub(crate) fn get_size(&self) -> usize {
-        self.width * self.height
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The pixels, row by row.
+    pub fn pixels(&self) -> &[P] {
+        &self.pixels
+    }
+
+    /// The pixel at (x, y), which must be inside the image.
+    pub fn get_pixel(&self, x: usize, y: usize) -> P {
+        self.pixels[self.get_index(x, y)]
     }
 }
 
-#[wasm_bindgen]
 impl Image {
+    /// Create an image from the data of a canvas ImageData: the RGBA bytes
+    /// of the pixels, row by row. An error if the image is empty or the
+    /// data is too short.
+    pub fn from_image_data(
+        image_data_rgba: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<Image, QuadtreeError> {
+        check_rgba_len(width.saturating_mul(height), image_data_rgba)?;
+        let pixels = image_data_rgba
+            .chunks_exact(4)
+            .take(width * height)
+            .map(Rgba::from_slice)
+            .collect();
+        Image::from_pixels(width, height, pixels)
+    }
+
+    /// The mean color and mean squared error of the given region, which must
+    /// be inside the image, by copying its pixels as the region quadtree did
+    /// before it used summed-area tables. Only public for the benchmarks.
+    /// An error if the region is empty.
+    #[doc(hidden)]
+    pub fn copied_region_error(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<(Rgba, f32), QuadtreeError> {
+        let mut pixels = Vec::with_capacity(width * height);
+        for i in x..x + width {
+            for j in y..y + height {
+                pixels.push(self.get_pixel(i, j));
+            }
+        }
+        let mean = Rgba::mean(&pixels)?;
+        Ok((mean, mean.mse(&pixels)))
+    }
+
+    /// Write the pixels of this image to the data of a canvas ImageData
+    /// of the same size. An error if the data is too short.
+    pub fn to_image_data(&self, image_data_rgba: &mut [u8]) -> Result<(), QuadtreeError> {
+        check_rgba_len(self.pixels.len(), image_data_rgba)?;
+        for (rgba, pixel) in image_data_rgba.chunks_exact_mut(4).zip(&self.pixels) {
+            rgba.copy_from_slice(&[pixel.red, pixel.green, pixel.blue, pixel.alpha]);
+        }
+        Ok(())
+    }
+}
+
+/// An RGBA image, exported to JavaScript as Image.
+#[wasm_bindgen(js_name = Image)]
+pub struct JsImage(pub(crate) Image);
+
+/// Public methods, exported to JavaScript.
+#[wasm_bindgen(js_class = Image)]
+impl JsImage {
     pub fn width(&self) -> usize {
-        self.width
+        self.0.width
     }
 
     pub fn height(&self) -> usize {
-        self.height
+        self.0.height
     }
 
     pub fn pixels(&self) -> *const Rgba {
-        self.pixels.as_ptr()
-    }
-
-    pub fn from_image_data(image_data_rgba: &[u8], width: usize, height: usize) -> Image {
-        // utils::set_panic_hook();
-        let mut pixels = vec![];
-        for i in 0..(width * height) {
-            let index = i * 4;
-            pixels.push(Rgba {
-                red: image_data_rgba[index],
-                green: image_data_rgba[index + 1],
-                blue: image_data_rgba[index + 2],
-                alpha: image_data_rgba[index + 3],
-            });
-        }
+        self.0.pixels.as_ptr()
+    }
 
-        Image {
-            width,
-            height,
-            pixels,
-        }
+    pub fn from_image_data(
+        image_data_rgba: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<JsImage, QuadtreeError> {
+        Image::from_image_data(image_data_rgba, width, height).map(JsImage)
     }
 
-    pub fn to_image_data(&self, image_data_rgba: &mut [u8]) {
-        for i in 0..(self.width * self.height) {
-            let index = i * 4;
-            image_data_rgba[index] = self.pixels[i].red;
-            image_data_rgba[index + 1] = self.pixels[i].green;
-            image_data_rgba[index + 2] = self.pixels[i].blue;
-            image_data_rgba[index + 3] = self.pixels[i].alpha;
-        }
+    pub fn to_image_data(&self, image_data_rgba: &mut [u8]) -> Result<(), QuadtreeError> {
+        self.0.to_image_data(image_data_rgba)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel::Luma;
+    #[test]
+    fn image_data_roundtrip() {
+        let data: Vec<u8> = (0..3 * 2 * 4).collect();
+        let image = Image::from_image_data(&data, 3, 2).unwrap();
+        assert_eq!(image.get_pixel(2, 1), Rgba::from_slice(&data[20..24]));
+
+        let mut result = vec![0; data.len() + 4];
+        image.to_image_data(&mut result).unwrap();
+        assert_eq!(result[..data.len()], data);
+    }
+
+    #[test]
+    fn image_data_errors() {
+        let data = vec![0; 3 * 2 * 4];
+        assert_eq!(
+            Image::from_image_data(&data, 3, 3),
+            Err(QuadtreeError::BufferTooSmall {
+                expected: 36,
+                actual: 24
+            })
+        );
+        assert_eq!(
+            Image::from_image_data(&data, 0, 2),
+            Err(QuadtreeError::EmptyImage)
+        );
+        assert!(Image::from_image_data(&data, usize::MAX, 2).is_err());
+
+        let image = Image::from_image_data(&data, 3, 2).unwrap();
+        assert!(image.to_image_data(&mut [0; 23]).is_err());
+    }
+
+    #[test]
+    fn from_pixels() {
+        let image = Image::from_pixels(2, 2, vec![Luma(1u16), Luma(2), Luma(3), Luma(4), Luma(5)]);
+        let image = image.unwrap();
+        assert_eq!(image.pixels().len(), 4);
+        assert_eq!(image.get_pixel(1, 1), Luma(4));
+        assert!(Image::from_pixels(2, 2, vec![0.0f32; 3]).is_err());
+        assert_eq!(
+            Image::<f32>::from_pixels(0, 2, vec![]),
+            Err(QuadtreeError::EmptyImage)
+        );
+    }
+
+    #[test]
+    fn copied_region_error() {
+        let data: Vec<u8> = [[10, 20, 30, 255], [10, 20, 30, 255], [30, 20, 10, 255]].concat();
+        let image = Image::from_image_data(&data, 3, 1).unwrap();
+        let (mean, mse) = image.copied_region_error(0, 0, 2, 1).unwrap();
+        assert_eq!((mean, mse), (image.get_pixel(0, 0), 0.0));
+        let (_, mse) = image.copied_region_error(1, 0, 2, 1).unwrap();
+        assert!(mse > 0.0);
+        assert_eq!(
+            image.copied_region_error(1, 0, 0, 1),
+            Err(QuadtreeError::NoPixels)
+        );
     }
 }
@@ -1,7 +1,52 @@
+mod barnes_hut;
+#[cfg(feature = "codecs")]
+mod codec;
+mod complete_octree;
 mod complete_quad_tree;
+mod error;
+mod error_metric;
 mod image;
+mod linear_fit;
+mod loose_quad_tree;
+mod morton;
+mod nearest;
+mod overlay;
+mod pixel;
+mod point_quad_tree;
+mod region;
+mod region_octree;
 mod region_quad_tree;
+mod segmentation;
+mod serialization;
+mod split_strategy;
+mod summed_area_table;
+mod svg;
 mod utils;
+mod volume;
+
+pub use barnes_hut::{BarnesHut, Body};
+#[cfg(feature = "codecs")]
+pub use codec::{encode_apng, ImageFormat};
+pub use complete_octree::Octree;
+pub use complete_quad_tree::QuadTree;
+pub use error::QuadtreeError;
+pub use error_metric::{
+    ChannelVariance, DeltaE, ErrorMetric, MaxDeviation, Ssim, WeightedMse, METRIC_NAMES,
+};
+pub use image::{Image, Rgba};
+pub use linear_fit::{LeafModel, LinearFit};
+pub use loose_quad_tree::{Handle, LooseQuadTree};
+pub use overlay::{Border, LeafColoring, OverlayOptions, LEAF_COLORING_NAMES};
+pub use pixel::{Luma, Pixel, Rgb, Rgba16};
+pub use point_quad_tree::PointQuadTree;
+pub use region::{Point, Region, Region3};
+pub use region_octree::RegionOctreeVolume;
+pub use region_quad_tree::{Corner, Edge, LeafInfo, RegionQuadTreeImage};
+pub use segmentation::Segment;
+pub use split_strategy::{SplitStrategy, SPLIT_STRATEGY_NAMES};
+pub use summed_area_table::SummedAreaTable;
+pub use svg::{Stroke, SvgOptions};
+pub use volume::Volume;
 
 // use wasm_bindgen::prelude::*;
 
@@ -0,0 +1,94 @@
+//! Leaves that vary linearly in color over their region, instead of having
+//! a single flat color. On smooth gradients, like a sky, a flat leaf needs
+//! many splits to hide its edges, where a linear one fits in one go.
+//!
+//! The fit of each channel is the least-squares plane through the pixels
+//! of the region. Measured from the center of the region, x and y are
+//! uncorrelated over a rectangle, so the plane is the mean value plus a
+//! slope along x and one along y that can each be fitted on their own, in
+//! constant time from a summed-area table with moments.
+
+use std::marker::PhantomData;
+
+use crate::{image::Rgba, pixel::Pixel};
+
+/// How the leaves of a region quadtree approximate the pixels in their
+/// region.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LeafModel {
+    /// A single color, the mean of the region.
+    #[default]
+    Flat,
+    /// A color that varies linearly over the region, see `LinearFit`.
+    Linear,
+}
+
+/// A color that varies linearly over a region: for each channel, its value
+/// at the center of the region, and how much it changes per pixel to the
+/// right and per pixel down.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinearFit<P: Pixel = Rgba> {
+    center: (f64, f64),
+    /// The value at the center, the slope along x and the slope along y
+    /// of each channel.
+    channels: Vec<[f64; 3]>,
+    pixel: PhantomData<P>,
+}
+
+impl<P: Pixel> LinearFit<P> {
+    /// The fit with the given center, and value at the center, slope along
+    /// x and slope along y of each channel.
+    pub(crate) fn new(center: (f64, f64), channels: Vec<[f64; 3]>) -> LinearFit<P> {
+        LinearFit {
+            center,
+            channels,
+            pixel: PhantomData,
+        }
+    }
+
+    /// The center of the region, in pixel coordinates: halfway between
+    /// two pixels if the width or height is even.
+    pub fn center(&self) -> (f64, f64) {
+        self.center
+    }
+
+    /// The value at the center, the slope along x and the slope along y
+    /// of the given channel.
+    pub fn coefficients(&self, channel: usize) -> [f64; 3] {
+        self.channels[channel]
+    }
+
+    /// The value of the given channel at the pixel at (x, y).
+    pub fn value(&self, channel: usize, x: usize, y: usize) -> f64 {
+        let [value, slope_x, slope_y] = self.channels[channel];
+        value + slope_x * (x as f64 - self.center.0) + slope_y * (y as f64 - self.center.1)
+    }
+
+    /// The color at the pixel at (x, y).
+    pub fn color_at(&self, x: usize, y: usize) -> P {
+        let channels: Vec<_> = (0..P::CHANNELS).map(|c| self.value(c, x, y)).collect();
+        P::from_channels(&channels)
+    }
+
+    /// The mean color of the region, which is the color at its center.
+    pub fn mean(&self) -> P {
+        let channels: Vec<_> = self.channels.iter().map(|[value, ..]| *value).collect();
+        P::from_channels(&channels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel::Luma;
+
+    #[test]
+    fn values() {
+        let fit: LinearFit<Luma<u8>> = LinearFit::new((1.5, 1.0), vec![[100.0, 10.0, -4.0]]);
+        assert_eq!(fit.value(0, 0, 0), 100.0 - 15.0 + 4.0);
+        assert_eq!(fit.color_at(3, 2), Luma(111));
+        assert_eq!(fit.mean(), Luma(100));
+        // channels are clamped to the range of the pixel type.
+        assert_eq!(fit.color_at(30, 0), Luma(255));
+    }
+}
@@ -0,0 +1,666 @@
+//! A loose quadtree: indexes axis-aligned rectangles in a rectangle of the
+//! plane, each with a payload. Every node covers one cell of a regular
+//! subdivision of the bounds, but holds the rectangles that fit in its
+//! loose bounds: the cell grown by half its size on every side. A rectangle
+//! goes in the deepest node whose cell is at least as large as it and
+//! contains its center, so it is in exactly one node, and moving it a
+//! little rarely moves it to another node.
+//!
+//! Rectangles whose center is outside of the bounds stay in the root.
+
+use std::array;
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::region::Region;
+
+/// Nodes this deep have no children, however small their rectangles.
+const MAX_DEPTH: u32 = 16;
+
+/// Identifies a rectangle in a `LooseQuadTree`, until it is removed.
+/// The slots of removed rectangles are reused by later inserts, but each
+/// reuse starts a new generation, so the handle of a removed rectangle
+/// never refers to the one inserted in its place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Handle {
+    index: usize,
+    generation: u32,
+}
+
+impl Handle {
+    /// The handle as a single number, for JavaScript: the generation in
+    /// the high 32 bits, and the index in the low ones.
+    fn to_bits(self) -> u64 {
+        (self.generation as u64) << 32 | self.index as u64
+    }
+
+    fn from_bits(bits: u64) -> Handle {
+        Handle {
+            index: (bits & u32::MAX as u64) as usize,
+            generation: (bits >> 32) as u32,
+        }
+    }
+}
+
+/// A cell of the subdivision of the bounds: at the given depth there are
+/// 2^depth by 2^depth cells, and x and y count cells from the top left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    depth: u32,
+    x: u32,
+    y: u32,
+}
+
+impl Cell {
+    const ROOT: Cell = Cell {
+        depth: 0,
+        x: 0,
+        y: 0,
+    };
+
+    /// The cell of the given child, in the order of `Region::quadrants`.
+    fn child(&self, quadrant: usize) -> Cell {
+        Cell {
+            depth: self.depth + 1,
+            x: self.x << 1 | (quadrant >> 1) as u32,
+            y: self.y << 1 | (quadrant & 1) as u32,
+        }
+    }
+
+    fn parent(&self) -> Cell {
+        Cell {
+            depth: self.depth - 1,
+            x: self.x >> 1,
+            y: self.y >> 1,
+        }
+    }
+
+    /// The child of the cell at the given depth above this one that
+    /// leads to this one.
+    fn quadrant_at(&self, depth: u32) -> usize {
+        let shift = self.depth - depth - 1;
+        let right = (self.x >> shift) & 1;
+        let bottom = (self.y >> shift) & 1;
+        (right << 1 | bottom) as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry<T> {
+    rect: Region<f64>,
+    cell: Cell,
+    payload: T,
+}
+
+/// A place for an entry, empty if its rectangle was removed. generation
+/// counts how often it was emptied.
+#[derive(Debug, Clone)]
+struct Slot<T> {
+    generation: u32,
+    entry: Option<Entry<T>>,
+}
+
+/// The indices in the entries of the rectangles in a node. Children are
+/// only there if they or their descendants hold rectangles.
+#[derive(Debug, Clone, Default)]
+struct Node {
+    entries: Vec<usize>,
+    children: Option<Box<[Node; 4]>>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.children.is_none()
+    }
+
+    fn insert(&mut self, cell: &Cell, depth: u32, index: usize) {
+        if depth == cell.depth {
+            self.entries.push(index);
+            return;
+        }
+        let children = self
+            .children
+            .get_or_insert_with(|| Box::new(array::from_fn(|_| Node::default())));
+        children[cell.quadrant_at(depth)].insert(cell, depth + 1, index);
+    }
+
+    /// Remove the entry from the node of the given cell, and the children
+    /// that are left empty on the way.
+    fn remove(&mut self, cell: &Cell, depth: u32, index: usize) {
+        if depth == cell.depth {
+            let position = self.entries.iter().position(|&i| i == index);
+            self.entries
+                .swap_remove(position.expect("entry is in the node of its cell"));
+            return;
+        }
+        let children = self
+            .children
+            .as_mut()
+            .expect("entry is in the node of its cell");
+        children[cell.quadrant_at(depth)].remove(cell, depth + 1, index);
+        if children.iter().all(Node::is_empty) {
+            self.children = None;
+        }
+    }
+}
+
+/// A loose quadtree over a rectangle of the plane, whose rectangles each
+/// carry a payload of type T. Rectangles overlap if they share at least
+/// a point, edges included.
+#[derive(Debug, Clone)]
+pub struct LooseQuadTree<T> {
+    bounds: Region<f64>,
+    root: Node,
+    entries: Vec<Slot<T>>,
+    free: Vec<usize>,
+}
+
+impl<T> LooseQuadTree<T> {
+    /// Create an empty tree for rectangles in the given bounds.
+    pub fn new(bounds: Region<f64>) -> LooseQuadTree<T> {
+        LooseQuadTree {
+            bounds,
+            root: Node::default(),
+            entries: vec![],
+            free: vec![],
+        }
+    }
+
+    pub fn bounds(&self) -> &Region<f64> {
+        &self.bounds
+    }
+
+    /// The number of rectangles in the tree.
+    pub fn len(&self) -> usize {
+        self.entries.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The loose bounds of the node of the given cell.
+    fn loose_region(&self, cell: &Cell) -> Region<f64> {
+        let scale = (1u64 << cell.depth) as f64;
+        let (width, height) = (self.bounds.width / scale, self.bounds.height / scale);
+        Region {
+            x: self.bounds.x + cell.x as f64 * width - width / 2.0,
+            y: self.bounds.y + cell.y as f64 * height - height / 2.0,
+            width: 2.0 * width,
+            height: 2.0 * height,
+        }
+    }
+
+    /// The cell of the node the rectangle goes in.
+    fn cell_of(&self, rect: &Region<f64>) -> Cell {
+        let center = rect.center();
+        if !self.bounds.contains(&center) {
+            return Cell::ROOT;
+        }
+        let mut depth = 0;
+        while depth < MAX_DEPTH
+            && rect.width <= self.bounds.width / (2u64 << depth) as f64
+            && rect.height <= self.bounds.height / (2u64 << depth) as f64
+        {
+            depth += 1;
+        }
+        let cells = 1u32 << depth;
+        let scale = cells as f64;
+        let mut cell = Cell {
+            depth,
+            x: (((center.x - self.bounds.x) / self.bounds.width * scale) as u32).min(cells - 1),
+            y: (((center.y - self.bounds.y) / self.bounds.height * scale) as u32).min(cells - 1),
+        };
+        // rounding can leave the rectangle sticking out of the loose bounds by
+        // a hair, and the queries rely on it being in them.
+        while cell.depth > 0 && !self.loose_region(&cell).contains_region(rect) {
+            cell = cell.parent();
+        }
+        cell
+    }
+
+    fn entry(&self, index: usize) -> &Entry<T> {
+        self.entries[index]
+            .entry
+            .as_ref()
+            .expect("nodes only refer to live entries")
+    }
+
+    /// The handle of the entry at the given index.
+    fn handle(&self, index: usize) -> Handle {
+        Handle {
+            index,
+            generation: self.entries[index].generation,
+        }
+    }
+
+    /// The slot of the given handle, if it is of the current generation.
+    fn slot_mut(&mut self, handle: Handle) -> Option<&mut Slot<T>> {
+        let slot = self.entries.get_mut(handle.index)?;
+        (slot.generation == handle.generation).then_some(slot)
+    }
+
+    /// Add a rectangle with the given payload, and return its handle.
+    pub fn insert(&mut self, rect: Region<f64>, payload: T) -> Handle {
+        let cell = self.cell_of(&rect);
+        let entry = Some(Entry {
+            rect,
+            cell,
+            payload,
+        });
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.entries[index].entry = entry;
+                index
+            }
+            None => {
+                self.entries.push(Slot {
+                    generation: 0,
+                    entry,
+                });
+                self.entries.len() - 1
+            }
+        };
+        self.root.insert(&cell, 0, index);
+        self.handle(index)
+    }
+
+    /// The rectangle with the given handle and its payload, or None if
+    /// it was removed.
+    pub fn get(&self, handle: Handle) -> Option<(&Region<f64>, &T)> {
+        let slot = self.entries.get(handle.index)?;
+        let entry = slot.entry.as_ref()?;
+        (slot.generation == handle.generation).then_some((&entry.rect, &entry.payload))
+    }
+
+    /// Move or resize the rectangle with the given handle. False if it
+    /// was removed.
+    pub fn update(&mut self, handle: Handle, rect: Region<f64>) -> bool {
+        let cell = self.cell_of(&rect);
+        let Some(Slot {
+            entry: Some(entry), ..
+        }) = self.slot_mut(handle)
+        else {
+            return false;
+        };
+        entry.rect = rect;
+        if entry.cell != cell {
+            let old = std::mem::replace(&mut entry.cell, cell);
+            self.root.remove(&old, 0, handle.index);
+            self.root.insert(&cell, 0, handle.index);
+        }
+        true
+    }
+
+    /// Remove the rectangle with the given handle, and return its payload.
+    /// None if it was removed already.
+    pub fn remove(&mut self, handle: Handle) -> Option<T> {
+        let slot = self.slot_mut(handle)?;
+        let entry = slot.entry.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.root.remove(&entry.cell, 0, handle.index);
+        self.free.push(handle.index);
+        Some(entry.payload)
+    }
+
+    /// All the rectangles in the tree, with their handles and payloads,
+    /// in no particular order.
+    pub fn rects(&self) -> Vec<(Handle, &Region<f64>, &T)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slot)| {
+                let entry = slot.entry.as_ref()?;
+                Some((self.handle(i), &entry.rect, &entry.payload))
+            })
+            .collect()
+    }
+
+    fn query<'a>(
+        &'a self,
+        node: &Node,
+        cell: &Cell,
+        rect: &Region<f64>,
+        result: &mut Vec<(Handle, &'a T)>,
+    ) {
+        for &index in &node.entries {
+            let entry = self.entry(index);
+            if entry.rect.intersects(rect) {
+                result.push((self.handle(index), &entry.payload));
+            }
+        }
+        if let Some(children) = &node.children {
+            for (quadrant, child) in children.iter().enumerate() {
+                let child_cell = cell.child(quadrant);
+                if self.loose_region(&child_cell).intersects(rect) {
+                    self.query(child, &child_cell, rect, result);
+                }
+            }
+        }
+    }
+
+    /// The rectangles that overlap the given one, with their handles
+    /// and payloads.
+    pub fn query_rect(&self, rect: &Region<f64>) -> Vec<(Handle, &T)> {
+        let mut result = vec![];
+        self.query(&self.root, &Cell::ROOT, rect, &mut result);
+        result
+    }
+
+    /// Call f once for every pair of overlapping rectangles, with their
+    /// handles and payloads, in no particular order. The loose bounds of
+    /// neighbouring nodes overlap, so rectangles in different subtrees can
+    /// overlap too: this queries the tree with each rectangle.
+    pub fn for_each_overlapping_pair<'a>(
+        &'a self,
+        mut f: impl FnMut((Handle, &'a T), (Handle, &'a T)),
+    ) {
+        let mut overlapping = vec![];
+        for (index, slot) in self.entries.iter().enumerate() {
+            let Some(entry) = &slot.entry else {
+                continue;
+            };
+            overlapping.clear();
+            self.query(&self.root, &Cell::ROOT, &entry.rect, &mut overlapping);
+            // each pair is found from both sides, report it from the first.
+            for &(other, payload) in &overlapping {
+                if other.index > index {
+                    f((self.handle(index), &entry.payload), (other, payload));
+                }
+            }
+        }
+    }
+}
+
+/// A loose quadtree whose payloads are numeric ids, exported to JavaScript
+/// as LooseQuadTree. Handles are BigInts, and queries return ids.
+#[wasm_bindgen(js_name = LooseQuadTree)]
+pub struct JsLooseQuadTree(LooseQuadTree<u32>);
+
+/// Public methods, exported to JavaScript.
+#[wasm_bindgen(js_class = LooseQuadTree)]
+impl JsLooseQuadTree {
+    pub fn new(x: f64, y: f64, width: f64, height: f64) -> JsLooseQuadTree {
+        let bounds = Region {
+            x,
+            y,
+            width,
+            height,
+        };
+        JsLooseQuadTree(LooseQuadTree::new(bounds))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn insert(&mut self, x: f64, y: f64, width: f64, height: f64, id: u32) -> u64 {
+        let rect = Region {
+            x,
+            y,
+            width,
+            height,
+        };
+        self.0.insert(rect, id).to_bits()
+    }
+
+    pub fn update(&mut self, handle: u64, x: f64, y: f64, width: f64, height: f64) -> bool {
+        let rect = Region {
+            x,
+            y,
+            width,
+            height,
+        };
+        self.0.update(Handle::from_bits(handle), rect)
+    }
+
+    pub fn remove(&mut self, handle: u64) -> Option<u32> {
+        self.0.remove(Handle::from_bits(handle))
+    }
+
+    pub fn query_rect(&self, x: f64, y: f64, width: f64, height: f64) -> Vec<u32> {
+        let rect = Region {
+            x,
+            y,
+            width,
+            height,
+        };
+        self.0
+            .query_rect(&rect)
+            .into_iter()
+            .map(|(_, &id)| id)
+            .collect()
+    }
+
+    /// The ids of all the overlapping pairs, flattened: the first two
+    /// overlap, the next two, and so on.
+    pub fn overlapping_pairs(&self) -> Vec<u32> {
+        let mut pairs = vec![];
+        self.0
+            .for_each_overlapping_pair(|(_, &a), (_, &b)| pairs.extend([a, b]));
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds() -> Region<f64> {
+        rect(0.0, 0.0, 100.0, 100.0)
+    }
+
+    fn rect(x: f64, y: f64, width: f64, height: f64) -> Region<f64> {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Check that every entry is in the node of its cell, and fits in its
+    /// loose bounds, and that no node has only empty children. Returns the
+    /// number of entries.
+    fn check_node<T>(tree: &LooseQuadTree<T>, node: &Node, cell: &Cell) -> usize {
+        let mut len = node.entries.len();
+        for &index in &node.entries {
+            let entry = tree.entry(index);
+            assert_eq!(entry.cell, *cell);
+            if cell.depth > 0 {
+                assert!(tree.loose_region(cell).contains_region(&entry.rect));
+            }
+        }
+        if let Some(children) = &node.children {
+            assert!(cell.depth < MAX_DEPTH);
+            let mut children_len = 0;
+            for (quadrant, child) in children.iter().enumerate() {
+                children_len += check_node(tree, child, &cell.child(quadrant));
+            }
+            assert!(children_len > 0);
+            len += children_len;
+        }
+        len
+    }
+
+    fn check_tree<T>(tree: &LooseQuadTree<T>) {
+        assert_eq!(check_node(tree, &tree.root, &Cell::ROOT), tree.len());
+    }
+
+    fn pairs<T: Copy + Ord>(tree: &LooseQuadTree<T>) -> Vec<(T, T)> {
+        let mut pairs = vec![];
+        tree.for_each_overlapping_pair(|(_, &a), (_, &b)| pairs.push((a.min(b), a.max(b))));
+        pairs.sort_unstable();
+        pairs
+    }
+
+    #[test]
+    fn overlapping_pairs() {
+        let mut tree = LooseQuadTree::new(bounds());
+        // a large one over everything, two small overlapping ones, a small one
+        // on its own, and one touching it.
+        tree.insert(rect(10.0, 10.0, 80.0, 80.0), 0);
+        tree.insert(rect(20.0, 20.0, 2.0, 2.0), 1);
+        tree.insert(rect(21.0, 21.0, 2.0, 2.0), 2);
+        tree.insert(rect(70.0, 70.0, 1.0, 1.0), 3);
+        tree.insert(rect(71.0, 70.0, 1.0, 1.0), 4);
+        check_tree(&tree);
+        assert_eq!(
+            pairs(&tree),
+            vec![(0, 1), (0, 2), (0, 3), (0, 4), (1, 2), (3, 4)]
+        );
+        let mut found: Vec<_> = tree
+            .query_rect(&rect(0.0, 0.0, 20.0, 20.0))
+            .into_iter()
+            .map(|(_, &id)| id)
+            .collect();
+        found.sort_unstable();
+        assert_eq!(found, vec![0, 1]);
+    }
+
+    #[test]
+    fn update_and_remove() {
+        let mut tree = LooseQuadTree::new(bounds());
+        let a = tree.insert(rect(10.0, 10.0, 5.0, 5.0), 'a');
+        let b = tree.insert(rect(80.0, 80.0, 5.0, 5.0), 'b');
+        assert!(pairs(&tree).is_empty());
+
+        assert!(tree.update(a, rect(78.0, 78.0, 5.0, 5.0)));
+        check_tree(&tree);
+        assert_eq!(pairs(&tree), vec![('a', 'b')]);
+        assert_eq!(tree.get(a), Some((&rect(78.0, 78.0, 5.0, 5.0), &'a')));
+
+        assert_eq!(tree.remove(b), Some('b'));
+        assert_eq!(tree.remove(b), None);
+        assert!(!tree.update(b, bounds()));
+        assert_eq!(tree.get(b), None);
+        check_tree(&tree);
+        assert!(pairs(&tree).is_empty());
+
+        // the slot is reused, but the old handle doesn't refer to the new
+        // rectangle.
+        let c = tree.insert(rect(1.0, 1.0, 1.0, 1.0), 'c');
+        assert_eq!(c.index, b.index);
+        assert_ne!(c, b);
+        assert_eq!(tree.get(b), None);
+        assert!(!tree.update(b, bounds()));
+        assert_eq!(tree.remove(b), None);
+        assert_eq!(tree.get(c), Some((&rect(1.0, 1.0, 1.0, 1.0), &'c')));
+        assert_eq!(Handle::from_bits(c.to_bits()), c);
+        assert_eq!(tree.remove(a), Some('a'));
+        assert_eq!(tree.len(), 1);
+        check_tree(&tree);
+    }
+
+    #[test]
+    fn outside_of_bounds() {
+        let mut tree = LooseQuadTree::new(bounds());
+        tree.insert(rect(-50.0, -50.0, 10.0, 10.0), 0);
+        tree.insert(rect(-45.0, -45.0, 50.0, 50.0), 1);
+        tree.insert(rect(1.0, 1.0, 1.0, 1.0), 2);
+        tree.insert(rect(f64::NAN, 0.0, 1.0, 1.0), 3);
+        check_tree(&tree);
+        assert_eq!(tree.root.entries.len(), 3);
+        assert_eq!(pairs(&tree), vec![(0, 1), (1, 2)]);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    mod properties {
+        use proptest::prelude::*;
+
+        use super::*;
+
+        #[derive(Debug, Clone)]
+        enum Operation {
+            Insert(Region<f64>),
+            /// Update or remove the rectangle at this index in the ones
+            /// inserted so far, wrapping around.
+            Update(usize, Region<f64>),
+            Remove(usize),
+        }
+
+        /// Rectangles of all sizes, mostly in the bounds, sometimes on a
+        /// coarse grid so that edges touch and line up with cells.
+        fn any_rect() -> impl Strategy<Value = Region<f64>> {
+            prop_oneof![
+                (-20.0..120.0, -20.0..120.0, 0.0..30.0, 0.0..30.0)
+                    .prop_map(|(x, y, w, h)| rect(x, y, w, h)),
+                (0..8u8, 0..8u8, 0..4u8, 0..4u8).prop_map(|(x, y, w, h)| rect(
+                    x as f64 * 12.5,
+                    y as f64 * 12.5,
+                    w as f64 * 6.25,
+                    h as f64 * 6.25
+                )),
+                (0.0..100.0, 0.0..100.0, 50.0..150.0, 0.0..150.0).prop_map(|(x, y, w, h)| rect(
+                    x - w / 2.0,
+                    y - h / 2.0,
+                    w,
+                    h
+                )),
+            ]
+        }
+
+        fn operation() -> impl Strategy<Value = Operation> {
+            prop_oneof![
+                3 => any_rect().prop_map(Operation::Insert),
+                2 => (any::<usize>(), any_rect()).prop_map(|(i, r)| Operation::Update(i, r)),
+                1 => any::<usize>().prop_map(Operation::Remove),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn same_as_brute_force(
+                operations in prop::collection::vec(operation(), 0..200),
+                query in any_rect(),
+            ) {
+                let mut tree = LooseQuadTree::new(bounds());
+                let mut oracle: Vec<(Handle, Region<f64>, usize)> = vec![];
+                for (id, operation) in operations.into_iter().enumerate() {
+                    match operation {
+                        Operation::Insert(rect) => {
+                            let handle = tree.insert(rect.clone(), id);
+                            oracle.push((handle, rect, id));
+                        }
+                        Operation::Update(_, _) | Operation::Remove(_) if oracle.is_empty() => {}
+                        Operation::Update(n, rect) => {
+                            let n = n % oracle.len();
+                            prop_assert!(tree.update(oracle[n].0, rect.clone()));
+                            oracle[n].1 = rect;
+                        }
+                        Operation::Remove(n) => {
+                            let (handle, _, id) = oracle.swap_remove(n % oracle.len());
+                            prop_assert_eq!(tree.remove(handle), Some(id));
+                        }
+                    }
+                }
+
+                check_tree(&tree);
+                let mut expected = vec![];
+                for (i, (_, a, id_a)) in oracle.iter().enumerate() {
+                    for (_, b, id_b) in &oracle[i + 1..] {
+                        if a.intersects(b) {
+                            expected.push((*id_a.min(id_b), *id_a.max(id_b)));
+                        }
+                    }
+                }
+                expected.sort_unstable();
+                prop_assert_eq!(pairs(&tree), expected);
+
+                let mut found: Vec<_> = tree.query_rect(&query).into_iter().map(|(_, &id)| id).collect();
+                found.sort_unstable();
+                let mut expected: Vec<_> = oracle
+                    .iter()
+                    .filter(|(_, r, _)| r.intersects(&query))
+                    .map(|&(_, _, id)| id)
+                    .collect();
+                expected.sort_unstable();
+                prop_assert_eq!(found, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Morton (Z-order) indexing for the complete quad tree.
+//!
+//! The complete quad tree numbers its nodes level by level, root first.
+//! Within a level, nodes are in Morton order: the position of a node in
+//! its level interleaves the bits of its coordinates, y in the even bits
+//! and x in the odd bits. So the four children of a node are its top left,
+//! bottom left, top right and bottom right quadrant, in that order, and
+//! they are numbered next to each other at index 4 * parent + 1 up to
+//! 4 * parent + 4. Nodes outside of the image are not stored, so the tree
+//! looks nodes up by their index rather than store them at it.
+
+use std::ops::RangeInclusive;
+
+use crate::complete_quad_tree::Coordinate;
+
+/// Interleave the bits of the coordinate into its Morton code.
+pub(crate) fn encode(coordinate: Coordinate) -> usize {
+    let mut code = 0;
+    let mut bit = 0;
+    while (coordinate.x >> bit) > 0 || (coordinate.y >> bit) > 0 {
+        code |= ((coordinate.y >> bit) & 1) << (2 * bit);
+        code |= ((coordinate.x >> bit) & 1) << (2 * bit + 1);
+        bit += 1;
+    }
+    code
+}
+
+/// The coordinate with the given Morton code.
+pub(crate) fn decode(code: usize) -> Coordinate {
+    let mut coordinate = Coordinate { x: 0, y: 0 };
+    let mut bit = 0;
+    while (code >> (2 * bit)) > 0 {
+        coordinate.y |= ((code >> (2 * bit)) & 1) << bit;
+        coordinate.x |= ((code >> (2 * bit + 1)) & 1) << bit;
+        bit += 1;
+    }
+    coordinate
+}
+
+/// Index of the first node at the given level, i.e. the number
+/// of nodes in all the levels above it.
+pub(crate) fn level_offset(level: u32) -> usize {
+    (4usize.pow(level) - 1) / 3
+}
+
+/// Index of the node at the given level that contains the given
+/// coordinate, where the coordinate is expressed in units of that level.
+pub(crate) fn index(coordinate: Coordinate, level: u32) -> usize {
+    level_offset(level) + encode(coordinate)
+}
+
+/// The parent of the node at the given index, or None for the root.
+pub(crate) fn parent(index: usize) -> Option<usize> {
+    if index == 0 {
+        None
+    } else {
+        Some((index - 1) / 4)
+    }
+}
+
+/// The indices of the four children of the node at the given index.
+pub(crate) fn children(index: usize) -> RangeInclusive<usize> {
+    4 * index + 1..=4 * index + 4
+}
+
+/// The indices of the node at the given index and its three siblings.
+/// The root is its own only sibling.
+#[cfg(test)]
+pub(crate) fn siblings(index: usize) -> RangeInclusive<usize> {
+    match parent(index) {
+        Some(parent) => children(parent),
+        None => 0..=0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        assert_eq!(encode(Coordinate { x: 0, y: 0 }), 0);
+        assert_eq!(encode(Coordinate { x: 0, y: 1 }), 1);
+        assert_eq!(encode(Coordinate { x: 1, y: 0 }), 2);
+        assert_eq!(encode(Coordinate { x: 1, y: 1 }), 3);
+        assert_eq!(encode(Coordinate { x: 2, y: 0 }), 8);
+        assert_eq!(encode(Coordinate { x: 3, y: 5 }), 0b011011);
+
+        for x in 0..64 {
+            for y in 0..64 {
+                let coordinate = Coordinate { x, y };
+                assert_eq!(decode(encode(coordinate)), coordinate);
+            }
+        }
+        for code in 0..4096 {
+            assert_eq!(encode(decode(code)), code);
+        }
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(level_offset(0), 0);
+        assert_eq!(level_offset(1), 1);
+        assert_eq!(level_offset(2), 5);
+        assert_eq!(level_offset(3), 21);
+        assert_eq!(index(Coordinate { x: 1, y: 1 }, 1), 4);
+    }
+
+    #[test]
+    fn parent_child_sibling() {
+        assert_eq!(parent(0), None);
+        assert_eq!(parent(1), Some(0));
+        assert_eq!(parent(4), Some(0));
+        assert_eq!(parent(5), Some(1));
+        assert_eq!(children(0), 1..=4);
+        assert_eq!(children(2), 9..=12);
+        assert_eq!(siblings(0), 0..=0);
+        assert_eq!(siblings(11), 9..=12);
+
+        // the child of a node at a level contains the coordinate with
+        // one more bit of precision, in the next level.
+        for level in 0..4 {
+            let side = 1 << level;
+            for x in 0..side {
+                for y in 0..side {
+                    let node = index(Coordinate { x, y }, level);
+                    for (quadrant, child) in children(node).enumerate() {
+                        let expected = Coordinate {
+                            x: 2 * x + (quadrant >> 1),
+                            y: 2 * y + (quadrant & 1),
+                        };
+                        assert_eq!(child, index(expected, level + 1));
+                        assert_eq!(parent(child), Some(node));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Best-first search for the items nearest to a point, shared by the
+//! quadtrees. Nodes and items wait in a single priority queue, ordered by
+//! their (minimum) distance to the point. A node's distance is never more
+//! than that of anything in it, so when an item comes out of the queue,
+//! no item that is still in the queue or in a node in it is nearer.
+
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+enum Candidate<N, I> {
+    Node(N),
+    Item(I),
+}
+
+struct Entry<N, I> {
+    distance_squared: f64,
+    /// The order in which the entries were pushed, to break ties.
+    order: usize,
+    candidate: Candidate<N, I>,
+}
+
+impl<N, I> PartialEq for Entry<N, I> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<N, I> Eq for Entry<N, I> {}
+
+impl<N, I> PartialOrd for Entry<N, I> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Reversed, so that the max-heap pops the nearest entry first.
+impl<N, I> Ord for Entry<N, I> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .distance_squared
+            .total_cmp(&self.distance_squared)
+            .then_with(|| other.order.cmp(&self.order))
+    }
+}
+
+/// A best-first search over nodes of type N, finding items of type I.
+pub(crate) struct BestFirst<N, I> {
+    queue: BinaryHeap<Entry<N, I>>,
+    pushed: usize,
+    max_distance_squared: f64,
+}
+
+impl<N, I> BestFirst<N, I> {
+    /// A search for the items at most max_distance away, if given.
+    pub(crate) fn new(max_distance: Option<f64>) -> BestFirst<N, I> {
+        let max_distance = max_distance.unwrap_or(f64::INFINITY);
+        BestFirst {
+            queue: BinaryHeap::new(),
+            pushed: 0,
+            max_distance_squared: max_distance * max_distance,
+        }
+    }
+
+    fn push(&mut self, distance_squared: f64, candidate: Candidate<N, I>) {
+        if distance_squared <= self.max_distance_squared {
+            self.queue.push(Entry {
+                distance_squared,
+                order: self.pushed,
+                candidate,
+            });
+            self.pushed += 1;
+        }
+    }
+
+    /// Add a node, at the given squared distance to its nearest point.
+    pub(crate) fn push_node(&mut self, distance_squared: f64, node: N) {
+        self.push(distance_squared, Candidate::Node(node));
+    }
+
+    /// Add an item, at the given squared distance.
+    pub(crate) fn push_item(&mut self, distance_squared: f64, item: I) {
+        self.push(distance_squared, Candidate::Item(item));
+    }
+
+    /// The next nearest item and its squared distance, or None if there are
+    /// no items left. expand pushes the children or items of a node.
+    pub(crate) fn next(&mut self, mut expand: impl FnMut(N, &mut Self)) -> Option<(f64, I)> {
+        while let Some(entry) = self.queue.pop() {
+            match entry.candidate {
+                Candidate::Node(node) => expand(node, self),
+                Candidate::Item(item) => return Some((entry.distance_squared, item)),
+            }
+        }
+        None
+    }
+
+    /// The k nearest items, nearest first.
+    pub(crate) fn take(&mut self, k: usize, mut expand: impl FnMut(N, &mut Self)) -> Vec<I> {
+        let mut result = Vec::with_capacity(k.min(64));
+        while result.len() < k {
+            match self.next(&mut expand) {
+                Some((_, item)) => result.push(item),
+                None => break,
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_first() {
+        let numbers = [3.0, 17.0, 11.0, 25.0, 29.0, 5.0, 21.0];
+        let target = 12.0;
+        let mut search = BestFirst::new(Some(10.0));
+        // the nodes are the decades 0 to 2, which contain the numbers in them.
+        for decade in 0..3 {
+            let low = decade as f64 * 10.0;
+            let distance = (low - target).max(target - (low + 10.0)).max(0.0);
+            search.push_node(distance * distance, decade);
+        }
+        let expand = |decade: usize, search: &mut BestFirst<usize, f64>| {
+            let low = decade as f64 * 10.0;
+            for &n in numbers.iter().filter(|&&n| (low..low + 10.0).contains(&n)) {
+                search.push_item((n - target) * (n - target), n);
+            }
+        };
+        // 25 and 29 are too far away, 3 and 21 are as far but 3 was found first.
+        assert_eq!(search.take(10, expand), vec![11.0, 17.0, 5.0, 3.0, 21.0]);
+    }
+}
@@ -0,0 +1,281 @@
+//! Debug renderings of the leaves of a quadtree, to see the partition while
+//! tuning thresholds: leaves filled with their mean color, or colored by
+//! their depth or error on a heatmap, with optional borders around them.
+
+use crate::{
+    error::{check_rgba_len, QuadtreeError},
+    image::{Image, Rgba},
+    region::Region,
+    region_quad_tree::LeafInfo,
+};
+
+/// How to fill the leaves of an overlay.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LeafColoring {
+    /// The mean color of the leaf, like the result image.
+    #[default]
+    Mean,
+    /// A heatmap of the depth of the leaf, from blue for the root to red
+    /// for the deepest leaf.
+    Depth,
+    /// A heatmap of the error of the leaf, from blue for no error to red
+    /// for the largest error of any leaf.
+    Error,
+}
+
+/// The names of the leaf colorings, as accepted by `LeafColoring::by_name`.
+pub const LEAF_COLORING_NAMES: [&str; 3] = ["mean", "depth", "error"];
+
+impl LeafColoring {
+    /// The leaf coloring with the given name, one of `LEAF_COLORING_NAMES`.
+    pub fn by_name(name: &str) -> Option<LeafColoring> {
+        match name {
+            "mean" => Some(LeafColoring::Mean),
+            "depth" => Some(LeafColoring::Depth),
+            "error" => Some(LeafColoring::Error),
+            _ => None,
+        }
+    }
+}
+
+/// A border drawn on the inside of the edges of every leaf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Border {
+    pub color: Rgba,
+    /// The thickness of the border, in pixels.
+    pub thickness: usize,
+}
+
+/// Options for the overlay images of `RegionQuadTreeImage` and `QuadTree`.
+/// The default has no borders and fills leaves with their mean color.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OverlayOptions {
+    pub border: Option<Border>,
+    pub coloring: LeafColoring,
+}
+
+impl OverlayOptions {
+    /// The options as given to the overlay methods exported to JavaScript:
+    /// a coloring name, and a border in the given RGBA color if its
+    /// thickness is greater than 0.
+    pub(crate) fn from_js(
+        coloring: &str,
+        border_rgba: &[u8],
+        border_thickness: usize,
+    ) -> Result<OverlayOptions, QuadtreeError> {
+        let coloring = LeafColoring::by_name(coloring)
+            .ok_or_else(|| QuadtreeError::UnknownLeafColoring(coloring.to_string()))?;
+        let border = if border_thickness > 0 {
+            check_rgba_len(1, border_rgba)?;
+            Some(Border {
+                color: Rgba::from_slice(border_rgba),
+                thickness: border_thickness,
+            })
+        } else {
+            None
+        };
+        Ok(OverlayOptions { border, coloring })
+    }
+}
+
+/// The color at t, from 0 to 1, on a heatmap that goes from blue through
+/// cyan, green and yellow to red.
+fn heat(t: f64) -> Rgba {
+    const STOPS: [[f64; 3]; 5] = [
+        [0.0, 0.0, 255.0],
+        [0.0, 255.0, 255.0],
+        [0.0, 255.0, 0.0],
+        [255.0, 255.0, 0.0],
+        [255.0, 0.0, 0.0],
+    ];
+    let scaled = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f64;
+    let i = (scaled as usize).min(STOPS.len() - 2);
+    let f = scaled - i as f64;
+    let [red, green, blue] =
+        [0, 1, 2].map(|c| (STOPS[i][c] + f * (STOPS[i + 1][c] - STOPS[i][c])).round() as u8);
+    Rgba {
+        red,
+        green,
+        blue,
+        alpha: 255,
+    }
+}
+
+/// Fill the given rectangle of the image, clipped to the image.
+fn fill(image: &mut Image, x: usize, y: usize, width: usize, height: usize, color: Rgba) {
+    for y in y..(y + height).min(image.height()) {
+        for x in x..(x + width).min(image.width()) {
+            image.set_index(x, y, color);
+        }
+    }
+}
+
+/// An image of the given size with each leaf filled according to the
+/// coloring. Borders are drawn along the top and left edge of each leaf,
+/// and along the right and bottom edge of the image, so that the border
+/// between two leaves is as thick as the one around the image.
+pub(crate) fn render(
+    width: usize,
+    height: usize,
+    leaves: &[LeafInfo],
+    options: &OverlayOptions,
+) -> Image {
+    let max_depth = leaves.iter().map(|l| l.depth).max().unwrap_or(0);
+    let max_error = leaves.iter().map(|l| l.error).fold(0.0, f32::max);
+    let mut image = Image::empty(width, height);
+    for leaf in leaves {
+        let color = match options.coloring {
+            LeafColoring::Mean => leaf.mean,
+            LeafColoring::Depth if max_depth == 0 => heat(0.0),
+            LeafColoring::Depth => heat(leaf.depth as f64 / max_depth as f64),
+            LeafColoring::Error if max_error == 0.0 => heat(0.0),
+            LeafColoring::Error => heat((leaf.error / max_error) as f64),
+        };
+        let Region {
+            x,
+            y,
+            width,
+            height,
+        } = leaf.region;
+        fill(&mut image, x, y, width, height, color);
+    }
+    if let Some(Border { color, thickness }) = options.border {
+        for leaf in leaves {
+            let Region {
+                x,
+                y,
+                width,
+                height,
+            } = leaf.region;
+            fill(&mut image, x, y, width, thickness.min(height), color);
+            fill(&mut image, x, y, thickness.min(width), height, color);
+        }
+        let thickness_x = thickness.min(width);
+        let thickness_y = thickness.min(height);
+        fill(
+            &mut image,
+            0,
+            height - thickness_y,
+            width,
+            thickness_y,
+            color,
+        );
+        fill(
+            &mut image,
+            width - thickness_x,
+            0,
+            thickness_x,
+            height,
+            color,
+        );
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(x: usize, y: usize, size: usize, depth: u32, error: f32) -> LeafInfo {
+        LeafInfo {
+            region: Region {
+                x,
+                y,
+                width: size,
+                height: size,
+            },
+            mean: Rgba::from_slice(&[10, 20, 30, 255]),
+            depth,
+            error,
+        }
+    }
+
+    #[test]
+    fn heatmap() {
+        assert_eq!(heat(0.0), Rgba::from_slice(&[0, 0, 255, 255]));
+        assert_eq!(heat(0.5), Rgba::from_slice(&[0, 255, 0, 255]));
+        assert_eq!(heat(1.0), Rgba::from_slice(&[255, 0, 0, 255]));
+        assert_eq!(heat(0.125), Rgba::from_slice(&[0, 128, 255, 255]));
+    }
+
+    #[test]
+    fn colorings_and_borders() {
+        // a 4x4 leaf next to four 2x2 leaves.
+        let leaves = [
+            leaf(0, 0, 4, 1, 0.0),
+            leaf(4, 0, 2, 2, 1.0),
+            leaf(6, 0, 2, 2, 4.0),
+            leaf(4, 2, 2, 2, 2.0),
+            leaf(6, 2, 2, 2, 0.0),
+        ];
+        let mean = render(8, 4, &leaves, &OverlayOptions::default());
+        assert!(mean.pixels().iter().all(|&p| p == leaves[0].mean));
+
+        let depth = OverlayOptions {
+            border: None,
+            coloring: LeafColoring::Depth,
+        };
+        let depth = render(8, 4, &leaves, &depth);
+        assert_eq!(depth.get_pixel(0, 0), heat(0.5));
+        assert_eq!(depth.get_pixel(7, 3), heat(1.0));
+
+        let error = OverlayOptions {
+            border: None,
+            coloring: LeafColoring::Error,
+        };
+        let error = render(8, 4, &leaves, &error);
+        assert_eq!(error.get_pixel(0, 0), heat(0.0));
+        assert_eq!(error.get_pixel(7, 0), heat(1.0));
+        assert_eq!(error.get_pixel(4, 3), heat(0.5));
+
+        let black = Rgba::from_slice(&[0, 0, 0, 255]);
+        let border = OverlayOptions {
+            border: Some(Border {
+                color: black,
+                thickness: 1,
+            }),
+            coloring: LeafColoring::Mean,
+        };
+        let border = render(8, 4, &leaves, &border);
+        let rows: Vec<String> = (0..4)
+            .map(|y| {
+                (0..8)
+                    .map(|x| {
+                        if border.get_pixel(x, y) == black {
+                            '#'
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(rows, ["########", "#...#.##", "#...####", "########"]);
+    }
+
+    #[test]
+    fn from_js() {
+        assert_eq!(
+            OverlayOptions::from_js("depth", &[], 0),
+            Ok(OverlayOptions {
+                border: None,
+                coloring: LeafColoring::Depth
+            })
+        );
+        assert_eq!(
+            OverlayOptions::from_js("error", &[1, 2, 3, 4], 2),
+            Ok(OverlayOptions {
+                border: Some(Border {
+                    color: Rgba::from_slice(&[1, 2, 3, 4]),
+                    thickness: 2
+                }),
+                coloring: LeafColoring::Error
+            })
+        );
+        assert_eq!(
+            OverlayOptions::from_js("heat", &[], 0),
+            Err(QuadtreeError::UnknownLeafColoring("heat".to_string()))
+        );
+        assert!(OverlayOptions::from_js("mean", &[0, 0], 1).is_err());
+    }
+}
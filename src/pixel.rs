@@ -0,0 +1,266 @@
+//! The pixel types an `Image` can hold. The quadtrees only need to
+//! average pixels and measure how far apart they are, channel by channel,
+//! so any type with numeric channels will do.
+
+use std::fmt::Debug;
+
+use crate::{error::QuadtreeError, image::Rgba};
+
+/// A pixel made of a fixed number of numeric channels.
+pub trait Pixel: Copy + Debug + PartialEq + 'static {
+    /// The number of channels.
+    const CHANNELS: usize;
+    /// How much each channel counts in the squared error.
+    const WEIGHTS: &'static [f64];
+
+    /// The pixel with all channels 0.
+    fn zero() -> Self;
+
+    /// The value of the given channel, from 0 to CHANNELS - 1.
+    fn channel(&self, channel: usize) -> f64;
+
+    /// The pixel with the given channel values. Integer channels are
+    /// truncated, and clamped to the range of their type.
+    fn from_channels(channels: &[f64]) -> Self;
+
+    /// The mean of the given pixels, channel by channel.
+    /// An error if there are none.
+    fn mean(pixels: &[Self]) -> Result<Self, QuadtreeError> {
+        if pixels.is_empty() {
+            return Err(QuadtreeError::NoPixels);
+        }
+        let mut sums = vec![0.0; Self::CHANNELS];
+        for pixel in pixels {
+            for (c, sum) in sums.iter_mut().enumerate() {
+                *sum += pixel.channel(c);
+            }
+        }
+        for sum in &mut sums {
+            *sum /= pixels.len() as f64;
+        }
+        Ok(Self::from_channels(&sums))
+    }
+
+    /// The squared differences of the channels, weighted and summed.
+    fn squared_error(&self, other: &Self) -> f64 {
+        (0..Self::CHANNELS)
+            .map(|c| Self::WEIGHTS[c] * (self.channel(c) - other.channel(c)).powi(2))
+            .sum()
+    }
+}
+
+/// The mean of the given pixels, each weighted by the given count.
+pub(crate) fn weighted_mean<P: Pixel>(pixels: &[(P, usize)]) -> P {
+    let mut sums = vec![0.0; P::CHANNELS];
+    let mut len = 0;
+    for (pixel, count) in pixels {
+        for (c, sum) in sums.iter_mut().enumerate() {
+            *sum += pixel.channel(c) * *count as f64;
+        }
+        len += count;
+    }
+    for sum in &mut sums {
+        *sum /= len as f64;
+    }
+    P::from_channels(&sums)
+}
+
+/// The channel-wise minimum of the two pixels.
+pub(crate) fn min<P: Pixel>(a: &P, b: &P) -> P {
+    let channels: Vec<_> = (0..P::CHANNELS)
+        .map(|c| a.channel(c).min(b.channel(c)))
+        .collect();
+    P::from_channels(&channels)
+}
+
+/// The channel-wise maximum of the two pixels.
+pub(crate) fn max<P: Pixel>(a: &P, b: &P) -> P {
+    let channels: Vec<_> = (0..P::CHANNELS)
+        .map(|c| a.channel(c).max(b.channel(c)))
+        .collect();
+    P::from_channels(&channels)
+}
+
+/// The weights of red, green and blue: how sensitive the eye is to each.
+pub(crate) const LUMA_WEIGHTS: [f64; 3] = [0.2989, 0.5870, 0.1140];
+
+/// A grayscale pixel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Luma<T>(pub T);
+
+/// A color pixel without alpha.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rgb<T> {
+    pub red: T,
+    pub green: T,
+    pub blue: T,
+}
+
+/// A color pixel with 16 bits per channel. The 8 bit `Rgba` is exported
+/// to JavaScript, which is why this is a separate type rather than a
+/// parameter of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rgba16 {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+    pub alpha: u16,
+}
+
+macro_rules! impl_luma {
+    ($t:ty) => {
+        impl Pixel for Luma<$t> {
+            const CHANNELS: usize = 1;
+            const WEIGHTS: &'static [f64] = &[1.0];
+
+            fn zero() -> Self {
+                Luma(0)
+            }
+
+            fn channel(&self, _channel: usize) -> f64 {
+                self.0 as f64
+            }
+
+            fn from_channels(channels: &[f64]) -> Self {
+                Luma(channels[0] as $t)
+            }
+        }
+    };
+}
+
+impl_luma!(u8);
+impl_luma!(u16);
+
+impl Pixel for Rgb<u8> {
+    const CHANNELS: usize = 3;
+    const WEIGHTS: &'static [f64] = &LUMA_WEIGHTS;
+
+    fn zero() -> Self {
+        Rgb::default()
+    }
+
+    fn channel(&self, channel: usize) -> f64 {
+        [self.red, self.green, self.blue][channel] as f64
+    }
+
+    fn from_channels(channels: &[f64]) -> Self {
+        Rgb {
+            red: channels[0] as u8,
+            green: channels[1] as u8,
+            blue: channels[2] as u8,
+        }
+    }
+}
+
+/// Alpha doesn't count in the squared error, as in `Rgba::mse`.
+impl Pixel for Rgba {
+    const CHANNELS: usize = 4;
+    const WEIGHTS: &'static [f64] = &[LUMA_WEIGHTS[0], LUMA_WEIGHTS[1], LUMA_WEIGHTS[2], 0.0];
+
+    fn zero() -> Self {
+        Rgba::new()
+    }
+
+    fn channel(&self, channel: usize) -> f64 {
+        [self.red, self.green, self.blue, self.alpha][channel] as f64
+    }
+
+    fn from_channels(channels: &[f64]) -> Self {
+        Rgba {
+            red: channels[0] as u8,
+            green: channels[1] as u8,
+            blue: channels[2] as u8,
+            alpha: channels[3] as u8,
+        }
+    }
+}
+
+impl Pixel for Rgba16 {
+    const CHANNELS: usize = 4;
+    const WEIGHTS: &'static [f64] = Rgba::WEIGHTS;
+
+    fn zero() -> Self {
+        Rgba16::default()
+    }
+
+    fn channel(&self, channel: usize) -> f64 {
+        [self.red, self.green, self.blue, self.alpha][channel] as f64
+    }
+
+    fn from_channels(channels: &[f64]) -> Self {
+        Rgba16 {
+            red: channels[0] as u16,
+            green: channels[1] as u16,
+            blue: channels[2] as u16,
+            alpha: channels[3] as u16,
+        }
+    }
+}
+
+/// A single float channel, for example a height map.
+impl Pixel for f32 {
+    const CHANNELS: usize = 1;
+    const WEIGHTS: &'static [f64] = &[1.0];
+
+    fn zero() -> Self {
+        0.0
+    }
+
+    fn channel(&self, _channel: usize) -> f64 {
+        *self as f64
+    }
+
+    fn from_channels(channels: &[f64]) -> Self {
+        channels[0] as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip<P: Pixel>(pixel: P) {
+        let channels: Vec<_> = (0..P::CHANNELS).map(|c| pixel.channel(c)).collect();
+        assert_eq!(P::from_channels(&channels), pixel);
+        assert_eq!(pixel.squared_error(&pixel), 0.0);
+    }
+
+    #[test]
+    fn channels_roundtrip() {
+        roundtrip(Luma(200u8));
+        roundtrip(Luma(60000u16));
+        roundtrip(Rgb {
+            red: 1u8,
+            green: 2,
+            blue: 3,
+        });
+        roundtrip(Rgba::from_slice(&[1, 2, 3, 4]));
+        roundtrip(Rgba16 {
+            red: 1000,
+            green: 2000,
+            blue: 3000,
+            alpha: 65535,
+        });
+        roundtrip(-1.5f32);
+    }
+
+    #[test]
+    fn mean() {
+        assert_eq!(Luma::mean(&[Luma(1u16), Luma(65535)]), Ok(Luma(32768)));
+        assert_eq!(f32::mean(&[1.0, 2.0]), Ok(1.5));
+        // integer channels are truncated.
+        assert_eq!(Luma::mean(&[Luma(1u8), Luma(2)]), Ok(Luma(1)));
+        assert_eq!(Luma::<u8>::mean(&[]), Err(QuadtreeError::NoPixels));
+    }
+
+    #[test]
+    fn squared_error() {
+        assert_eq!(Luma(10u16).squared_error(&Luma(13)), 9.0);
+        assert_eq!(2.0f32.squared_error(&-1.0), 9.0);
+        let black = Rgba::new();
+        let transparent_white = Rgba::from_slice(&[255, 255, 255, 0]);
+        let opaque_black = Rgba::from_slice(&[0, 0, 0, 255]);
+        assert!((black.squared_error(&transparent_white) - 255.0 * 255.0).abs() < 10.0);
+        assert_eq!(black.squared_error(&opaque_black), 0.0);
+    }
+}
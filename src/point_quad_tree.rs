@@ -0,0 +1,630 @@
+//! A point-region (PR) quadtree: indexes points in a rectangle of the plane,
+//! each with a payload. Every leaf holds at most a fixed number of points,
+//! its bucket capacity, and splits into four equal quadrants when it
+//! overflows. Removing points merges quadrants back together when they
+//! fit in a single leaf again.
+
+use std::array;
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::{
+    error::QuadtreeError,
+    nearest::BestFirst,
+    region::{Point, Region},
+};
+
+/// Leaves this deep never split, however many points they hold, so
+/// that more points than the capacity at the same position don't make
+/// the tree split forever.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone)]
+enum Node<T> {
+    Leaf(Vec<(Point, T)>),
+    Branch(Box<[Node<T>; 4]>),
+}
+
+impl<T> Node<T> {
+    fn insert(
+        &mut self,
+        region: &Region<f64>,
+        depth: usize,
+        capacity: usize,
+        point: Point,
+        payload: T,
+    ) {
+        match self {
+            Node::Branch(children) => {
+                let quadrant = region.quadrant_of(&point);
+                let child_region = &region.quadrants()[quadrant];
+                children[quadrant].insert(child_region, depth + 1, capacity, point, payload);
+            }
+            Node::Leaf(points) => {
+                points.push((point, payload));
+                if points.len() > capacity && depth < MAX_DEPTH {
+                    let points = std::mem::take(points);
+                    *self = Node::Branch(Box::new(array::from_fn(|_| Node::Leaf(vec![]))));
+                    for (point, payload) in points {
+                        self.insert(region, depth, capacity, point, payload);
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, region: &Region<f64>, capacity: usize, point: &Point) -> Option<T> {
+        match self {
+            Node::Leaf(points) => {
+                let index = points.iter().position(|(p, _)| p == point)?;
+                Some(points.swap_remove(index).1)
+            }
+            Node::Branch(children) => {
+                let quadrant = region.quadrant_of(point);
+                let child_region = &region.quadrants()[quadrant];
+                let removed = children[quadrant].remove(child_region, capacity, point)?;
+                self.merge_if_fits(capacity);
+                Some(removed)
+            }
+        }
+    }
+
+    /// Replace this branch by a single leaf, if its children are all leaves
+    /// and their points fit in one.
+    fn merge_if_fits(&mut self, capacity: usize) {
+        let Node::Branch(children) = self else {
+            return;
+        };
+        let mut len = 0;
+        for child in children.iter() {
+            match child {
+                Node::Leaf(points) => len += points.len(),
+                Node::Branch(_) => return,
+            }
+        }
+        if len > capacity {
+            return;
+        }
+        let mut merged = Vec::with_capacity(len);
+        for child in children.iter_mut() {
+            if let Node::Leaf(points) = child {
+                merged.append(points);
+            }
+        }
+        *self = Node::Leaf(merged);
+    }
+
+    /// Push the points in this node for which contains is true onto result,
+    /// skipping the quadrants for which overlaps is false.
+    fn query<'a>(
+        &'a self,
+        region: &Region<f64>,
+        overlaps: &impl Fn(&Region<f64>) -> bool,
+        contains: &impl Fn(&Point) -> bool,
+        result: &mut Vec<(Point, &'a T)>,
+    ) {
+        match self {
+            Node::Leaf(points) => result.extend(
+                points
+                    .iter()
+                    .filter(|(p, _)| contains(p))
+                    .map(|(p, t)| (*p, t)),
+            ),
+            Node::Branch(children) => {
+                for (child, child_region) in children.iter().zip(region.quadrants()) {
+                    if overlaps(&child_region) {
+                        child.query(&child_region, overlaps, contains, result);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A point-region quadtree over a fixed rectangle of the plane, whose
+/// points each carry a payload of type T.
+#[derive(Debug, Clone)]
+pub struct PointQuadTree<T> {
+    bounds: Region<f64>,
+    capacity: usize,
+    root: Node<T>,
+    len: usize,
+}
+
+impl<T> PointQuadTree<T> {
+    /// Create an empty tree for points in the given bounds, where each leaf
+    /// holds up to capacity points. The bounds include their left and top
+    /// edges but not their right and bottom ones. An error if the bounds
+    /// are not finite, or their width or height is not positive.
+    pub fn new(bounds: Region<f64>, capacity: usize) -> Result<PointQuadTree<T>, QuadtreeError> {
+        let Region {
+            x,
+            y,
+            width,
+            height,
+        } = bounds;
+        let finite = [x, y, x + width, y + height].iter().all(|v| v.is_finite());
+        if !finite || width <= 0.0 || height <= 0.0 {
+            return Err(QuadtreeError::InvalidBounds {
+                x,
+                y,
+                width,
+                height,
+            });
+        }
+        Ok(PointQuadTree {
+            bounds,
+            capacity: capacity.max(1),
+            root: Node::Leaf(vec![]),
+            len: 0,
+        })
+    }
+
+    pub fn bounds(&self) -> &Region<f64> {
+        &self.bounds
+    }
+
+    /// The number of points in the tree.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add a point with the given payload. Points at the same position
+    /// are allowed. An error if the point is outside of the bounds.
+    pub fn insert(&mut self, point: Point, payload: T) -> Result<(), QuadtreeError> {
+        if !self.bounds.contains(&point) {
+            return Err(QuadtreeError::PointOutOfBounds {
+                x: point.x,
+                y: point.y,
+            });
+        }
+        self.root
+            .insert(&self.bounds, 0, self.capacity, point, payload);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove a point at exactly the given position, and return its payload.
+    /// If there are several, which one is removed is unspecified.
+    /// None if there are none.
+    pub fn remove(&mut self, point: &Point) -> Option<T> {
+        if !self.bounds.contains(point) {
+            return None;
+        }
+        let removed = self.root.remove(&self.bounds, self.capacity, point)?;
+        self.len -= 1;
+        Some(removed)
+    }
+
+    fn query(
+        &self,
+        overlaps: impl Fn(&Region<f64>) -> bool,
+        contains: impl Fn(&Point) -> bool,
+    ) -> Vec<(Point, &T)> {
+        let mut result = vec![];
+        self.root
+            .query(&self.bounds, &overlaps, &contains, &mut result);
+        result
+    }
+
+    /// All the points in the tree, with their payloads, in no particular order.
+    pub fn points(&self) -> Vec<(Point, &T)> {
+        self.query(|_| true, |_| true)
+    }
+
+    /// The points in the given rectangle, edges included, with their payloads.
+    pub fn query_rect(&self, rect: &Region<f64>) -> Vec<(Point, &T)> {
+        self.query(|r| r.intersects(rect), |p| rect.contains_closed(p))
+    }
+
+    /// The points at most radius away from center, with their payloads.
+    /// None if the radius is negative or NaN.
+    pub fn query_circle(&self, center: &Point, radius: f64) -> Vec<(Point, &T)> {
+        if radius.is_nan() || radius < 0.0 {
+            return vec![];
+        }
+        let radius_squared = radius * radius;
+        self.query(
+            |r| r.distance_squared(center) <= radius_squared,
+            |p| p.distance_squared(center) <= radius_squared,
+        )
+    }
+
+    /// The k points nearest to the given point, nearest first, with their
+    /// payloads. Only points at most max_distance away, if given.
+    pub fn k_nearest(
+        &self,
+        point: &Point,
+        k: usize,
+        max_distance: Option<f64>,
+    ) -> Vec<(Point, &T)> {
+        let mut search = BestFirst::new(max_distance);
+        search.push_node(
+            self.bounds.distance_squared(point),
+            (&self.root, self.bounds.clone()),
+        );
+        search.take(k, |(node, region), search| match node {
+            Node::Leaf(points) => {
+                for (p, payload) in points {
+                    search.push_item(p.distance_squared(point), (*p, payload));
+                }
+            }
+            Node::Branch(children) => {
+                for (child, child_region) in children.iter().zip(region.quadrants()) {
+                    search.push_node(child_region.distance_squared(point), (child, child_region));
+                }
+            }
+        })
+    }
+
+    /// The point nearest to the given point, with its payload. Only if
+    /// it is at most max_distance away, if given.
+    pub fn nearest(&self, point: &Point, max_distance: Option<f64>) -> Option<(Point, &T)> {
+        self.k_nearest(point, 1, max_distance).pop()
+    }
+}
+
+/// A point quadtree whose payloads are numeric ids, exported to JavaScript
+/// as PointQuadTree. Queries return the ids of the points they find.
+#[wasm_bindgen(js_name = PointQuadTree)]
+pub struct JsPointQuadTree(PointQuadTree<u32>);
+
+/// Public methods, exported to JavaScript.
+#[wasm_bindgen(js_class = PointQuadTree)]
+impl JsPointQuadTree {
+    pub fn new(
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+        capacity: usize,
+    ) -> Result<JsPointQuadTree, QuadtreeError> {
+        let bounds = Region {
+            x,
+            y,
+            width,
+            height,
+        };
+        PointQuadTree::new(bounds, capacity).map(JsPointQuadTree)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn insert(&mut self, x: f64, y: f64, id: u32) -> Result<(), QuadtreeError> {
+        self.0.insert(Point::new(x, y), id)
+    }
+
+    pub fn remove(&mut self, x: f64, y: f64) -> Option<u32> {
+        self.0.remove(&Point::new(x, y))
+    }
+
+    pub fn query_rect(&self, x: f64, y: f64, width: f64, height: f64) -> Vec<u32> {
+        let rect = Region {
+            x,
+            y,
+            width,
+            height,
+        };
+        self.0
+            .query_rect(&rect)
+            .into_iter()
+            .map(|(_, &id)| id)
+            .collect()
+    }
+
+    pub fn query_circle(&self, x: f64, y: f64, radius: f64) -> Vec<u32> {
+        let center = Point::new(x, y);
+        self.0
+            .query_circle(&center, radius)
+            .into_iter()
+            .map(|(_, &id)| id)
+            .collect()
+    }
+
+    pub fn nearest(&self, x: f64, y: f64, max_distance: Option<f64>) -> Option<u32> {
+        self.0
+            .nearest(&Point::new(x, y), max_distance)
+            .map(|(_, &id)| id)
+    }
+
+    pub fn k_nearest(&self, x: f64, y: f64, k: usize, max_distance: Option<f64>) -> Vec<u32> {
+        self.0
+            .k_nearest(&Point::new(x, y), k, max_distance)
+            .into_iter()
+            .map(|(_, &id)| id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds() -> Region<f64> {
+        Region {
+            x: 0.0,
+            y: 0.0,
+            width: 100.0,
+            height: 100.0,
+        }
+    }
+
+    /// Check that every point is in the region of its leaf, that leaves
+    /// only overflow at the maximum depth, and that every branch holds
+    /// more points than fit in a leaf. Returns the number of points.
+    fn check_node<T>(node: &Node<T>, region: &Region<f64>, depth: usize, capacity: usize) -> usize {
+        match node {
+            Node::Leaf(points) => {
+                assert!(points.len() <= capacity || depth == MAX_DEPTH);
+                for (point, _) in points {
+                    assert!(region.contains(point), "{point:?} outside {region:?}");
+                }
+                points.len()
+            }
+            Node::Branch(children) => {
+                let len: usize = children
+                    .iter()
+                    .zip(region.quadrants())
+                    .map(|(child, r)| check_node(child, &r, depth + 1, capacity))
+                    .sum();
+                assert!(len > capacity);
+                len
+            }
+        }
+    }
+
+    fn check_tree<T>(tree: &PointQuadTree<T>) {
+        let len = check_node(&tree.root, &tree.bounds, 0, tree.capacity);
+        assert_eq!(len, tree.len());
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let mut tree = PointQuadTree::new(bounds(), 2).unwrap();
+        for (i, (x, y)) in [(10.0, 10.0), (60.0, 10.0), (10.0, 60.0)]
+            .into_iter()
+            .enumerate()
+        {
+            tree.insert(Point::new(x, y), i).unwrap();
+        }
+        assert!(matches!(tree.root, Node::Branch(_)));
+        check_tree(&tree);
+
+        assert_eq!(tree.remove(&Point::new(60.0, 10.0)), Some(1));
+        assert_eq!(tree.remove(&Point::new(60.0, 10.0)), None);
+        assert!(matches!(tree.root, Node::Leaf(_)));
+        check_tree(&tree);
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn same_position() {
+        let mut tree = PointQuadTree::new(bounds(), 1).unwrap();
+        for i in 0..100 {
+            tree.insert(Point::new(33.3, 66.6), i).unwrap();
+        }
+        check_tree(&tree);
+        assert_eq!(tree.query_circle(&Point::new(33.3, 66.6), 0.0).len(), 100);
+        for _ in 0..100 {
+            assert!(tree.remove(&Point::new(33.3, 66.6)).is_some());
+        }
+        assert!(tree.is_empty());
+        assert!(matches!(tree.root, Node::Leaf(_)));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let mut tree = PointQuadTree::new(bounds(), 4).unwrap();
+        assert_eq!(
+            tree.insert(Point::new(100.0, 0.0), ()),
+            Err(QuadtreeError::PointOutOfBounds { x: 100.0, y: 0.0 })
+        );
+        assert!(tree.insert(Point::new(f64::NAN, 0.0), ()).is_err());
+        assert!(tree.insert(Point::new(0.0, 99.9), ()).is_ok());
+        assert_eq!(tree.remove(&Point::new(-1.0, 0.0)), None);
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.query_circle(&Point::new(0.0, 99.9), 0.0).len(), 1);
+        assert!(tree.query_circle(&Point::new(0.0, 99.9), -1.0).is_empty());
+        assert!(tree
+            .query_circle(&Point::new(0.0, 99.9), f64::NAN)
+            .is_empty());
+
+        for (x, y, width, height) in [
+            (0.0, 0.0, -1.0, 1.0),
+            (0.0, 0.0, 1.0, 0.0),
+            (0.0, 0.0, f64::NAN, 1.0),
+            (f64::NAN, 0.0, 1.0, 1.0),
+            (0.0, f64::NEG_INFINITY, 1.0, 1.0),
+            (0.0, 0.0, 1.0, f64::INFINITY),
+        ] {
+            let bounds = Region {
+                x,
+                y,
+                width,
+                height,
+            };
+            assert!(
+                matches!(
+                    PointQuadTree::<()>::new(bounds, 4),
+                    Err(QuadtreeError::InvalidBounds { .. })
+                ),
+                "{x}, {y}, {width}, {height}"
+            );
+        }
+    }
+
+    #[test]
+    fn nearest() {
+        let mut tree = PointQuadTree::new(bounds(), 1).unwrap();
+        for (i, (x, y)) in [(10.0, 10.0), (60.0, 10.0), (10.0, 65.0), (80.0, 80.0)]
+            .into_iter()
+            .enumerate()
+        {
+            tree.insert(Point::new(x, y), i).unwrap();
+        }
+        let from = Point::new(55.0, 55.0);
+        assert_eq!(tree.nearest(&from, None).map(|(_, &i)| i), Some(3));
+        assert_eq!(tree.nearest(&from, Some(30.0)), None);
+        let ids: Vec<_> = tree
+            .k_nearest(&from, 3, None)
+            .into_iter()
+            .map(|(_, &i)| i)
+            .collect();
+        assert_eq!(ids, vec![3, 1, 2]);
+        assert_eq!(tree.k_nearest(&from, 10, Some(50.0)).len(), 3);
+        assert!(PointQuadTree::<()>::new(bounds(), 4)
+            .unwrap()
+            .nearest(&from, None)
+            .is_none());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    mod properties {
+        use proptest::prelude::*;
+
+        use super::*;
+
+        #[derive(Debug, Clone)]
+        enum Operation {
+            Insert(Point),
+            /// Remove the point at the position of the nth point
+            /// inserted so far, wrapping around.
+            RemoveExisting(usize),
+            Remove(Point),
+        }
+
+        /// Points anywhere in the bounds, or on a coarse grid so that
+        /// there are many at the same position and on quadrant edges.
+        fn point() -> impl Strategy<Value = Point> {
+            prop_oneof![
+                (0.0..100.0, 0.0..100.0).prop_map(|(x, y)| Point::new(x, y)),
+                (0..8u8, 0..8u8).prop_map(|(x, y)| Point::new(x as f64 * 12.5, y as f64 * 12.5)),
+            ]
+        }
+
+        fn operation() -> impl Strategy<Value = Operation> {
+            prop_oneof![
+                3 => point().prop_map(Operation::Insert),
+                1 => any::<usize>().prop_map(Operation::RemoveExisting),
+                1 => point().prop_map(Operation::Remove),
+            ]
+        }
+
+        fn rect() -> impl Strategy<Value = Region<f64>> {
+            (-10.0..110.0, -10.0..110.0, 0.0..60.0, 0.0..60.0).prop_map(|(x, y, width, height)| {
+                Region {
+                    x,
+                    y,
+                    width,
+                    height,
+                }
+            })
+        }
+
+        fn ids(points: Vec<(Point, &usize)>) -> Vec<usize> {
+            let mut ids: Vec<_> = points.into_iter().map(|(_, &id)| id).collect();
+            ids.sort_unstable();
+            ids
+        }
+
+        fn oracle_ids(oracle: &[(Point, usize)], filter: impl Fn(&Point) -> bool) -> Vec<usize> {
+            let mut ids: Vec<_> = oracle
+                .iter()
+                .filter(|(p, _)| filter(p))
+                .map(|&(_, id)| id)
+                .collect();
+            ids.sort_unstable();
+            ids
+        }
+
+        proptest! {
+            #[test]
+            fn same_as_brute_force(
+                capacity in 1..6usize,
+                operations in prop::collection::vec(operation(), 0..300),
+                rects in prop::collection::vec(rect(), 1..5),
+                circles in prop::collection::vec((point(), 0.0..40.0), 1..5),
+            ) {
+                let mut tree = PointQuadTree::new(bounds(), capacity).unwrap();
+                let mut oracle: Vec<(Point, usize)> = vec![];
+                for (id, operation) in operations.into_iter().enumerate() {
+                    let point = match operation {
+                        Operation::Insert(point) => {
+                            tree.insert(point, id).unwrap();
+                            oracle.push((point, id));
+                            continue;
+                        }
+                        Operation::RemoveExisting(_) if oracle.is_empty() => continue,
+                        Operation::RemoveExisting(n) => oracle[n % oracle.len()].0,
+                        Operation::Remove(point) => point,
+                    };
+                    match tree.remove(&point) {
+                        Some(id) => {
+                            let index = oracle.iter().position(|&(p, i)| i == id && p == point);
+                            prop_assert!(index.is_some());
+                            oracle.swap_remove(index.unwrap());
+                        }
+                        None => prop_assert!(oracle.iter().all(|(p, _)| *p != point)),
+                    }
+                }
+
+                check_tree(&tree);
+                prop_assert_eq!(ids(tree.points()), oracle_ids(&oracle, |_| true));
+                for rect in rects {
+                    prop_assert_eq!(
+                        ids(tree.query_rect(&rect)),
+                        oracle_ids(&oracle, |p| rect.contains_closed(p))
+                    );
+                }
+                for (center, radius) in circles {
+                    prop_assert_eq!(
+                        ids(tree.query_circle(&center, radius)),
+                        oracle_ids(&oracle, |p| p.distance_squared(&center) <= radius * radius)
+                    );
+                }
+            }
+
+            #[test]
+            fn nearest_same_as_brute_force(
+                capacity in 1..6usize,
+                points in prop::collection::vec(point(), 0..200),
+                from in (-20.0..120.0, -20.0..120.0).prop_map(|(x, y)| Point::new(x, y)),
+                k in 0..20usize,
+                max_distance in prop::option::of(0.0..80.0),
+            ) {
+                let mut tree = PointQuadTree::new(bounds(), capacity).unwrap();
+                for (id, &point) in points.iter().enumerate() {
+                    tree.insert(point, id).unwrap();
+                }
+                // ties can come in any order, so compare the distances.
+                let max = max_distance.unwrap_or(f64::INFINITY);
+                let mut expected: Vec<_> = points
+                    .iter()
+                    .map(|p| p.distance_squared(&from))
+                    .filter(|&d| d <= max * max)
+                    .collect();
+                expected.sort_by(f64::total_cmp);
+                expected.truncate(k);
+                let actual: Vec<_> = tree
+                    .k_nearest(&from, k, max_distance)
+                    .into_iter()
+                    .map(|(p, &id)| {
+                        assert_eq!(p, points[id]);
+                        p.distance_squared(&from)
+                    })
+                    .collect();
+                prop_assert_eq!(actual, expected);
+            }
+        }
+    }
+}
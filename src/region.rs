@@ -0,0 +1,348 @@
+use std::ops::{Add, Sub};
+
+/// A region of an image or a 2D plane, described by its top left corner
+/// and its width and height. In pixels for images, or in floating point
+/// coordinates for points in the plane.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region<T = usize> {
+    pub x: T,
+    pub y: T,
+    pub width: T,
+    pub height: T,
+}
+
+/// A box of voxels in a volume, described by its top left front corner
+/// and its width, height and depth.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region3 {
+    pub x: usize,
+    pub y: usize,
+    pub z: usize,
+    pub width: usize,
+    pub height: usize,
+    pub depth: usize,
+}
+
+/// A point in the plane.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Point {
+    pub fn new(x: f64, y: f64) -> Point {
+        Point { x, y }
+    }
+
+    pub fn distance_squared(&self, other: &Point) -> f64 {
+        (self.x - other.x).powi(2) + (self.y - other.y).powi(2)
+    }
+}
+
+impl<T: Copy + Add<Output = T> + Sub<Output = T>> Region<T> {
+    /// The top left, bottom left, top right and bottom right quadrants,
+    /// when split at the given width of the left and height of the top ones.
+    pub(crate) fn split_at(&self, left_width: T, top_height: T) -> [Region<T>; 4] {
+        let right_width = self.width - left_width;
+        let bottom_height = self.height - top_height;
+        [
+            Region {
+                x: self.x,
+                y: self.y,
+                width: left_width,
+                height: top_height,
+            },
+            Region {
+                x: self.x,
+                y: self.y + top_height,
+                width: left_width,
+                height: bottom_height,
+            },
+            Region {
+                x: self.x + left_width,
+                y: self.y,
+                width: right_width,
+                height: top_height,
+            },
+            Region {
+                x: self.x + left_width,
+                y: self.y + top_height,
+                width: right_width,
+                height: bottom_height,
+            },
+        ]
+    }
+}
+
+impl Region {
+    /// The top left, bottom left, top right and bottom right quadrants
+    /// of this region. If the width or height is odd, the right or bottom
+    /// quadrants are the larger ones.
+    pub(crate) fn quadrants(&self) -> [Region; 4] {
+        self.split_at(self.width / 2, self.height / 2)
+    }
+
+    /// Whether the pixel at (x, y) is inside this region.
+    pub(crate) fn contains_pixel(&self, x: usize, y: usize) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+
+    /// Whether all the pixels of the other region are inside this region.
+    pub(crate) fn contains_region(&self, other: &Region) -> bool {
+        self.x <= other.x
+            && self.y <= other.y
+            && other.x + other.width <= self.x + self.width
+            && other.y + other.height <= self.y + self.height
+    }
+
+    /// Whether this region and the other have any pixel in common.
+    pub(crate) fn intersects(&self, other: &Region) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.y < other.y + other.height
+            && other.y < self.y + self.height
+    }
+
+    /// The same region, in floating point coordinates.
+    pub(crate) fn to_f64(&self) -> Region<f64> {
+        Region {
+            x: self.x as f64,
+            y: self.y as f64,
+            width: self.width as f64,
+            height: self.height as f64,
+        }
+    }
+}
+
+impl Region3 {
+    /// This region halved along each edge longer than the given length: the
+    /// eight octants if all three edges are, in the order of the quadrants of
+    /// its front half as in `Region::quadrants`, then those of its back half,
+    /// and fewer boxes in the same order otherwise. Just this region if no
+    /// edge is. If a halved edge is odd, the right, bottom or back boxes are
+    /// the larger ones.
+    pub(crate) fn split_longer_than(&self, min_edge_length: usize) -> Vec<Region3> {
+        let halve = |length: usize| length > min_edge_length;
+        let (split_x, split_y, split_z) =
+            (halve(self.width), halve(self.height), halve(self.depth));
+        (0..8usize)
+            .map(|octant| (octant >> 2 & 1, octant >> 1 & 1, octant & 1))
+            .filter(|&(back, right, bottom)| {
+                (split_x || right == 0) && (split_y || bottom == 0) && (split_z || back == 0)
+            })
+            .map(|(back, right, bottom)| {
+                let split = |start: usize, length: usize, halved: bool, second: usize| {
+                    let first = if halved { length / 2 } else { length };
+                    if second == 1 {
+                        (start + first, length - first)
+                    } else {
+                        (start, first)
+                    }
+                };
+                let (x, width) = split(self.x, self.width, split_x, right);
+                let (y, height) = split(self.y, self.height, split_y, bottom);
+                let (z, depth) = split(self.z, self.depth, split_z, back);
+                Region3 {
+                    x,
+                    y,
+                    z,
+                    width,
+                    height,
+                    depth,
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn volume(&self) -> usize {
+        self.width * self.height * self.depth
+    }
+}
+
+impl Region<f64> {
+    /// The top left, bottom left, top right and bottom right quadrants
+    /// of this region, all of the same size.
+    pub(crate) fn quadrants(&self) -> [Region<f64>; 4] {
+        self.split_at(self.width / 2.0, self.height / 2.0)
+    }
+
+    /// The index in quadrants of the quadrant the point is in, if the
+    /// point is in this region.
+    pub(crate) fn quadrant_of(&self, point: &Point) -> usize {
+        let right = point.x >= self.x + self.width / 2.0;
+        let bottom = point.y >= self.y + self.height / 2.0;
+        (right as usize) << 1 | bottom as usize
+    }
+
+    /// Whether the point is in this region. The left and top edges are
+    /// part of the region, the right and bottom edges are not, so that
+    /// a point is in exactly one of the quadrants of a region.
+    pub fn contains(&self, point: &Point) -> bool {
+        (self.x..self.x + self.width).contains(&point.x)
+            && (self.y..self.y + self.height).contains(&point.y)
+    }
+
+    /// Whether the point is in this region, edges included.
+    pub fn contains_closed(&self, point: &Point) -> bool {
+        (self.x..=self.x + self.width).contains(&point.x)
+            && (self.y..=self.y + self.height).contains(&point.y)
+    }
+
+    /// The point in the middle of this region.
+    pub fn center(&self) -> Point {
+        Point::new(self.x + self.width / 2.0, self.y + self.height / 2.0)
+    }
+
+    /// Whether the other region is entirely in this one, edges included.
+    pub fn contains_region(&self, other: &Region<f64>) -> bool {
+        self.x <= other.x
+            && self.y <= other.y
+            && other.x + other.width <= self.x + self.width
+            && other.y + other.height <= self.y + self.height
+    }
+
+    /// Whether this region and the other one overlap, edges included.
+    pub fn intersects(&self, other: &Region<f64>) -> bool {
+        self.x <= other.x + other.width
+            && other.x <= self.x + self.width
+            && self.y <= other.y + other.height
+            && other.y <= self.y + self.height
+    }
+
+    /// The squared distance from the point to the nearest point of this
+    /// region, 0 if the point is in it.
+    pub fn distance_squared(&self, point: &Point) -> f64 {
+        let dx = (self.x - point.x)
+            .max(point.x - (self.x + self.width))
+            .max(0.0);
+        let dy = (self.y - point.y)
+            .max(point.y - (self.y + self.height))
+            .max(0.0);
+        dx * dx + dy * dy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quadrants() {
+        let region = Region {
+            x: 1,
+            y: 2,
+            width: 5,
+            height: 4,
+        };
+        let [tl, bl, tr, br] = region.quadrants();
+        assert_eq!((tl.x, tl.y, tl.width, tl.height), (1, 2, 2, 2));
+        assert_eq!((bl.x, bl.y, bl.width, bl.height), (1, 4, 2, 2));
+        assert_eq!((tr.x, tr.y, tr.width, tr.height), (3, 2, 3, 2));
+        assert_eq!((br.x, br.y, br.width, br.height), (3, 4, 3, 2));
+    }
+
+    #[test]
+    fn split_longer_than() {
+        let region = Region3 {
+            x: 1,
+            y: 2,
+            z: 3,
+            width: 5,
+            height: 4,
+            depth: 3,
+        };
+        let octants = region.split_longer_than(1);
+        assert_eq!(octants.len(), 8);
+        assert_eq!(
+            octants.iter().map(Region3::volume).sum::<usize>(),
+            region.volume()
+        );
+        for (front, back) in octants[..4].iter().zip(&octants[4..]) {
+            assert_eq!((front.z, front.depth, back.z, back.depth), (3, 1, 4, 2));
+        }
+        let quadrants = Region {
+            x: 1,
+            y: 2,
+            width: 5,
+            height: 4,
+        }
+        .quadrants();
+        for (octant, quadrant) in octants.iter().zip(quadrants.iter().cycle()) {
+            let Region3 {
+                x,
+                y,
+                width,
+                height,
+                ..
+            } = octant.clone();
+            assert_eq!(
+                Region {
+                    x,
+                    y,
+                    width,
+                    height
+                },
+                *quadrant
+            );
+        }
+
+        // only the edges longer than the minimum are halved.
+        let halves = region.split_longer_than(3);
+        assert_eq!(halves.len(), 4);
+        assert!(halves.iter().all(|half| (half.z, half.depth) == (3, 3)));
+        assert_eq!(halves[1].y, 4);
+        assert_eq!(region.split_longer_than(5), vec![region]);
+    }
+
+    #[test]
+    fn float_quadrants() {
+        let region = Region {
+            x: -1.0,
+            y: 0.0,
+            width: 2.0,
+            height: 1.0,
+        };
+        let quadrants = region.quadrants();
+        for point in [
+            Point::new(-1.0, 0.0),
+            Point::new(0.0, 0.5),
+            Point::new(-0.1, 0.9),
+            Point::new(0.99, 0.1),
+        ] {
+            let quadrant = region.quadrant_of(&point);
+            for (i, q) in quadrants.iter().enumerate() {
+                assert_eq!(q.contains(&point), i == quadrant, "{point:?} in {q:?}");
+            }
+        }
+        assert!(!region.contains(&Point::new(1.0, 0.5)));
+        assert!(region.contains_closed(&Point::new(1.0, 0.5)));
+    }
+
+    #[test]
+    fn distance() {
+        let region = Region {
+            x: 0.0,
+            y: 0.0,
+            width: 2.0,
+            height: 2.0,
+        };
+        assert_eq!(region.distance_squared(&Point::new(1.0, 1.0)), 0.0);
+        assert_eq!(region.distance_squared(&Point::new(5.0, 1.0)), 9.0);
+        assert_eq!(region.distance_squared(&Point::new(-3.0, 6.0)), 25.0);
+        assert!(region.intersects(&Region {
+            x: 2.0,
+            y: -1.0,
+            width: 1.0,
+            height: 1.0
+        }));
+        assert_eq!(region.center(), Point::new(1.0, 1.0));
+        assert!(region.contains_region(&region));
+        assert!(!region.contains_region(&Region {
+            x: 1.0,
+            y: 1.0,
+            width: 1.5,
+            height: 0.5
+        }));
+    }
+}
@@ -0,0 +1,422 @@
+//! The 3D counterpart of the region quadtree: adaptively subdivides a
+//! volume into boxes, splitting a box into its eight octants while the
+//! mean squared error of its voxels with respect to their mean is above
+//! a threshold. A box with edges no longer than the minimum is only split
+//! along its other edges, so thin volumes are split like images.
+
+use std::marker::PhantomData;
+
+use crate::{
+    error::QuadtreeError,
+    image::Image,
+    pixel::{Luma, Pixel},
+    region::Region3,
+    volume::Volume,
+};
+
+/// The 3D counterpart of `SummedAreaTable`: for every position, holds the
+/// sum of the values and the sum of the squared values of each channel in
+/// the box from the top left front of the volume up to, but not including,
+/// that position. Takes two f64 per channel per voxel.
+#[derive(Debug, Clone)]
+struct SummedVolumeTable<P: Pixel> {
+    width: usize,
+    height: usize,
+    /// The sums of each position, channel by channel.
+    sums: Vec<f64>,
+    squares: Vec<f64>,
+    pixel: PhantomData<P>,
+}
+
+impl<P: Pixel> SummedVolumeTable<P> {
+    fn new(volume: &Volume<P>) -> SummedVolumeTable<P> {
+        let (width, height, depth) = (volume.width(), volume.height(), volume.depth());
+        let channels = P::CHANNELS;
+        let len = (width + 1) * (height + 1) * (depth + 1) * channels;
+        let mut table = SummedVolumeTable {
+            width,
+            height,
+            sums: vec![0.0; len],
+            squares: vec![0.0; len],
+            pixel: PhantomData,
+        };
+        for z in 0..depth {
+            for y in 0..height {
+                for x in 0..width {
+                    let voxel = volume.get_voxel(x, y, z);
+                    let at = |dx, dy, dz| table.index(x + 1 - dx, y + 1 - dy, z + 1 - dz);
+                    // inclusion-exclusion of the seven boxes before this position.
+                    let corners = [
+                        (at(1, 0, 0), 1.0),
+                        (at(0, 1, 0), 1.0),
+                        (at(0, 0, 1), 1.0),
+                        (at(1, 1, 0), -1.0),
+                        (at(1, 0, 1), -1.0),
+                        (at(0, 1, 1), -1.0),
+                        (at(1, 1, 1), 1.0),
+                    ];
+                    let index = at(0, 0, 0);
+                    for c in 0..channels {
+                        let value = voxel.channel(c);
+                        let mut sum = value;
+                        let mut square = value * value;
+                        for &(corner, sign) in &corners {
+                            sum += sign * table.sums[corner + c];
+                            square += sign * table.squares[corner + c];
+                        }
+                        table.sums[index + c] = sum;
+                        table.squares[index + c] = square;
+                    }
+                }
+            }
+        }
+        table
+    }
+
+    fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        ((z * (self.height + 1) + y) * (self.width + 1) + x) * P::CHANNELS
+    }
+
+    /// The sum of the given channel of the given table, over the given region.
+    fn region_sum(&self, table: &[f64], channel: usize, region: &Region3) -> f64 {
+        let at = |dx: usize, dy: usize, dz: usize| {
+            let x = region.x + dx * region.width;
+            let y = region.y + dy * region.height;
+            let z = region.z + dz * region.depth;
+            table[self.index(x, y, z) + channel]
+        };
+        at(1, 1, 1) - at(0, 1, 1) - at(1, 0, 1) - at(1, 1, 0)
+            + at(0, 0, 1)
+            + at(0, 1, 0)
+            + at(1, 0, 0)
+            - at(0, 0, 0)
+    }
+
+    /// The mean color of the given region.
+    fn mean(&self, region: &Region3) -> P {
+        let len = region.volume() as f64;
+        let channels: Vec<_> = (0..P::CHANNELS)
+            .map(|c| self.region_sum(&self.sums, c, region) / len)
+            .collect();
+        P::from_channels(&channels)
+    }
+
+    /// The mean squared error of the voxels in the given region with
+    /// respect to the given color, with the channels weighted as in
+    /// `Pixel::squared_error`.
+    fn mse(&self, region: &Region3, color: &P) -> f32 {
+        let len = region.volume() as f64;
+        (0..P::CHANNELS)
+            .map(|c| {
+                let sum = self.region_sum(&self.sums, c, region);
+                let square = self.region_sum(&self.squares, c, region);
+                let value = color.channel(c);
+                let squared_error = square - 2.0 * value * sum + len * value * value;
+                P::WEIGHTS[c] * squared_error.max(0.0) / len
+            })
+            .sum::<f64>() as f32
+    }
+}
+
+#[derive(Debug, Clone)]
+enum RegionOctree<P: Pixel> {
+    Leaf(Region3, P),
+    /// A box split along the edges longer than the minimum length, into
+    /// two, four or eight children.
+    Branch(Region3, Vec<RegionOctree<P>>),
+}
+
+impl<P: Pixel> RegionOctree<P> {
+    fn leaf(region: Region3, table: &SummedVolumeTable<P>) -> RegionOctree<P> {
+        let mean = table.mean(&region);
+        RegionOctree::Leaf(region, mean)
+    }
+
+    /// Split the leaves whose error is at least the given threshold in half
+    /// along each edge greater than the given minimum length, and the boxes
+    /// that gives in turn, as long as any edge of the region is.
+    fn subdivide(
+        &mut self,
+        table: &SummedVolumeTable<P>,
+        error_threshold: f32,
+        min_edge_length: usize,
+    ) {
+        match self {
+            RegionOctree::Leaf(region, mean) => {
+                if table.mse(region, mean) < error_threshold {
+                    return;
+                }
+                let parts = region.split_longer_than(min_edge_length);
+                if parts.len() == 1 {
+                    return;
+                }
+                let children = parts
+                    .into_iter()
+                    .map(|part| RegionOctree::leaf(part, table))
+                    .collect();
+                *self = RegionOctree::Branch(region.clone(), children);
+                self.subdivide(table, error_threshold, min_edge_length);
+            }
+            RegionOctree::Branch(_, children) => {
+                for child in children.iter_mut() {
+                    child.subdivide(table, error_threshold, min_edge_length);
+                }
+            }
+        }
+    }
+
+    /// Call f with the region and mean color of each leaf.
+    fn for_each_leaf(&self, f: &mut impl FnMut(&Region3, &P)) {
+        match self {
+            RegionOctree::Leaf(region, mean) => f(region, mean),
+            RegionOctree::Branch(_, children) => {
+                for child in children.iter() {
+                    child.for_each_leaf(f);
+                }
+            }
+        }
+    }
+
+    fn region(&self) -> &Region3 {
+        match self {
+            RegionOctree::Leaf(region, _) | RegionOctree::Branch(region, _) => region,
+        }
+    }
+
+    /// Write the mean colors of the leaves that intersect slice z in the image.
+    fn render_slice(&self, z: usize, result: &mut Image<P>) {
+        match self {
+            RegionOctree::Leaf(region, mean) => {
+                for x in region.x..region.x + region.width {
+                    for y in region.y..region.y + region.height {
+                        result.set_index(x, y, *mean);
+                    }
+                }
+            }
+            RegionOctree::Branch(_, children) => {
+                for child in children {
+                    let region = child.region();
+                    if (region.z..region.z + region.depth).contains(&z) {
+                        child.render_slice(z, result);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An octree over a volume, the 3D counterpart of `RegionQuadTreeImage`.
+pub struct RegionOctreeVolume<P: Pixel = Luma<u16>> {
+    octree: RegionOctree<P>,
+    volume: Volume<P>,
+    table: SummedVolumeTable<P>,
+    leaf_count: usize,
+    total_error: f32,
+}
+
+impl<P: Pixel> RegionOctreeVolume<P> {
+    /// Create a new region octree for the given volume, that splits regions
+    /// based on their weighted mean squared error.
+    pub fn new(volume: Volume<P>) -> RegionOctreeVolume<P> {
+        let table = SummedVolumeTable::new(&volume);
+        let octree = RegionOctree::leaf(Self::region(&volume), &table);
+        let mut result = RegionOctreeVolume {
+            octree,
+            volume,
+            table,
+            leaf_count: 1,
+            total_error: 0.0,
+        };
+        result.update_stats();
+        result
+    }
+
+    /// The region of the whole volume.
+    fn region(volume: &Volume<P>) -> Region3 {
+        Region3 {
+            x: 0,
+            y: 0,
+            z: 0,
+            width: volume.width(),
+            height: volume.height(),
+            depth: volume.depth(),
+        }
+    }
+
+    fn update_stats(&mut self) {
+        let (mut leaf_count, mut total_error) = (0, 0.0);
+        let table = &self.table;
+        self.octree.for_each_leaf(&mut |region, mean| {
+            leaf_count += 1;
+            total_error += table.mse(region, mean);
+        });
+        self.leaf_count = leaf_count;
+        self.total_error = total_error;
+    }
+
+    /// Split regions in half along their edges longer than the given minimum
+    /// length until the mean squared error of every region is below the given
+    /// threshold, or none of its edges is longer than the minimum.
+    pub fn subdivide_until(&mut self, error_threshold: f32, min_edge_length: usize) {
+        // a region with an edge of 1 can't be split, whatever the minimum.
+        let min_edge_length = min_edge_length.max(1);
+        self.octree
+            .subdivide(&self.table, error_threshold, min_edge_length);
+        self.update_stats();
+    }
+
+    /// The number of leaves in the octree.
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
+
+    /// The sum of the errors of all the leaves in the octree.
+    pub fn total_error(&self) -> f32 {
+        self.total_error
+    }
+
+    /// The volume with the voxels of each leaf set to its mean color.
+    pub fn get_result_volume(&self) -> Volume<P> {
+        let volume = &self.volume;
+        let mut result = Volume::empty(volume.width(), volume.height(), volume.depth());
+        self.octree.for_each_leaf(&mut |region, mean| {
+            for z in region.z..region.z + region.depth {
+                for y in region.y..region.y + region.height {
+                    for x in region.x..region.x + region.width {
+                        result.set_index(x, y, z, *mean);
+                    }
+                }
+            }
+        });
+        result
+    }
+
+    /// The slice at the given z of the result volume, only rendering the
+    /// leaves that intersect it. An error if z is past the back of the volume.
+    pub fn get_result_slice(&self, z: usize) -> Result<Image<P>, QuadtreeError> {
+        if z >= self.volume.depth() {
+            return Err(QuadtreeError::SliceOutOfRange {
+                z,
+                depth: self.volume.depth(),
+            });
+        }
+        let mut result = Image::empty(self.volume.width(), self.volume.height());
+        self.octree.render_slice(z, &mut result);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A volume of 16 cubed voxels with a bright 8 by 8 by 8 block in the back
+    /// bottom right octant, with a gradient along x, and dark elsewhere.
+    fn test_volume() -> Volume {
+        let mut voxels = vec![];
+        for z in 0..16 {
+            for y in 0..16 {
+                for x in 0..16u16 {
+                    let bright = x >= 8 && y >= 8 && z >= 8;
+                    voxels.push(Luma(if bright { 1000 + 10 * x } else { 0 }));
+                }
+            }
+        }
+        Volume::from_voxels(16, 16, 16, voxels).unwrap()
+    }
+
+    #[test]
+    fn summed_volume_table() {
+        let voxels: Vec<_> = (0..60).map(|i| (i * 7 % 11) as f32).collect();
+        let volume = Volume::from_voxels(5, 4, 3, voxels).unwrap();
+        let table = SummedVolumeTable::new(&volume);
+        let region = Region3 {
+            x: 1,
+            y: 1,
+            z: 1,
+            width: 3,
+            height: 2,
+            depth: 2,
+        };
+        let mut values = vec![];
+        for z in 1..3 {
+            for y in 1..3 {
+                for x in 1..4 {
+                    values.push(volume.get_voxel(x, y, z));
+                }
+            }
+        }
+        let mean = f32::mean(&values).unwrap();
+        assert!((table.mean(&region) - mean).abs() < 1e-5);
+        let mse = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
+        assert!((table.mse(&region, &mean) - mse).abs() < 1e-4);
+    }
+
+    #[test]
+    fn subdivide() {
+        let mut octree = RegionOctreeVolume::new(test_volume());
+        octree.subdivide_until(1.0, 2);
+        // the seven dark octants stay whole, the bright one is split down
+        // to boxes 2 voxels wide, each with an error of 25.
+        assert_eq!(octree.leaf_count(), 7 + 64);
+        assert_eq!(octree.total_error(), 64.0 * 25.0);
+        let result = octree.get_result_volume();
+        assert_eq!(result.get_voxel(0, 0, 0), Luma(0));
+        assert_eq!(result.get_voxel(9, 9, 9), Luma(1085));
+        assert_eq!(octree.get_result_slice(9), result.slice(9));
+
+        // with a higher threshold, splitting stops at boxes 4 voxels wide.
+        let mut octree = RegionOctreeVolume::new(test_volume());
+        octree.subdivide_until(200.0, 1);
+        assert_eq!(octree.leaf_count(), 7 + 8);
+        assert!(octree.get_result_slice(16).is_err());
+    }
+
+    #[test]
+    fn thin_volumes() {
+        // a single slice with a bright top left quadrant is split like an image.
+        let voxels: Vec<_> = (0..16 * 16)
+            .map(|i| Luma(if i % 16 < 8 && i / 16 < 8 { 1000u16 } else { 0 }))
+            .collect();
+        let volume = Volume::from_voxels(16, 16, 1, voxels).unwrap();
+        let mut octree = RegionOctreeVolume::new(volume.clone());
+        octree.subdivide_until(1.0, 1);
+        assert_eq!(octree.leaf_count(), 4);
+        assert_eq!(octree.total_error(), 0.0);
+        assert_eq!(octree.get_result_volume(), volume);
+
+        // in a volume 2 deep, boxes are split down to the minimum length along
+        // x and y even though the depth is already below it.
+        let voxels: Vec<_> = (0..16 * 16 * 2)
+            .map(|i| Luma((i * 31 % 256) as u8))
+            .collect();
+        let volume = Volume::from_voxels(16, 16, 2, voxels).unwrap();
+        let mut octree = RegionOctreeVolume::new(volume);
+        octree.subdivide_until(0.0, 4);
+        assert_eq!(octree.leaf_count(), 16);
+        octree.octree.for_each_leaf(&mut |region, _| {
+            assert_eq!((region.width, region.height, region.depth), (4, 4, 2));
+        });
+        assert_eq!(
+            octree.get_result_slice(1),
+            octree.get_result_volume().slice(1)
+        );
+    }
+
+    #[test]
+    fn odd_sizes() {
+        let voxels: Vec<_> = (0..7 * 5 * 3).map(|i| Luma((i * 31 % 256) as u8)).collect();
+        let volume = Volume::from_voxels(7, 5, 3, voxels).unwrap();
+        let mut octree = RegionOctreeVolume::new(volume.clone());
+        octree.subdivide_until(0.0, 1);
+        let mut covered = 0;
+        octree
+            .octree
+            .for_each_leaf(&mut |region, _| covered += region.volume());
+        assert_eq!(covered, 7 * 5 * 3);
+        let result = octree.get_result_volume();
+        for z in 0..3 {
+            assert_eq!(octree.get_result_slice(z), result.slice(z));
+        }
+    }
+}
@@ -1,24 +1,231 @@
+use std::{
+    cell::OnceCell,
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    iter,
+};
+
 use wasm_bindgen::prelude::wasm_bindgen;
 
 use crate::{
-    image::{Image, Rgba},
+    error::check_rgba_len,
+    error::QuadtreeError,
+    error_metric::{self, ErrorMetric, WeightedMse},
+    image::{Image, JsImage, Rgba},
+    linear_fit::{LeafModel, LinearFit},
+    nearest::BestFirst,
+    overlay::{self, OverlayOptions},
+    pixel::Pixel,
+    region::{Point, Region},
+    segmentation::{self, Segment},
+    serialization,
+    split_strategy::SplitStrategy,
+    summed_area_table::SummedAreaTable,
+    svg::{self, Stroke, SvgOptions},
     utils,
 };
 
-/// A region of an image or a 2D plane, described by its top left corner
-/// and its width and height.
-#[derive(Debug, Clone)]
-struct Region {
-    x: usize,
-    y: usize,
+/// The image a region quadtree approximates and its summed-area table,
+/// both built on first use. The image of a decoded quadtree is the
+/// rendering of that quadtree, so decoding doesn't take the time and
+/// memory of the image and the table unless they are needed.
+struct Source<P: Pixel> {
     width: usize,
     height: usize,
+    /// The quadtree the image is the rendering of, if it was decoded.
+    decoded: Option<RegionQuadTree<P>>,
+    image: OnceCell<Image<P>>,
+    table: OnceCell<SummedAreaTable<P>>,
+    /// Whether the table has the moments, for linear fits.
+    moments: bool,
 }
 
+impl<P: Pixel> Source<P> {
+    fn new(image: Image<P>) -> Source<P> {
+        Source {
+            width: image.width(),
+            height: image.height(),
+            decoded: None,
+            image: OnceCell::from(image),
+            table: OnceCell::new(),
+            moments: false,
+        }
+    }
+
+    fn decoded(quadtree: RegionQuadTree<P>) -> Source<P> {
+        let region = quadtree.region();
+        Source {
+            width: region.width,
+            height: region.height,
+            decoded: Some(quadtree),
+            image: OnceCell::new(),
+            table: OnceCell::new(),
+            moments: false,
+        }
+    }
+
+    fn image(&self) -> &Image<P> {
+        self.image.get_or_init(|| {
+            let mut image = Image::empty(self.width, self.height);
+            self.decoded
+                .as_ref()
+                .expect("a source without an image is decoded")
+                .get_averaged_points(&mut image);
+            image
+        })
+    }
+
+    fn table(&self) -> &SummedAreaTable<P> {
+        self.table.get_or_init(|| {
+            if self.moments {
+                SummedAreaTable::with_moments(self.image())
+            } else {
+                SummedAreaTable::new(self.image())
+            }
+        })
+    }
+
+    /// Build the table with the moments, for linear fits, from now on.
+    fn use_moments(&mut self) {
+        if !self.moments {
+            self.moments = true;
+            self.table.take();
+        }
+    }
+}
+
+/// The image a region quadtree approximates, with what's needed to
+/// compute the mean color and the error of its regions.
+struct ImageStats<'a, P: Pixel> {
+    source: &'a Source<P>,
+    metric: &'a dyn ErrorMetric<P>,
+    model: LeafModel,
+}
+
+impl<'a, P: Pixel> ImageStats<'a, P> {
+    fn new(source: &'a Source<P>, metric: &'a dyn ErrorMetric<P>, model: LeafModel) -> Self {
+        ImageStats {
+            source,
+            metric,
+            model,
+        }
+    }
+
+    fn table(&self) -> &'a SummedAreaTable<P> {
+        self.source.table()
+    }
+
+    fn mean(&self, region: &Region) -> P {
+        self.table()
+            .mean(region.x, region.y, region.width, region.height)
+    }
+
+    /// The linear fit of the region, if leaves are linear.
+    fn fit(&self, region: &Region) -> Option<LinearFit<P>> {
+        match self.model {
+            LeafModel::Flat => None,
+            LeafModel::Linear => {
+                self.table()
+                    .linear_fit(region.x, region.y, region.width, region.height)
+            }
+        }
+    }
+
+    /// The error of a leaf with the given region and mean color: of the
+    /// mean color, or of the linear fit if leaves are linear.
+    fn error(&self, region: &Region, color: &P) -> f32 {
+        let (image, table) = (self.source.image(), self.table());
+        match self.fit(region) {
+            Some(fit) => self.metric.fit_error(image, table, region, &fit),
+            None => self.metric.error(image, table, region, color),
+        }
+    }
+}
+
+/// An edge of a node in a region quadtree, to find the nodes across it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    North,
+    South,
+    East,
+    West,
+}
+
+impl Edge {
+    pub const ALL: [Edge; 4] = [Edge::North, Edge::South, Edge::East, Edge::West];
+
+    /// The strip of pixels just across this edge of the given region, one
+    /// pixel wide, or None if the edge is on the border of the image with
+    /// the given region.
+    fn across(self, region: &Region, image: &Region) -> Option<Region> {
+        let strip = match self {
+            Edge::North => Region {
+                x: region.x,
+                y: region.y.checked_sub(1)?,
+                width: region.width,
+                height: 1,
+            },
+            Edge::South => Region {
+                x: region.x,
+                y: region.y + region.height,
+                width: region.width,
+                height: 1,
+            },
+            Edge::West => Region {
+                x: region.x.checked_sub(1)?,
+                y: region.y,
+                width: 1,
+                height: region.height,
+            },
+            Edge::East => Region {
+                x: region.x + region.width,
+                y: region.y,
+                width: 1,
+                height: region.height,
+            },
+        };
+        image.contains_region(&strip).then_some(strip)
+    }
+}
+
+/// A corner of a leaf in a region quadtree, to find the leaf diagonally
+/// across it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corner {
+    NorthWest,
+    NorthEast,
+    SouthWest,
+    SouthEast,
+}
+
+impl Corner {
+    pub const ALL: [Corner; 4] = [
+        Corner::NorthWest,
+        Corner::NorthEast,
+        Corner::SouthWest,
+        Corner::SouthEast,
+    ];
+}
+
+/// A leaf of a region quadtree, with its depth, where the root is at
+/// depth 0, and its error according to the metric of the tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeafInfo<P: Pixel = Rgba> {
+    pub region: Region,
+    pub mean: P,
+    pub depth: u32,
+    pub error: f32,
+}
+
+/// A leaf with its region and mean color, or a branch with its children
+/// in order: the top left, bottom left, top right and bottom right
+/// quadrants, or the left and right or top and bottom parts of a binary
+/// split. The regions of the children cover the region of the branch,
+/// but are not necessarily of equal size, see `SplitStrategy`.
 #[derive(Debug, Clone)]
-enum RegionQuadTree {
-    Leaf(Region, Rgba),
-    Branch([Box<RegionQuadTree>; 4]),
+pub(crate) enum RegionQuadTree<P: Pixel = Rgba> {
+    Leaf(Region, P),
+    Branch(Vec<RegionQuadTree<P>>),
 }
 
 // macro_rules! log {
@@ -27,59 +234,176 @@ enum RegionQuadTree {
 //     }
 // }
 
-impl RegionQuadTree {
-    fn new(x: usize, y: usize, width: usize, height: usize) -> RegionQuadTree {
-        RegionQuadTree::Leaf(
-            Region {
-                x,
-                y,
-                width,
-                height,
-            },
-            Rgba::new(),
-        )
-    }
-
-    fn leaf(x: usize, y: usize, width: usize, height: usize, image: &Image) -> RegionQuadTree {
-        let pixels = Self::get_original_points(&Self::new(x, y, width, height), image);
-        let mean = Rgba::mean(&pixels);
-        RegionQuadTree::Leaf(
-            Region {
-                x,
-                y,
-                width,
-                height,
+impl<P: Pixel> RegionQuadTree<P> {
+    fn leaf(
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        stats: &ImageStats<P>,
+    ) -> RegionQuadTree<P> {
+        let region = Region {
+            x,
+            y,
+            width,
+            height,
+        };
+        let mean = stats.mean(&region);
+        RegionQuadTree::Leaf(region, mean)
+    }
+
+    /// Split the given region into leaves according to the strategy, each
+    /// with the mean color of its own region in the image. Only sides longer
+    /// than the minimum length, which is at least 1, are cut.
+    fn split(
+        region: &Region,
+        stats: &ImageStats<P>,
+        strategy: SplitStrategy,
+        min_region_length: usize,
+    ) -> RegionQuadTree<P> {
+        let children = strategy
+            .cut(stats.table(), region, min_region_length)
+            .children(region)
+            .into_iter()
+            .map(|child| {
+                let mean = stats.mean(&child);
+                RegionQuadTree::Leaf(child, mean)
+            })
+            .collect();
+        RegionQuadTree::Branch(children)
+    }
+
+    pub(crate) fn region(&self) -> Region {
+        // the first leaf has the top left corner, and the last leaf the
+        // bottom right one.
+        let (mut first, mut last) = (self, self);
+        while let RegionQuadTree::Branch(children) = first {
+            first = &children[0];
+        }
+        while let RegionQuadTree::Branch(children) = last {
+            last = &children[children.len() - 1];
+        }
+        let (RegionQuadTree::Leaf(first, _), RegionQuadTree::Leaf(last, _)) = (first, last) else {
+            unreachable!("the loops only end at a leaf")
+        };
+        Region {
+            x: first.x,
+            y: first.y,
+            width: last.x + last.width - first.x,
+            height: last.y + last.height - first.y,
+        }
+    }
+
+    /// The subtree at the given path, where each step in the path
+    /// is the index of a child.
+    fn get_mut(&mut self, path: &[usize]) -> &mut RegionQuadTree<P> {
+        match path.split_first() {
+            None => self,
+            Some((&child, rest)) => match self {
+                RegionQuadTree::Branch(children) => children[child].get_mut(rest),
+                RegionQuadTree::Leaf(..) => panic!("path {path:?} goes past a leaf"),
             },
-            mean,
-        )
+        }
     }
 
-    fn region(&self) -> Region {
+    /// Push the path to each leaf of this quadtree onto leaves, in pre-order.
+    fn leaf_paths(&self, path: &mut Vec<usize>, leaves: &mut Vec<Vec<usize>>) {
         match self {
-            RegionQuadTree::Leaf(region, _) => region.clone(),
-            RegionQuadTree::Branch(children) => Region {
-                x: children[0].region().x,
-                y: children[0].region().y,
-                width: children[0].region().width + children[2].region().width,
-                height: children[0].region().height + children[1].region().height,
-            },
+            RegionQuadTree::Leaf(..) => leaves.push(path.clone()),
+            RegionQuadTree::Branch(children) => {
+                for (i, child) in children.iter().enumerate() {
+                    path.push(i);
+                    child.leaf_paths(path, leaves);
+                    path.pop();
+                }
+            }
         }
     }
 
-    /// Get the original points in the given image, in the region of this quadtree.
-    fn get_original_points(&self, image: &Image) -> Vec<Rgba> {
-        let region = self.region();
-        let mut points = Vec::with_capacity(region.width * region.height);
-        for x in region.x..region.x + region.width {
-            for y in region.y..region.y + region.height {
-                points.push(image.get_pixel(x, y));
+    /// Call f with the region, mean color and depth of each leaf, in
+    /// pre-order, where this quadtree is at the given depth.
+    fn for_each_leaf<'a>(&'a self, depth: u32, f: &mut impl FnMut(&'a Region, &'a P, u32)) {
+        match self {
+            RegionQuadTree::Leaf(region, mean) => f(region, mean, depth),
+            RegionQuadTree::Branch(children) => {
+                for child in children {
+                    child.for_each_leaf(depth + 1, f);
+                }
+            }
+        }
+    }
+
+    /// The node with the given region, or None if no node has that region.
+    fn find(&self, target: &Region) -> Option<&RegionQuadTree<P>> {
+        let mut node = self;
+        while node.region() != *target {
+            let RegionQuadTree::Branch(children) = node else {
+                return None;
+            };
+            node = children
+                .iter()
+                .find(|c| c.region().contains_pixel(target.x, target.y))?;
+        }
+        Some(node)
+    }
+
+    /// The deepest node whose region contains all of the given area, or
+    /// this quadtree if none of its children does.
+    fn covering(&self, area: &Region) -> &RegionQuadTree<P> {
+        let mut node = self;
+        while let RegionQuadTree::Branch(children) = node {
+            match children.iter().find(|c| c.region().contains_region(area)) {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+        node
+    }
+
+    /// Call f with the region and mean color of each leaf that has pixels
+    /// in the given area, in pre-order.
+    fn for_each_leaf_in<'a>(&'a self, area: &Region, f: &mut impl FnMut(&'a Region, &'a P)) {
+        if !self.region().intersects(area) {
+            return;
+        }
+        match self {
+            RegionQuadTree::Leaf(region, mean) => f(region, mean),
+            RegionQuadTree::Branch(children) => {
+                for child in children {
+                    child.for_each_leaf_in(area, f);
+                }
+            }
+        }
+    }
+
+    fn leaf_count(&self) -> usize {
+        match self {
+            RegionQuadTree::Leaf(..) => 1,
+            RegionQuadTree::Branch(children) => children.iter().map(|c| c.leaf_count()).sum(),
+        }
+    }
+
+    fn node_count(&self) -> usize {
+        match self {
+            RegionQuadTree::Leaf(..) => 1,
+            RegionQuadTree::Branch(children) => {
+                1 + children.iter().map(|c| c.node_count()).sum::<usize>()
+            }
+        }
+    }
+
+    /// The depth of the deepest leaf, where this quadtree is at depth 0.
+    fn depth(&self) -> u32 {
+        match self {
+            RegionQuadTree::Leaf(..) => 0,
+            RegionQuadTree::Branch(children) => {
+                1 + children.iter().map(|c| c.depth()).max().unwrap_or(0)
             }
         }
-        points
     }
 
     /// Write the averaged points in the given image, in the region of this quadtree.
-    fn get_averaged_points(&self, result: &mut Image) {
+    fn get_averaged_points(&self, result: &mut Image<P>) {
         match self {
             RegionQuadTree::Leaf(region, mean) => {
                 for x in region.x..region.x + region.width {
@@ -96,82 +420,91 @@ impl RegionQuadTree {
         }
     }
 
-    /// Get the mean squared error of the region of this quadtree.
-    fn get_error(&self, image: &Image) -> f32 {
-        match self {
-            RegionQuadTree::Leaf(_, mean) => {
-                let pixels = self.get_original_points(image);
-                mean.mse(&pixels)
+    /// Write the pixels of the leaves in the given image, in the region of
+    /// this quadtree: their mean colors, or their linear fits if leaves
+    /// are linear.
+    fn render(&self, stats: &ImageStats<P>, result: &mut Image<P>) {
+        if stats.model == LeafModel::Flat {
+            return self.get_averaged_points(result);
+        }
+        self.for_each_leaf(0, &mut |region, mean, _| match stats.fit(region) {
+            Some(fit) => {
+                for x in region.x..region.x + region.width {
+                    for y in region.y..region.y + region.height {
+                        result.set_index(x, y, fit.color_at(x, y));
+                    }
+                }
             }
+            None => RegionQuadTree::Leaf(region.clone(), *mean).get_averaged_points(result),
+        });
+    }
+
+    /// Get the error of the region of this quadtree: the error of
+    /// a leaf according to the metric, or the sum of the errors of the leaves.
+    fn get_error(&self, stats: &ImageStats<P>) -> f32 {
+        match self {
+            RegionQuadTree::Leaf(region, mean) => stats.error(region, mean),
             RegionQuadTree::Branch(children) => {
                 let mut error = 0.0;
                 for child in children {
-                    error += child.get_error(image);
+                    error += child.get_error(stats);
                 }
                 error
             }
         }
     }
 
-    /// Subdivide the region of this quadtree into 4 smaller regions,
-    /// if the error is above the given threshold and the region is
-    /// greater than the given minimum length.
+    /// Bottom-up, replace each branch whose children are all leaves by a
+    /// single leaf, if the error of that leaf is below the given threshold.
+    fn merge(&mut self, stats: &ImageStats<P>, error_threshold: f32) {
+        let RegionQuadTree::Branch(children) = self else {
+            return;
+        };
+        for child in children.iter_mut() {
+            child.merge(stats, error_threshold);
+        }
+        if children
+            .iter()
+            .any(|c| matches!(c, RegionQuadTree::Branch(_)))
+        {
+            return;
+        }
+        let region = self.region();
+        let mean = stats.mean(&region);
+        if stats.error(&region, &mean) < error_threshold {
+            *self = RegionQuadTree::Leaf(region, mean);
+        }
+    }
+
+    /// Subdivide the region of this quadtree into smaller regions according
+    /// to the strategy, if the error is above the given threshold and the
+    /// strategy can split the region, see `SplitStrategy::can_split`.
     fn subdivide(
         &self,
-        image: &Image,
+        stats: &ImageStats<P>,
+        strategy: SplitStrategy,
         error_threshold: f32,
         min_region_length: usize,
-    ) -> Option<RegionQuadTree> {
+    ) -> Option<RegionQuadTree<P>> {
         let region = self.region();
-        if self.get_error(image) < error_threshold
-            || region.height <= min_region_length
-            || region.width <= min_region_length
+        if self.get_error(stats) < error_threshold
+            || !strategy.can_split(&region, min_region_length)
         {
             return None;
         }
         match self {
-            RegionQuadTree::Leaf(region, _) => {
-                let half_width_l = (region.width as f64 / 2.0).floor() as usize;
-                let half_width_r = (region.width as f64 / 2.0).ceil() as usize;
-                let half_height_up = (region.height as f64 / 2.0).floor() as usize;
-                let half_height_dwn = (region.height as f64 / 2.0).ceil() as usize;
-
-                let children = [
-                    Box::new(RegionQuadTree::leaf(
-                        region.x,
-                        region.y,
-                        half_width_l,
-                        half_height_up,
-                        image,
-                    )),
-                    Box::new(RegionQuadTree::leaf(
-                        region.x,
-                        region.y + half_height_up,
-                        half_width_l,
-                        half_height_dwn,
-                        image,
-                    )),
-                    Box::new(RegionQuadTree::leaf(
-                        region.x + half_width_l,
-                        region.y,
-                        half_width_r,
-                        half_height_up,
-                        image,
-                    )),
-                    Box::new(RegionQuadTree::leaf(
-                        region.x + half_width_l,
-                        region.y + half_height_up,
-                        half_width_r,
-                        half_height_dwn,
-                        image,
-                    )),
-                ];
-                Some(RegionQuadTree::Branch(children))
-            }
+            RegionQuadTree::Leaf(region, _) => Some(RegionQuadTree::split(
+                region,
+                stats,
+                strategy,
+                min_region_length,
+            )),
             RegionQuadTree::Branch(children) => {
                 let sub_children: Vec<_> = children
                     .iter()
-                    .map(|child| child.subdivide(image, error_threshold, min_region_length))
+                    .map(|child| {
+                        child.subdivide(stats, strategy, error_threshold, min_region_length)
+                    })
                     .zip(children)
                     .collect();
 
@@ -181,55 +514,789 @@ impl RegionQuadTree {
 
                 let children = sub_children
                     .into_iter()
-                    .map(|(nc, oc)| Box::new(nc.unwrap_or(*oc.clone())))
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .unwrap();
+                    .map(|(nc, oc)| nc.unwrap_or_else(|| oc.clone()))
+                    .collect();
                 Some(RegionQuadTree::Branch(children))
             }
         }
     }
 }
 
-/// Convenience struct to hold a quadtree and an image,
-/// for JS interop.
-#[wasm_bindgen]
-pub struct RegionQuadTreeImage {
-    quadtree: RegionQuadTree,
-    image: Image,
+/// A leaf that is a candidate for splitting, in budget mode.
+/// Ordered by error, so the leaf with the largest error is split first.
+/// Ties are broken by path, so the order is deterministic.
+#[derive(Debug)]
+struct SplitCandidate {
+    error: f32,
+    path: Vec<usize>,
 }
 
-#[wasm_bindgen]
-impl RegionQuadTreeImage {
-    pub fn new(image: Image) -> RegionQuadTreeImage {
+impl PartialEq for SplitCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SplitCandidate {}
+
+impl PartialOrd for SplitCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SplitCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.error
+            .total_cmp(&other.error)
+            .then_with(|| other.path.cmp(&self.path))
+    }
+}
+
+/// The leaves that can still be split in budget mode, for a given
+/// minimum region length.
+#[derive(Debug)]
+struct SplitQueue {
+    min_region_length: usize,
+    candidates: BinaryHeap<SplitCandidate>,
+}
+
+/// Convenience struct to hold a quadtree and an image.
+pub struct RegionQuadTreeImage<P: Pixel = Rgba> {
+    quadtree: RegionQuadTree<P>,
+    source: Source<P>,
+    metric: Box<dyn ErrorMetric<P>>,
+    model: LeafModel,
+    strategy: SplitStrategy,
+    leaf_count: usize,
+    total_error: f32,
+    split_queue: Option<SplitQueue>,
+}
+
+impl<P: Pixel> RegionQuadTreeImage<P> {
+    /// Whether the given leaf can be split in budget mode.
+    fn is_splittable(
+        strategy: SplitStrategy,
+        leaf: &RegionQuadTree<P>,
+        error: f32,
+        min_region_length: usize,
+    ) -> bool {
+        error > 0.0 && strategy.can_split(&leaf.region(), min_region_length)
+    }
+
+    /// Build the split queue from the current leaves, unless it already
+    /// exists for the given minimum region length.
+    fn ensure_split_queue(&mut self, min_region_length: usize) {
+        if let Some(queue) = &self.split_queue {
+            if queue.min_region_length == min_region_length {
+                return;
+            }
+        }
+        let stats = ImageStats::new(&self.source, self.metric.as_ref(), self.model);
+        let mut paths = vec![];
+        self.quadtree.leaf_paths(&mut vec![], &mut paths);
+        let mut candidates = BinaryHeap::with_capacity(paths.len());
+        for path in paths {
+            let leaf = self.quadtree.get_mut(&path);
+            let error = leaf.get_error(&stats);
+            if Self::is_splittable(self.strategy, leaf, error, min_region_length) {
+                candidates.push(SplitCandidate { error, path });
+            }
+        }
+        self.split_queue = Some(SplitQueue {
+            min_region_length,
+            candidates,
+        });
+    }
+
+    /// Create a new region quadtree for the given image, that splits
+    /// regions based on the given error metric.
+    pub fn with_metric(image: Image<P>, metric: Box<dyn ErrorMetric<P>>) -> RegionQuadTreeImage<P> {
         utils::set_panic_hook();
-        let quadtree = RegionQuadTree::new(0, 0, image.width(), image.height());
-        RegionQuadTreeImage { quadtree, image }
+        let source = Source::new(image);
+        let stats = ImageStats::new(&source, metric.as_ref(), LeafModel::Flat);
+        let quadtree = RegionQuadTree::leaf(0, 0, source.width, source.height, &stats);
+        let total_error = quadtree.get_error(&stats);
+        RegionQuadTreeImage {
+            quadtree,
+            source,
+            metric,
+            model: LeafModel::Flat,
+            strategy: SplitStrategy::Midpoint,
+            leaf_count: 1,
+            total_error,
+            split_queue: None,
+        }
+    }
+
+    /// Use the given error metric from now on. Only affects
+    /// regions that are split after this.
+    pub fn set_metric(&mut self, metric: Box<dyn ErrorMetric<P>>) {
+        self.metric = metric;
+        let stats = ImageStats::new(&self.source, self.metric.as_ref(), self.model);
+        self.total_error = self.quadtree.get_error(&stats);
+        self.split_queue = None;
+    }
+
+    /// Use the given leaf model from now on: with `LeafModel::Linear`,
+    /// each leaf approximates its pixels by a linear fit instead of by its
+    /// mean color, which fits gradients with far fewer leaves. Errors, and
+    /// so splitting, and the result image follow the model; the encoding,
+    /// SVG, segments and overlays still use the mean colors of the leaves.
+    pub fn set_leaf_model(&mut self, model: LeafModel) {
+        if model == LeafModel::Linear {
+            self.source.use_moments();
+        }
+        self.model = model;
+        let stats = ImageStats::new(&self.source, self.metric.as_ref(), self.model);
+        self.total_error = self.quadtree.get_error(&stats);
+        self.split_queue = None;
+    }
+
+    /// The leaf model, see set_leaf_model.
+    pub fn leaf_model(&self) -> LeafModel {
+        self.model
+    }
+
+    /// Split leaves according to the given strategy from now on: into
+    /// equal quadrants, quadrants cut where the colors change, or two
+    /// halves. Only affects regions that are split after this.
+    pub fn set_split_strategy(&mut self, strategy: SplitStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// The split strategy, see set_split_strategy.
+    pub fn split_strategy(&self) -> SplitStrategy {
+        self.strategy
+    }
+
+    /// Create a new region quadtree for the given image, that splits
+    /// regions based on their weighted mean squared error.
+    pub fn new(image: Image<P>) -> RegionQuadTreeImage<P> {
+        RegionQuadTreeImage::with_metric(image, Box::new(WeightedMse))
     }
 
     pub fn subdivide_until(&mut self, error_threshold: f32, min_region_length: usize) {
+        self.subdivide_passes(error_threshold, min_region_length, |_, _| {});
+    }
+
+    /// Subdivide like subdivide_until, calling on_pass with the quadtree
+    /// after each pass over the leaves that splits any of them.
+    fn subdivide_passes(
+        &mut self,
+        error_threshold: f32,
+        min_region_length: usize,
+        mut on_pass: impl FnMut(&RegionQuadTree<P>, &ImageStats<P>),
+    ) {
+        // a region of length 1 can't be split, whatever the minimum.
+        let min_region_length = min_region_length.max(1);
+        let stats = ImageStats::new(&self.source, self.metric.as_ref(), self.model);
         loop {
             let new_quadtree =
                 self.quadtree
-                    .subdivide(&self.image, error_threshold, min_region_length);
+                    .subdivide(&stats, self.strategy, error_threshold, min_region_length);
             match new_quadtree {
                 Some(qt) => self.quadtree = qt,
                 None => break,
             }
+            on_pass(&self.quadtree, &stats);
+        }
+        self.leaf_count = self.quadtree.leaf_count();
+        self.total_error = self.quadtree.get_error(&stats);
+        self.split_queue = None;
+    }
+
+    /// Subdivide like subdivide_until, and return the result image before
+    /// subdividing and after each pass, to show the image being refined.
+    pub fn subdivide_until_frames(
+        &mut self,
+        error_threshold: f32,
+        min_region_length: usize,
+    ) -> Vec<Image<P>> {
+        let mut frames = vec![self.get_result_image()];
+        let (width, height) = (self.source.width, self.source.height);
+        self.subdivide_passes(error_threshold, min_region_length, |quadtree, stats| {
+            let mut frame = Image::empty(width, height);
+            quadtree.render(stats, &mut frame);
+            frames.push(frame);
+        });
+        frames
+    }
+
+    /// Budget mode: split the leaf with the largest error, if its region
+    /// is greater than the given minimum length.
+    /// Returns false if there is no such leaf left.
+    pub fn split_worst(&mut self, min_region_length: usize) -> bool {
+        // a region of length 1 can't be split, whatever the minimum.
+        let min_region_length = min_region_length.max(1);
+        self.ensure_split_queue(min_region_length);
+        let stats = ImageStats::new(&self.source, self.metric.as_ref(), self.model);
+        let queue = self.split_queue.as_mut().unwrap();
+        let Some(SplitCandidate { error, path }) = queue.candidates.pop() else {
+            return false;
+        };
+
+        let leaf = self.quadtree.get_mut(&path);
+        *leaf = RegionQuadTree::split(&leaf.region(), &stats, self.strategy, min_region_length);
+        let RegionQuadTree::Branch(children) = leaf else {
+            unreachable!("split always returns a branch")
+        };
+        self.total_error -= error;
+        self.leaf_count += children.len() - 1;
+        for (i, child) in children.iter().enumerate() {
+            let child_error = child.get_error(&stats);
+            self.total_error += child_error;
+            if Self::is_splittable(self.strategy, child, child_error, min_region_length) {
+                let mut child_path = path.clone();
+                child_path.push(i);
+                queue.candidates.push(SplitCandidate {
+                    error: child_error,
+                    path: child_path,
+                });
+            }
         }
+        true
     }
 
-    pub fn get_result_image(&self) -> Image {
-        let mut result = Image::empty(self.image.width(), self.image.height());
-        self.quadtree.get_averaged_points(&mut result);
+    /// Budget mode: keep splitting the leaf with the largest error until there
+    /// are max_leaves leaves, the total error is at most max_total_error, or
+    /// no leaf with a region greater than min_region_length is left.
+    pub fn subdivide_to_budget(
+        &mut self,
+        max_leaves: usize,
+        max_total_error: f32,
+        min_region_length: usize,
+    ) {
+        self.subdivide_to_budget_splits(max_leaves, max_total_error, min_region_length, |_| {});
+    }
+
+    /// Subdivide like subdivide_to_budget, calling on_split with the tree
+    /// after each split.
+    fn subdivide_to_budget_splits(
+        &mut self,
+        max_leaves: usize,
+        max_total_error: f32,
+        min_region_length: usize,
+        mut on_split: impl FnMut(&Self),
+    ) {
+        while self.leaf_count + self.strategy.branching() - 1 <= max_leaves
+            && self.total_error > max_total_error
+            && self.split_worst(min_region_length)
+        {
+            on_split(self);
+        }
+    }
+
+    /// Subdivide like subdivide_to_budget, and return the result image before
+    /// subdividing and after every splits_per_frame splits, as well as after
+    /// the last split, to show the image being refined.
+    pub fn subdivide_to_budget_frames(
+        &mut self,
+        max_leaves: usize,
+        max_total_error: f32,
+        min_region_length: usize,
+        splits_per_frame: usize,
+    ) -> Vec<Image<P>> {
+        let splits_per_frame = splits_per_frame.max(1);
+        let mut frames = vec![self.get_result_image()];
+        let mut splits = 0;
+        self.subdivide_to_budget_splits(max_leaves, max_total_error, min_region_length, |tree| {
+            splits += 1;
+            if splits % splits_per_frame == 0 {
+                frames.push(tree.get_result_image());
+            }
+        });
+        if splits % splits_per_frame != 0 {
+            frames.push(self.get_result_image());
+        }
+        frames
+    }
+
+    /// Merge pass: bottom-up, collapse each branch whose children are all
+    /// leaves back into a single leaf, if the error of that leaf is below
+    /// the given threshold. Undoes splits that turned out not to be needed,
+    /// for example after subdividing with a lower threshold, another metric
+    /// or in budget mode.
+    pub fn merge_siblings(&mut self, error_threshold: f32) {
+        let stats = ImageStats::new(&self.source, self.metric.as_ref(), self.model);
+        self.quadtree.merge(&stats, error_threshold);
+        self.leaf_count = self.quadtree.leaf_count();
+        self.total_error = self.quadtree.get_error(&stats);
+        self.split_queue = None;
+    }
+
+    /// Split-and-merge segmentation: merge adjacent leaves, also ones that
+    /// are not siblings, into segments of any shape, as long as the mean
+    /// colors of two adjacent segments are less than threshold apart in
+    /// weighted squared distance. See the segmentation module.
+    pub fn segments(&self, threshold: f64) -> Vec<Segment<P>> {
+        let mut leaves = vec![];
+        self.quadtree.for_each_leaf(0, &mut |region, mean, _| {
+            leaves.push((region.clone(), *mean))
+        });
+        let index: HashMap<_, _> = leaves
+            .iter()
+            .enumerate()
+            .map(|(i, (r, _))| ((r.x, r.y, r.width, r.height), i))
+            .collect();
+        let mut adjacent = vec![];
+        for (i, (region, _)) in leaves.iter().enumerate() {
+            for edge in [Edge::East, Edge::South] {
+                for (neighbor, _) in self.neighbor_leaves(region, edge) {
+                    let key = (neighbor.x, neighbor.y, neighbor.width, neighbor.height);
+                    adjacent.push((i, index[&key]));
+                }
+            }
+        }
+        segmentation::segment(&leaves, &adjacent, threshold)
+    }
+
+    /// The image with every pixel in the mean color of its segment.
+    /// See segments.
+    pub fn get_segmented_image(&self, threshold: f64) -> Image<P> {
+        let mut result = Image::empty(self.source.width, self.source.height);
+        for segment in self.segments(threshold) {
+            for region in &segment.regions {
+                RegionQuadTree::Leaf(region.clone(), segment.mean).get_averaged_points(&mut result);
+            }
+        }
         result
     }
+
+    /// The number of leaves in the quadtree.
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
+
+    /// The sum of the errors of all the leaves in the quadtree.
+    pub fn total_error(&self) -> f32 {
+        self.total_error
+    }
+
+    /// The number of nodes in the quadtree, branches and leaves.
+    pub fn node_count(&self) -> usize {
+        self.quadtree.node_count()
+    }
+
+    /// The depth of the deepest leaf, where the root is at depth 0.
+    pub fn depth(&self) -> u32 {
+        self.quadtree.depth()
+    }
+
+    /// The leaves of the quadtree in pre-order: the children of each branch
+    /// in turn, from top left to bottom right. With midpoint splits, this
+    /// is Morton order.
+    pub fn leaves(&self) -> impl Iterator<Item = LeafInfo<P>> + '_ {
+        let stats = ImageStats::new(&self.source, self.metric.as_ref(), self.model);
+        let mut stack = vec![(&self.quadtree, 0)];
+        iter::from_fn(move || {
+            while let Some((node, depth)) = stack.pop() {
+                match node {
+                    RegionQuadTree::Leaf(region, mean) => {
+                        return Some(LeafInfo {
+                            region: region.clone(),
+                            mean: *mean,
+                            depth,
+                            error: stats.error(region, mean),
+                        })
+                    }
+                    RegionQuadTree::Branch(children) => {
+                        stack.extend(children.iter().rev().map(|c| (c, depth + 1)))
+                    }
+                }
+            }
+            None
+        })
+    }
+
+    pub fn get_result_image(&self) -> Image<P> {
+        let stats = ImageStats::new(&self.source, self.metric.as_ref(), self.model);
+        let mut result = Image::empty(self.source.width, self.source.height);
+        self.quadtree.render(&stats, &mut result);
+        result
+    }
+
+    /// The region of the whole image, which is the region of the root.
+    fn root_region(&self) -> Region {
+        Region {
+            x: 0,
+            y: 0,
+            width: self.source.width,
+            height: self.source.height,
+        }
+    }
+
+    /// The region and mean color of the leaf that contains the pixel at
+    /// (x, y), or None if the pixel is outside of the image.
+    pub fn leaf_at(&self, x: usize, y: usize) -> Option<(Region, P)> {
+        if !self.root_region().contains_pixel(x, y) {
+            return None;
+        }
+        let mut node = &self.quadtree;
+        while let RegionQuadTree::Branch(children) = node {
+            node = children.iter().find(|c| c.region().contains_pixel(x, y))?;
+        }
+        let RegionQuadTree::Leaf(region, mean) = node else {
+            unreachable!("the loop only ends at a leaf")
+        };
+        Some((region.clone(), *mean))
+    }
+
+    /// The region of the equal or larger neighbor across the given edge of
+    /// the node with the given region: the smallest node that covers all of
+    /// the edge, which with midpoint splits is the node at the same depth,
+    /// or the leaf that covers where it would be. This is a branch if the leaves
+    /// across the edge are smaller, see neighbor_leaves. None if the edge
+    /// is on the border of the image, or no node has the given region.
+    pub fn neighbor(&self, node: &Region, edge: Edge) -> Option<Region> {
+        self.quadtree.find(node)?;
+        let across = edge.across(node, &self.root_region())?;
+        Some(self.quadtree.covering(&across).region())
+    }
+
+    /// The region and mean color of all the leaves that share part of the
+    /// given edge of the node with the given region, from north to south
+    /// or west to east: the equal or larger neighbor if it is a leaf, or
+    /// all the smaller leaves along its facing edge otherwise. Empty if the
+    /// edge is on the border of the image, or no node has the given region.
+    pub fn neighbor_leaves(&self, node: &Region, edge: Edge) -> Vec<(Region, P)> {
+        let mut leaves = vec![];
+        let Some(across) = self
+            .quadtree
+            .find(node)
+            .and_then(|_| edge.across(node, &self.root_region()))
+        else {
+            return leaves;
+        };
+        self.quadtree
+            .for_each_leaf_in(&across, &mut |region, mean| {
+                leaves.push((region.clone(), *mean))
+            });
+        leaves
+    }
+
+    /// The region and mean color of the leaf diagonally across the given
+    /// corner of the given region, which touches it only in that corner
+    /// unless it is larger. None if the corner is on the border of the image.
+    pub fn corner_neighbor(&self, region: &Region, corner: Corner) -> Option<(Region, P)> {
+        let (west, east) = (region.x.checked_sub(1), Some(region.x + region.width));
+        let (north, south) = (region.y.checked_sub(1), Some(region.y + region.height));
+        let (x, y) = match corner {
+            Corner::NorthWest => (west?, north?),
+            Corner::NorthEast => (east?, north?),
+            Corner::SouthWest => (west?, south?),
+            Corner::SouthEast => (east?, south?),
+        };
+        self.leaf_at(x, y)
+    }
+
+    /// The k leaves nearest to the given point whose color matches, nearest
+    /// first, as their region and color. Only leaves at most max_distance
+    /// away, if given. The pixel at (x, y) covers the square from (x, y) to
+    /// (x + 1, y + 1), and the distance to a leaf is the distance to the
+    /// nearest point of its region, so 0 for the leaf the point is in.
+    pub fn k_nearest_leaves(
+        &self,
+        point: &Point,
+        k: usize,
+        max_distance: Option<f64>,
+        matches: impl Fn(&P) -> bool,
+    ) -> Vec<(Region, P)> {
+        let mut search = BestFirst::new(max_distance);
+        let region = Region {
+            x: 0,
+            y: 0,
+            width: self.source.width,
+            height: self.source.height,
+        };
+        search.push_node(
+            region.to_f64().distance_squared(point),
+            (&self.quadtree, region),
+        );
+        search.take(k, |(node, _), search| match node {
+            RegionQuadTree::Leaf(region, color) => {
+                if matches(color) {
+                    search.push_item(
+                        region.to_f64().distance_squared(point),
+                        (region.clone(), *color),
+                    );
+                }
+            }
+            RegionQuadTree::Branch(children) => {
+                for child in children {
+                    let child_region = child.region();
+                    search.push_node(
+                        child_region.to_f64().distance_squared(point),
+                        (child, child_region),
+                    );
+                }
+            }
+        })
+    }
+
+    /// The leaf nearest to the given point whose color matches, as its
+    /// region and color. See k_nearest_leaves.
+    pub fn nearest_leaf(
+        &self,
+        point: &Point,
+        max_distance: Option<f64>,
+        matches: impl Fn(&P) -> bool,
+    ) -> Option<(Region, P)> {
+        self.k_nearest_leaves(point, 1, max_distance, matches).pop()
+    }
+}
+
+impl RegionQuadTreeImage {
+    /// A region quadtree whose image is the rendering of the given quadtree.
+    /// The image and its table are only built when they are needed.
+    fn from_quadtree(quadtree: RegionQuadTree) -> RegionQuadTreeImage {
+        utils::set_panic_hook();
+        RegionQuadTreeImage {
+            source: Source::decoded(quadtree.clone()),
+            leaf_count: quadtree.leaf_count(),
+            quadtree,
+            metric: Box::new(WeightedMse),
+            model: LeafModel::Flat,
+            strategy: SplitStrategy::Midpoint,
+            // every leaf is the mean color of its region of the image.
+            total_error: 0.0,
+            split_queue: None,
+        }
+    }
+
+    /// Use the built-in error metric with the given name from now on: one of
+    /// "mse", "max_deviation", "variance", "delta_e" or "ssim".
+    /// Only affects regions that are split after this.
+    pub fn set_error_metric(&mut self, name: &str) -> Result<(), QuadtreeError> {
+        let metric = error_metric::by_name(name)
+            .ok_or_else(|| QuadtreeError::UnknownErrorMetric(name.to_string()))?;
+        self.set_metric(metric);
+        Ok(())
+    }
+
+    /// Encode the quadtree in a compact binary format, see the serialization
+    /// module. color_bits is the number of bits per color channel, from 1 to 8;
+    /// less than 8 is lossy. delta codes each leaf color as the difference with
+    /// the previous one, which is smaller when neighbouring leaves are similar.
+    pub fn to_bytes(&self, color_bits: u8, delta: bool) -> Result<Vec<u8>, QuadtreeError> {
+        if !(1..=8).contains(&color_bits) {
+            return Err(QuadtreeError::InvalidColorBits(color_bits));
+        }
+        Ok(serialization::encode(&self.quadtree, color_bits, delta))
+    }
+
+    /// The partition of the image as an SVG document, with a rectangle in
+    /// the mean color of each leaf. See `SvgOptions`.
+    pub fn to_svg(&self, options: &SvgOptions) -> String {
+        let (width, height) = (self.source.width, self.source.height);
+        let background = self.source.table().mean(0, 0, width, height);
+        let mut leaves = vec![];
+        self.quadtree
+            .for_each_leaf(0, &mut |region, mean, _| leaves.push((region, mean)));
+        svg::render(width, height, &background, leaves, options)
+    }
+
+    /// A debug rendering of the partition of the image, with the leaves
+    /// colored and outlined according to the options. See `OverlayOptions`.
+    pub fn get_overlay_image(&self, options: &OverlayOptions) -> Image {
+        let leaves: Vec<_> = self.leaves().collect();
+        overlay::render(self.source.width, self.source.height, &leaves, options)
+    }
+
+    /// Decode a quadtree encoded with to_bytes. Its image is the image the
+    /// quadtree renders to, so get_result_image returns that image as well.
+    /// The image is only built when it is needed, for example to subdivide
+    /// further. An error if the bytes are not a valid encoding.
+    pub fn from_bytes(bytes: &[u8]) -> Result<RegionQuadTreeImage, QuadtreeError> {
+        serialization::decode(bytes)
+            .map(RegionQuadTreeImage::from_quadtree)
+            .ok_or(QuadtreeError::MalformedEncoding)
+    }
+}
+
+/// A region quadtree of an RGBA image, exported to JavaScript
+/// as RegionQuadTreeImage.
+#[wasm_bindgen(js_name = RegionQuadTreeImage)]
+pub struct JsRegionQuadTreeImage(RegionQuadTreeImage);
+
+/// Public methods, exported to JavaScript.
+#[wasm_bindgen(js_class = RegionQuadTreeImage)]
+impl JsRegionQuadTreeImage {
+    pub fn new(image: JsImage) -> JsRegionQuadTreeImage {
+        JsRegionQuadTreeImage(RegionQuadTreeImage::new(image.0))
+    }
+
+    pub fn set_error_metric(&mut self, name: &str) -> Result<(), QuadtreeError> {
+        self.0.set_error_metric(name)
+    }
+
+    /// Split leaves according to the split strategy with the given name, one
+    /// of "midpoint", "variance_cut" or "binary", from now on. See
+    /// `RegionQuadTreeImage::set_split_strategy`.
+    pub fn set_split_strategy(&mut self, name: &str) -> Result<(), QuadtreeError> {
+        let strategy = SplitStrategy::by_name(name)
+            .ok_or_else(|| QuadtreeError::UnknownSplitStrategy(name.to_string()))?;
+        self.0.set_split_strategy(strategy);
+        Ok(())
+    }
+
+    /// Approximate leaves by linear fits if linear is true, or by their
+    /// mean colors otherwise. See `RegionQuadTreeImage::set_leaf_model`.
+    pub fn set_linear_leaves(&mut self, linear: bool) {
+        self.0.set_leaf_model(if linear {
+            LeafModel::Linear
+        } else {
+            LeafModel::Flat
+        });
+    }
+
+    pub fn subdivide_until(&mut self, error_threshold: f32, min_region_length: usize) {
+        self.0.subdivide_until(error_threshold, min_region_length)
+    }
+
+    pub fn split_worst(&mut self, min_region_length: usize) -> bool {
+        self.0.split_worst(min_region_length)
+    }
+
+    pub fn subdivide_to_budget(
+        &mut self,
+        max_leaves: usize,
+        max_total_error: f32,
+        min_region_length: usize,
+    ) {
+        self.0
+            .subdivide_to_budget(max_leaves, max_total_error, min_region_length)
+    }
+
+    pub fn merge_siblings(&mut self, error_threshold: f32) {
+        self.0.merge_siblings(error_threshold)
+    }
+
+    pub fn get_segmented_image(&self, threshold: f64) -> JsImage {
+        JsImage(self.0.get_segmented_image(threshold))
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.0.leaf_count()
+    }
+
+    pub fn total_error(&self) -> f32 {
+        self.0.total_error()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.0.node_count()
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.0.depth()
+    }
+
+    /// The leaves in pre-order, as six numbers each: x, y, width, height,
+    /// the mean color packed as 0xRRGGBBAA, and the depth. To draw the
+    /// partition directly with a canvas or WebGL.
+    pub fn leaves(&self) -> Vec<u32> {
+        self.0
+            .leaves()
+            .flat_map(|leaf| {
+                let Region {
+                    x,
+                    y,
+                    width,
+                    height,
+                } = leaf.region;
+                [x, y, width, height]
+                    .map(|n| n as u32)
+                    .into_iter()
+                    .chain([leaf.mean.to_u32(), leaf.depth])
+            })
+            .collect()
+    }
+
+    /// The error of each leaf, in the same order as leaves.
+    pub fn leaf_errors(&self) -> Vec<f32> {
+        self.0.leaves().map(|leaf| leaf.error).collect()
+    }
+
+    pub fn to_bytes(&self, color_bits: u8, delta: bool) -> Result<Vec<u8>, QuadtreeError> {
+        self.0.to_bytes(color_bits, delta)
+    }
+
+    /// The partition as an SVG document. Leaves are outlined in the given
+    /// RGBA color if stroke_width is greater than 0, and left out if their
+    /// width and height are both less than min_size.
+    pub fn to_svg(
+        &self,
+        stroke_rgba: &[u8],
+        stroke_width: f64,
+        min_size: usize,
+    ) -> Result<String, QuadtreeError> {
+        let stroke = if stroke_width > 0.0 {
+            check_rgba_len(1, stroke_rgba)?;
+            Some(Stroke {
+                color: Rgba::from_slice(stroke_rgba),
+                width: stroke_width,
+            })
+        } else {
+            None
+        };
+        Ok(self.0.to_svg(&SvgOptions { stroke, min_size }))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<JsRegionQuadTreeImage, QuadtreeError> {
+        RegionQuadTreeImage::from_bytes(bytes).map(JsRegionQuadTreeImage)
+    }
+
+    pub fn get_result_image(&self) -> JsImage {
+        JsImage(self.0.get_result_image())
+    }
+
+    /// A debug rendering of the partition, with the leaves colored by
+    /// "mean", "depth" or "error", and outlined in the given RGBA color if
+    /// border_thickness is greater than 0.
+    pub fn get_overlay_image(
+        &self,
+        coloring: &str,
+        border_rgba: &[u8],
+        border_thickness: usize,
+    ) -> Result<JsImage, QuadtreeError> {
+        let options = OverlayOptions::from_js(coloring, border_rgba, border_thickness)?;
+        Ok(JsImage(self.0.get_overlay_image(&options)))
+    }
+
+    /// The x, y, width and height of the leaf nearest to (x, y) whose color
+    /// is within tolerance of the given RGBA color in every channel, or an
+    /// empty array if there is none at most max_distance away.
+    pub fn nearest_leaf(
+        &self,
+        x: f64,
+        y: f64,
+        rgba: &[u8],
+        tolerance: u8,
+        max_distance: Option<f64>,
+    ) -> Result<Vec<usize>, QuadtreeError> {
+        check_rgba_len(1, rgba)?;
+        let color = Rgba::from_slice(rgba);
+        let matches = |c: &Rgba| {
+            (0..Rgba::CHANNELS).all(|i| (c.channel(i) - color.channel(i)).abs() <= tolerance as f64)
+        };
+        Ok(self
+            .0
+            .nearest_leaf(&Point::new(x, y), max_distance, matches)
+            .map_or_else(Vec::new, |(r, _)| vec![r.x, r.y, r.width, r.height]))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    impl<P: Pixel> RegionQuadTreeImage<P> {
+        fn stats(&self) -> ImageStats<'_, P> {
+            ImageStats::new(&self.source, self.metric.as_ref(), self.model)
+        }
+    }
+
     fn test_image_4x4() -> Image {
         let mut pixels = vec![];
         for i in 1..1 + 16 {
@@ -249,7 +1316,721 @@ mod tests {
         let mut tree = RegionQuadTreeImage::new(test_image_4x4());
         tree.subdivide_until(1.0, 1);
         let result = tree.get_result_image();
-        assert_eq!(tree.image.width(), result.width());
-        assert_eq!(tree.image.height(), result.height());
+        assert_eq!(tree.source.width, result.width());
+        assert_eq!(tree.source.height, result.height());
+        assert_eq!(tree.leaf_count(), tree.quadtree.leaf_count());
+        assert_eq!(tree.total_error(), tree.quadtree.get_error(&tree.stats()));
+
+        // a minimum of 0 stops at single pixels, like a minimum of 1.
+        let mut zero = RegionQuadTreeImage::new(test_image_4x4());
+        zero.subdivide_until(0.0, 0);
+        let mut one = RegionQuadTreeImage::new(test_image_4x4());
+        one.subdivide_until(0.0, 1);
+        assert_eq!(zero.leaf_count(), one.leaf_count());
+    }
+
+    #[test]
+    fn error_metrics() {
+        let mut leaf_counts = vec![];
+        for name in error_metric::METRIC_NAMES {
+            let mut tree = RegionQuadTreeImage::new(test_image_gradient(32, 32));
+            tree.set_error_metric(name).unwrap();
+            tree.subdivide_to_budget(64, 0.0, 1);
+            assert_eq!(tree.leaf_count(), 64);
+            let actual = tree.quadtree.get_error(&tree.stats());
+            assert!((tree.total_error() - actual).abs() < 1e-3 * actual.max(1.0));
+
+            let mut tree = RegionQuadTreeImage::new(test_image_gradient(32, 32));
+            tree.set_error_metric(name).unwrap();
+            tree.subdivide_until(0.5, 1);
+            leaf_counts.push(tree.leaf_count());
+        }
+        assert!(leaf_counts.iter().all(|&c| c > 1));
+    }
+
+    #[test]
+    fn split_strategies() {
+        // a black stripe 3 pixels wide on the left of a white image.
+        let pixels = (0..8 * 8)
+            .map(|i| {
+                let value = if i % 8 < 3 { 0 } else { 255 };
+                Rgba::from_slice(&[value, value, value, 255])
+            })
+            .collect();
+        let image = Image::new(8, 8, pixels);
+        let leaf_count = |strategy| {
+            let mut tree = RegionQuadTreeImage::new(image.clone());
+            tree.set_split_strategy(strategy);
+            assert_eq!(tree.split_strategy(), strategy);
+            tree.subdivide_until(1.0, 1);
+            assert_eq!(tree.get_result_image(), image);
+            assert_eq!(tree.total_error(), 0.0);
+            tree.leaf_count()
+        };
+        // midpoint splits cut the regions along the edge of the stripe
+        // down to single pixels.
+        assert_eq!(leaf_count(SplitStrategy::Midpoint), 22);
+        // the cut is at the edge of the stripe.
+        assert_eq!(leaf_count(SplitStrategy::VarianceCut), 4);
+        // halves of 4, then 2, then 1 column, only across x.
+        assert_eq!(leaf_count(SplitStrategy::Binary), 4);
+
+        // a binary split adds one leaf at a time in budget mode.
+        let mut tree = RegionQuadTreeImage::new(test_image_gradient(16, 12));
+        tree.set_split_strategy(SplitStrategy::Binary);
+        tree.subdivide_to_budget(7, 0.0, 1);
+        assert_eq!(tree.leaf_count(), 7);
+        assert_eq!(tree.leaves().count(), 7);
+        let actual = tree.quadtree.get_error(&tree.stats());
+        assert!((tree.total_error() - actual).abs() < 1e-3 * actual.max(1.0));
+
+        // a binary split keeps cutting a strip 1 pixel wide along its
+        // length, where quadrants can't.
+        let strip = test_image_gradient(1, 8);
+        for budget in [false, true] {
+            let mut tree = RegionQuadTreeImage::new(strip.clone());
+            tree.set_split_strategy(SplitStrategy::Binary);
+            if budget {
+                tree.subdivide_to_budget(8, 0.0, 1);
+            } else {
+                tree.subdivide_until(0.0, 1);
+            }
+            assert_eq!(tree.get_result_image(), strip);
+            assert_eq!(tree.leaf_count(), 8);
+        }
+        let mut tree = RegionQuadTreeImage::new(strip);
+        tree.subdivide_until(0.0, 1);
+        assert_eq!(tree.leaf_count(), 1);
+
+        // sides of at most the minimum length are not cut.
+        let mut tree = RegionQuadTreeImage::new(test_image_gradient(3, 16));
+        tree.set_split_strategy(SplitStrategy::Binary);
+        tree.subdivide_until(0.0, 3);
+        // heights of 16, 8, 4 and then 2.
+        assert!(tree.leaves().all(|leaf| leaf.region.width == 3));
+        assert_eq!(tree.leaf_count(), 8);
+    }
+
+    #[test]
+    fn linear_leaves() {
+        // a smooth gradient in every channel.
+        let mut pixels = vec![];
+        for y in 0..32 {
+            for x in 0..32 {
+                pixels.push(Rgba {
+                    red: (20 + 5 * x) as u8,
+                    green: (200 - 3 * y) as u8,
+                    blue: (2 * x + 2 * y) as u8,
+                    alpha: 255,
+                });
+            }
+        }
+        let image = Image::new(32, 32, pixels);
+
+        let mut flat = RegionQuadTreeImage::new(image.clone());
+        flat.subdivide_until(1.0, 1);
+        assert!(flat.leaf_count() > 1);
+
+        let mut linear = RegionQuadTreeImage::new(image.clone());
+        linear.set_leaf_model(LeafModel::Linear);
+        assert_eq!(linear.leaf_model(), LeafModel::Linear);
+        assert!(linear.total_error() < 1e-3);
+        linear.subdivide_until(1.0, 1);
+        assert_eq!(linear.leaf_count(), 1);
+        let result = linear.get_result_image();
+        for (a, b) in result.pixels().iter().zip(image.pixels()) {
+            assert_eq!(a, b);
+        }
+
+        // the leaf model only changes the error, not the leaves.
+        linear.set_leaf_model(LeafModel::Flat);
+        assert!(linear.total_error() > 1.0);
+        assert_eq!(linear.leaf_count(), 1);
+    }
+
+    #[test]
+    fn linear_leaves_fewer() {
+        for name in error_metric::METRIC_NAMES {
+            let mut flat = RegionQuadTreeImage::new(test_image_gradient(32, 32));
+            flat.set_error_metric(name).unwrap();
+            flat.subdivide_to_budget(40, 0.0, 1);
+            let mut linear = RegionQuadTreeImage::new(test_image_gradient(32, 32));
+            linear.set_error_metric(name).unwrap();
+            linear.set_leaf_model(LeafModel::Linear);
+            linear.subdivide_to_budget(40, 0.0, 1);
+            assert_eq!(linear.leaf_count(), flat.leaf_count(), "{name}");
+            assert!(linear.total_error() < flat.total_error(), "{name}");
+            let actual = linear.quadtree.get_error(&linear.stats());
+            assert!((linear.total_error() - actual).abs() < 1e-3 * actual.max(1.0));
+        }
+    }
+
+    #[test]
+    fn bytes_roundtrip() {
+        let mut tree = RegionQuadTreeImage::new(test_image_gradient(37, 29));
+        tree.subdivide_until(20.0, 1);
+        let expected = tree.get_result_image();
+        for delta in [false, true] {
+            let bytes = tree.to_bytes(8, delta).unwrap();
+            let decoded = RegionQuadTreeImage::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded.leaf_count(), tree.leaf_count());
+            assert_eq!(decoded.get_result_image(), expected);
+            // the image is the rendering of the tree, so it has no error.
+            assert_eq!(decoded.total_error(), 0.0);
+            assert_eq!(decoded.quadtree.get_error(&decoded.stats()), 0.0);
+            assert_eq!(decoded.to_bytes(8, delta).unwrap(), bytes);
+        }
+        // trees with other cuts than quadrants are written with their cuts.
+        for strategy in [SplitStrategy::VarianceCut, SplitStrategy::Binary] {
+            let mut other = RegionQuadTreeImage::new(test_image_block());
+            other.set_split_strategy(strategy);
+            other.subdivide_until(20.0, 1);
+            let bytes = other.to_bytes(8, true).unwrap();
+            let decoded = RegionQuadTreeImage::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded.leaf_count(), other.leaf_count());
+            assert_eq!(decoded.get_result_image(), other.get_result_image());
+            assert_eq!(decoded.to_bytes(8, true).unwrap(), bytes);
+        }
+        // the gradient is smooth, so deltas are small.
+        assert!(tree.to_bytes(8, true).unwrap().len() < tree.to_bytes(8, false).unwrap().len());
+    }
+
+    #[test]
+    fn bytes_quantized() {
+        let mut tree = RegionQuadTreeImage::new(test_image_gradient(32, 32));
+        tree.subdivide_until(20.0, 1);
+        let expected = tree.get_result_image();
+        for bits in 1..8 {
+            let bytes = tree.to_bytes(bits, true).unwrap();
+            let decoded = RegionQuadTreeImage::from_bytes(&bytes).unwrap();
+            let result = decoded.get_result_image();
+            let max_difference = (256u32 >> bits) as u8;
+            for x in 0..32 {
+                for y in 0..32 {
+                    let (a, b) = (result.get_pixel(x, y), expected.get_pixel(x, y));
+                    assert!(a.red.abs_diff(b.red) <= max_difference);
+                    assert!(a.green.abs_diff(b.green) <= max_difference);
+                    assert!(a.blue.abs_diff(b.blue) <= max_difference);
+                }
+            }
+            // decoding is lossless from then on.
+            assert_eq!(decoded.to_bytes(bits, true).unwrap(), bytes);
+            assert!(bytes.len() < tree.to_bytes(8, false).unwrap().len());
+        }
+    }
+
+    #[test]
+    fn bytes_malformed() {
+        let tree = RegionQuadTreeImage::new(test_image_4x4());
+        let bytes = tree.to_bytes(8, false).unwrap();
+        assert!(RegionQuadTreeImage::from_bytes(&bytes).is_ok());
+        assert!(RegionQuadTreeImage::from_bytes(&bytes[1..]).is_err());
+        assert!(RegionQuadTreeImage::from_bytes(&[]).is_err());
+        // a 14 byte header that claims an image of u32::MAX by u32::MAX
+        // pixels, with a single leaf.
+        let mut oversized = bytes[..14].to_vec();
+        oversized[4..12].copy_from_slice(&[0xff; 8]);
+        oversized.extend_from_slice(&[0, 0, 0, 0, 0]);
+        assert_eq!(
+            RegionQuadTreeImage::from_bytes(&oversized).err(),
+            Some(QuadtreeError::MalformedEncoding)
+        );
+        let mut huge = oversized.clone();
+        huge[4..12].copy_from_slice(&[0, 0x40, 0, 0, 0, 0x40, 0, 0]);
+        assert!(RegionQuadTreeImage::from_bytes(&huge).is_err());
+        // a 4096x4096 image of a single leaf is decoded without building
+        // its image or its table.
+        let mut large = oversized;
+        large[4..12].copy_from_slice(&[0, 0x10, 0, 0, 0, 0x10, 0, 0]);
+        let decoded = RegionQuadTreeImage::from_bytes(&large).unwrap();
+        assert_eq!(decoded.leaf_count(), 1);
+        assert_eq!(decoded.leaf_at(4095, 4095).unwrap().0.width, 4096);
+        assert!(decoded.source.image.get().is_none());
+        assert!(decoded.source.table.get().is_none());
+        assert_eq!(
+            tree.to_bytes(9, false),
+            Err(QuadtreeError::InvalidColorBits(9))
+        );
+        assert!(tree.to_bytes(0, true).is_err());
+    }
+
+    #[test]
+    fn svg() {
+        let mut tree = RegionQuadTreeImage::new(test_image_gradient(37, 29));
+        tree.subdivide_until(20.0, 1);
+        let svg = tree.to_svg(&SvgOptions::default());
+        assert_eq!(svg.matches("<rect").count(), tree.leaf_count());
+        let options = SvgOptions {
+            stroke: None,
+            min_size: 4,
+        };
+        let small = tree.to_svg(&options);
+        assert!(small.matches("<rect").count() < tree.leaf_count());
+    }
+
+    #[test]
+    fn overlay() {
+        let mut tree = RegionQuadTreeImage::new(test_image_gradient(37, 29));
+        tree.subdivide_until(20.0, 1);
+        let mean = tree.get_overlay_image(&OverlayOptions::default());
+        assert_eq!(mean, tree.get_result_image());
+
+        let black = Rgba::from_slice(&[0, 0, 0, 255]);
+        let options = OverlayOptions {
+            border: Some(overlay::Border {
+                color: black,
+                thickness: 1,
+            }),
+            coloring: overlay::LeafColoring::Depth,
+        };
+        let image = tree.get_overlay_image(&options);
+        assert_eq!((image.width(), image.height()), (37, 29));
+        let mut leaves = vec![];
+        tree.quadtree.for_each_leaf(0, &mut |region, _, depth| {
+            leaves.push((region.clone(), depth))
+        });
+        let deepest = leaves.iter().map(|(_, depth)| *depth).max().unwrap();
+        for (region, depth) in leaves {
+            // the top left corner of every leaf is on its border.
+            assert_eq!(image.get_pixel(region.x, region.y), black);
+            let inside_x = region.x + region.width - 1;
+            let inside_y = region.y + region.height - 1;
+            if region.width > 1 && region.height > 1 && inside_x < 36 && inside_y < 28 {
+                let inside = image.get_pixel(inside_x, inside_y);
+                assert_ne!(inside, black);
+                // the deepest leaves are red on the heatmap.
+                assert_eq!(
+                    [inside.red, inside.green, inside.blue] == [255, 0, 0],
+                    depth == deepest
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_error_metric() {
+        let mut tree = RegionQuadTreeImage::new(test_image_4x4());
+        assert_eq!(
+            tree.set_error_metric("psnr"),
+            Err(QuadtreeError::UnknownErrorMetric("psnr".to_string()))
+        );
+    }
+
+    #[test]
+    fn height_map() {
+        // a plateau in one quadrant, and flat elsewhere.
+        let mut heights = vec![0.0f32; 16 * 16];
+        for y in 0..8 {
+            for x in 8..16 {
+                heights[y * 16 + x] = 100.0 + x as f32;
+            }
+        }
+        let mut tree = RegionQuadTreeImage::new(Image::new(16, 16, heights));
+        tree.subdivide_until(1.0, 1);
+        // the flat quadrants stay whole, the plateau is split down to 2x2 regions
+        // with a mean squared error of at most 1.
+        assert_eq!(tree.leaf_count(), 3 + 16);
+        let result = tree.get_result_image();
+        assert_eq!(result.get_pixel(0, 15), 0.0);
+        assert_eq!(result.get_pixel(8, 0), 108.5);
+    }
+
+    #[test]
+    fn nearest_leaves() {
+        let mut heights = vec![0.0f32; 16 * 16];
+        for y in 0..8 {
+            for x in 8..16 {
+                heights[y * 16 + x] = 100.0 + x as f32;
+            }
+        }
+        let mut tree = RegionQuadTreeImage::new(Image::new(16, 16, heights));
+        tree.subdivide_until(1.0, 1);
+        let high = |h: &f32| *h > 50.0;
+        let (region, height) = tree
+            .nearest_leaf(&Point::new(0.5, 0.5), None, high)
+            .unwrap();
+        assert_eq!(
+            (region.x, region.y, region.width, region.height),
+            (8, 0, 2, 2)
+        );
+        assert_eq!(height, 108.5);
+        assert!(tree
+            .nearest_leaf(&Point::new(0.5, 0.5), Some(7.0), high)
+            .is_none());
+        // the point is in the first leaf, then come the one below it and the one on its left.
+        let nearest = tree.k_nearest_leaves(&Point::new(15.0, 1.5), 3, None, high);
+        let xs: Vec<_> = nearest.iter().map(|(r, _)| (r.x, r.y)).collect();
+        assert_eq!(xs, vec![(14, 0), (14, 2), (12, 0)]);
+    }
+
+    #[test]
+    fn nearest_leaves_same_as_brute_force() {
+        let mut tree = RegionQuadTreeImage::new(test_image_gradient(37, 29));
+        tree.subdivide_until(20.0, 1);
+        let mut paths = vec![];
+        tree.quadtree.leaf_paths(&mut vec![], &mut paths);
+        let mut leaves = vec![];
+        for path in paths {
+            if let RegionQuadTree::Leaf(region, color) = tree.quadtree.get_mut(&path).clone() {
+                leaves.push((region, color));
+            }
+        }
+        let reddish = |c: &Rgba| c.red > 100;
+        for point in [
+            Point::new(0.0, 0.0),
+            Point::new(20.5, 3.2),
+            Point::new(50.0, -5.0),
+        ] {
+            let mut expected: Vec<_> = leaves
+                .iter()
+                .filter(|(_, c)| reddish(c))
+                .map(|(r, _)| r.to_f64().distance_squared(&point))
+                .collect();
+            expected.sort_by(f64::total_cmp);
+            expected.truncate(10);
+            let actual: Vec<_> = tree
+                .k_nearest_leaves(&point, 10, None, reddish)
+                .into_iter()
+                .map(|(r, c)| {
+                    assert!(leaves.contains(&(r.clone(), c)));
+                    r.to_f64().distance_squared(&point)
+                })
+                .collect();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    /// A gradient with a bright block off the midlines, so that the
+    /// subdivision is irregular.
+    fn test_image_block() -> Image {
+        let gradient = test_image_gradient(37, 29);
+        let mut pixels = gradient.pixels().to_vec();
+        for y in 7..10 {
+            for x in 20..25 {
+                pixels[y * 37 + x] = Rgba::from_slice(&[255, 255, 255, 255]);
+            }
+        }
+        Image::new(37, 29, pixels)
+    }
+
+    /// Whether b shares part of the given edge of a.
+    fn shares_edge(a: &Region, b: &Region, edge: Edge) -> bool {
+        let overlaps_x = a.x < b.x + b.width && b.x < a.x + a.width;
+        let overlaps_y = a.y < b.y + b.height && b.y < a.y + a.height;
+        match edge {
+            Edge::North => b.y + b.height == a.y && overlaps_x,
+            Edge::South => a.y + a.height == b.y && overlaps_x,
+            Edge::West => b.x + b.width == a.x && overlaps_y,
+            Edge::East => a.x + a.width == b.x && overlaps_y,
+        }
+    }
+
+    #[test]
+    fn neighbors_same_as_brute_force() {
+        let strategies = [
+            SplitStrategy::Midpoint,
+            SplitStrategy::VarianceCut,
+            SplitStrategy::Binary,
+        ];
+        for strategy in strategies {
+            for (error, min_region_length) in [(200.0, 1), (50.0, 2), (1000.0, 3)] {
+                neighbors_same_as_brute_force_for(strategy, error, min_region_length);
+            }
+        }
+    }
+
+    fn neighbors_same_as_brute_force_for(
+        strategy: SplitStrategy,
+        error: f32,
+        min_region_length: usize,
+    ) {
+        let mut tree = RegionQuadTreeImage::new(test_image_block());
+        tree.set_split_strategy(strategy);
+        tree.subdivide_until(error, min_region_length);
+        let mut leaves = vec![];
+        tree.quadtree.for_each_leaf(0, &mut |region, mean, _| {
+            leaves.push((region.clone(), *mean))
+        });
+        let mut smaller_neighbors = 0;
+        for (leaf, mean) in &leaves {
+            assert_eq!(tree.leaf_at(leaf.x, leaf.y), Some((leaf.clone(), *mean)));
+            for edge in Edge::ALL {
+                let mut expected: Vec<_> = leaves
+                    .iter()
+                    .filter(|(r, _)| shares_edge(leaf, r, edge))
+                    .cloned()
+                    .collect();
+                expected.sort_by_key(|(r, _)| match edge {
+                    Edge::North | Edge::South => r.x,
+                    Edge::East | Edge::West => r.y,
+                });
+                let actual = tree.neighbor_leaves(leaf, edge);
+                assert_eq!(actual, expected, "{edge:?} of {leaf:?}");
+
+                let Some(neighbor) = tree.neighbor(leaf, edge) else {
+                    assert!(expected.is_empty());
+                    continue;
+                };
+                // the neighbor is across the edge, and covers all the
+                // leaves along it.
+                assert!(shares_edge(leaf, &neighbor, edge));
+                for (r, _) in &actual {
+                    assert!(neighbor.to_f64().contains_region(&r.to_f64()));
+                }
+                if actual.len() > 1 {
+                    smaller_neighbors += 1;
+                }
+            }
+            for corner in Corner::ALL {
+                let x = match corner {
+                    Corner::NorthWest | Corner::SouthWest => leaf.x.checked_sub(1),
+                    Corner::NorthEast | Corner::SouthEast => Some(leaf.x + leaf.width),
+                };
+                let y = match corner {
+                    Corner::NorthWest | Corner::NorthEast => leaf.y.checked_sub(1),
+                    Corner::SouthWest | Corner::SouthEast => Some(leaf.y + leaf.height),
+                };
+                let expected = x.zip(y).and_then(|(x, y)| {
+                    leaves.iter().find(|(r, _)| r.contains_pixel(x, y)).cloned()
+                });
+                assert_eq!(tree.corner_neighbor(leaf, corner), expected);
+            }
+        }
+        assert!(smaller_neighbors > 0);
+    }
+
+    #[test]
+    fn leaves() {
+        let mut tree = RegionQuadTreeImage::new(test_image_block());
+        tree.subdivide_until(50.0, 1);
+        let leaves: Vec<_> = tree.leaves().collect();
+        assert_eq!(leaves.len(), tree.leaf_count());
+        let mut expected = vec![];
+        tree.quadtree.for_each_leaf(0, &mut |region, mean, depth| {
+            expected.push((region.clone(), *mean, depth))
+        });
+        for (leaf, (region, mean, depth)) in leaves.iter().zip(expected) {
+            assert_eq!(
+                (&leaf.region, leaf.mean, leaf.depth),
+                (&region, mean, depth)
+            );
+        }
+        let total_error: f32 = leaves.iter().map(|l| l.error).sum();
+        assert!((total_error - tree.total_error()).abs() <= 1e-3 * tree.total_error());
+
+        // every split turns a leaf into a branch with four children.
+        assert_eq!(tree.node_count(), 1 + (tree.leaf_count() - 1) / 3 * 4);
+        let deepest = leaves.iter().map(|l| l.depth).max().unwrap();
+        assert_eq!(tree.depth(), deepest);
+        assert_eq!(RegionQuadTreeImage::new(test_image_4x4()).depth(), 0);
+
+        let flat = JsRegionQuadTreeImage(tree).leaves();
+        assert_eq!(flat.len(), 6 * leaves.len());
+        let Region {
+            x,
+            y,
+            width,
+            height,
+        } = leaves[1].region;
+        let color = leaves[1].mean;
+        assert_eq!(
+            flat[6..12],
+            [
+                x as u32,
+                y as u32,
+                width as u32,
+                height as u32,
+                u32::from_be_bytes([color.red, color.green, color.blue, color.alpha]),
+                leaves[1].depth,
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_siblings() {
+        // a white block in the top left corner of a black image.
+        let mut pixels = vec![Rgba::from_slice(&[0, 0, 0, 255]); 16 * 16];
+        for y in 0..4 {
+            for x in 0..4 {
+                pixels[y * 16 + x] = Rgba::from_slice(&[255, 255, 255, 255]);
+            }
+        }
+        let image = Image::new(16, 16, pixels);
+        let mut tree = RegionQuadTreeImage::new(image.clone());
+        // split everything, down to single pixels.
+        tree.subdivide_until(0.0, 1);
+        assert_eq!(tree.leaf_count(), 256);
+        tree.merge_siblings(1.0);
+        // the three other quadrants, and the four quadrants of the top left.
+        assert_eq!(tree.leaf_count(), 7);
+        assert_eq!(tree.leaf_count(), tree.quadtree.leaf_count());
+        assert_eq!(tree.total_error(), 0.0);
+        assert_eq!(tree.get_result_image(), image);
+        // merging is done, and splitting picks up where it left off.
+        tree.merge_siblings(1.0);
+        assert_eq!(tree.leaf_count(), 7);
+        assert!(!tree.split_worst(1));
+        // with a large enough threshold, everything merges into the root.
+        tree.merge_siblings(f32::MAX);
+        assert_eq!(tree.leaf_count(), 1);
+    }
+
+    #[test]
+    fn segments() {
+        // black on the left and white on the right, split off the midline.
+        let mut pixels = vec![];
+        for _ in 0..32 {
+            for x in 0..32 {
+                let value = if x < 13 { 0 } else { 255 };
+                pixels.push(Rgba::from_slice(&[value, value, value, 255]));
+            }
+        }
+        let image = Image::new(32, 32, pixels);
+        let mut tree = RegionQuadTreeImage::new(image.clone());
+        tree.subdivide_until(1.0, 1);
+        assert!(tree.leaf_count() > 2);
+        let segments = tree.segments(1.0);
+        assert_eq!(segments.len(), 2);
+        let area = |s: &Segment| s.regions.iter().map(|r| r.width * r.height).sum::<usize>();
+        assert_eq!((area(&segments[0]), area(&segments[1])), (13 * 32, 19 * 32));
+        assert_eq!(segments[0].mean, Rgba::from_slice(&[0, 0, 0, 255]));
+        assert_eq!(tree.get_segmented_image(1.0), image);
+        // everything merges with a large threshold, nothing with 0.
+        assert_eq!(tree.segments(1e9).len(), 1);
+        assert_eq!(tree.segments(0.0).len(), tree.leaf_count());
+
+        // segments cover the image exactly once.
+        let mut tree = RegionQuadTreeImage::new(test_image_block());
+        tree.subdivide_until(50.0, 1);
+        let segments = tree.segments(200.0);
+        assert!(segments.len() < tree.leaf_count());
+        let mut covered = vec![0; 37 * 29];
+        for segment in &segments {
+            for r in &segment.regions {
+                for y in r.y..r.y + r.height {
+                    for x in r.x..r.x + r.width {
+                        covered[y * 37 + x] += 1;
+                    }
+                }
+            }
+        }
+        assert!(covered.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn neighbors_of_branches() {
+        let mut tree = RegionQuadTreeImage::new(test_image_block());
+        tree.subdivide_until(200.0, 1);
+        let root = tree.root_region();
+        assert_eq!(tree.leaf_at(37, 0), None);
+        for edge in Edge::ALL {
+            assert_eq!(tree.neighbor(&root, edge), None);
+            assert!(tree.neighbor_leaves(&root, edge).is_empty());
+        }
+        // the top left quadrant is across the west edge of the top right one,
+        // and both are branches.
+        let [top_left, _, top_right, _] = root.quadrants();
+        assert_eq!(
+            tree.neighbor(&top_right, Edge::West),
+            Some(top_left.clone())
+        );
+        let leaves = tree.neighbor_leaves(&top_right, Edge::West);
+        assert!(leaves.len() > 1);
+        assert!(leaves
+            .iter()
+            .all(|(r, _)| r.x + r.width == top_right.x && r.y < top_right.height));
+        // not the region of any node.
+        let region = Region {
+            x: 1,
+            y: 1,
+            width: 3,
+            height: 3,
+        };
+        assert_eq!(tree.neighbor(&region, Edge::East), None);
+        assert!(tree.neighbor_leaves(&region, Edge::East).is_empty());
+    }
+
+    fn test_image_gradient(width: usize, height: usize) -> Image {
+        let mut pixels = vec![];
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(Rgba {
+                    red: (x * 255 / width) as u8,
+                    green: (y * 255 / height) as u8,
+                    blue: ((x * y) % 256) as u8,
+                    alpha: 255,
+                });
+            }
+        }
+
+        Image::new(width, height, pixels)
+    }
+
+    #[test]
+    fn split_worst() {
+        let mut tree = RegionQuadTreeImage::new(test_image_gradient(16, 12));
+        assert_eq!(tree.leaf_count(), 1);
+        let mut previous_error = tree.total_error();
+        while tree.split_worst(2) {
+            assert_eq!(tree.leaf_count(), tree.quadtree.leaf_count());
+            assert!(tree.total_error() <= previous_error + 1e-3);
+            let actual = tree.quadtree.get_error(&tree.stats());
+            assert!((tree.total_error() - actual).abs() < 1e-2 * actual.max(1.0));
+            previous_error = tree.total_error();
+        }
+        // every region of length > 2 is split, so all leaves are 2 wide
+        // and 1 or 2 high.
+        assert_eq!(tree.leaf_count(), 8 * 8);
+    }
+
+    #[test]
+    fn frames() {
+        let image = test_image_gradient(37, 29);
+        let mut tree = RegionQuadTreeImage::new(image.clone());
+        let frames = tree.subdivide_until_frames(20.0, 1);
+        let mut expected = RegionQuadTreeImage::new(image.clone());
+        expected.subdivide_until(20.0, 1);
+        assert_eq!(tree.leaf_count(), expected.leaf_count());
+        assert_eq!(frames.last(), Some(&expected.get_result_image()));
+        assert_eq!(
+            frames[0],
+            RegionQuadTreeImage::new(image.clone()).get_result_image()
+        );
+        // each pass splits at least one leaf, and there are at most as many
+        // passes as the depth of the tree.
+        assert!(frames.windows(2).all(|w| w[0] != w[1]));
+        assert!(frames.len() <= 1 + 6);
+
+        let mut tree = RegionQuadTreeImage::new(image.clone());
+        let frames = tree.subdivide_to_budget_frames(40, 0.0, 1, 3);
+        let mut expected = RegionQuadTreeImage::new(image);
+        expected.subdivide_to_budget(40, 0.0, 1);
+        assert_eq!(tree.leaf_count(), expected.leaf_count());
+        // 13 splits make 40 leaves, in 5 frames of 3 and a last one of 1.
+        assert_eq!(frames.len(), 1 + 5);
+        assert_eq!(frames.last(), Some(&expected.get_result_image()));
+    }
+
+    #[test]
+    fn subdivide_to_budget() {
+        let mut tree = RegionQuadTreeImage::new(test_image_gradient(32, 32));
+        tree.subdivide_to_budget(40, 0.0, 1);
+        assert_eq!(tree.leaf_count(), 40);
+
+        let mut other = RegionQuadTreeImage::new(test_image_gradient(32, 32));
+        other.subdivide_to_budget(40, 0.0, 1);
+        assert_eq!(tree.get_result_image(), other.get_result_image());
+
+        let error = tree.total_error();
+        tree.subdivide_to_budget(usize::MAX, error / 2.0, 0);
+        assert!(tree.total_error() <= error / 2.0);
+        assert!(tree.leaf_count() > 40);
+
+        // subdivide_until and budget mode can be mixed.
+        let mut tree = RegionQuadTreeImage::new(test_image_gradient(32, 32));
+        tree.subdivide_until(500.0, 1);
+        let leaf_count = tree.leaf_count();
+        tree.subdivide_to_budget(leaf_count + 3, 0.0, 1);
+        assert_eq!(tree.leaf_count(), leaf_count + 3);
     }
 }
@@ -0,0 +1,259 @@
+//! Split-and-merge segmentation: after a region quadtree has split an image
+//! into leaves of similar color, adjacent leaves with similar colors are
+//! merged into segments. Unlike the leaves, segments can have any shape,
+//! and cross the boundaries between quadrants.
+//!
+//! Merging is greedy: the two adjacent segments whose mean colors are
+//! closest are merged first, until no two adjacent segments are closer
+//! than the threshold.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, BinaryHeap},
+};
+
+use crate::{image::Rgba, pixel::Pixel, region::Region};
+
+/// A set of adjacent leaves of a region quadtree, with similar colors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment<P: Pixel = Rgba> {
+    /// The regions of the leaves in the segment, in pre-order.
+    pub regions: Vec<Region>,
+    /// The mean color of all the pixels in the segment.
+    pub mean: P,
+}
+
+/// A segment while merging, identified by the index of its first leaf.
+#[derive(Debug)]
+struct Partial {
+    /// The channel sums of all the pixels in the segment.
+    sums: Vec<f64>,
+    pixel_count: usize,
+    /// The first leaves of the adjacent segments.
+    neighbors: BTreeSet<usize>,
+}
+
+impl Partial {
+    /// The weighted squared distance between the mean colors.
+    fn distance<P: Pixel>(&self, other: &Partial) -> f64 {
+        (0..P::CHANNELS)
+            .map(|c| {
+                let a = self.sums[c] / self.pixel_count as f64;
+                let b = other.sums[c] / other.pixel_count as f64;
+                P::WEIGHTS[c] * (a - b).powi(2)
+            })
+            .sum()
+    }
+}
+
+/// Two adjacent segments that are candidates for merging, with the pixel
+/// counts they had when the candidate was made, so that candidates for
+/// segments that have grown since can be skipped. Ordered so that the
+/// closest segments are merged first, ties broken by the segments.
+#[derive(Debug)]
+struct MergeCandidate {
+    distance: f64,
+    segments: (usize, usize),
+    pixel_counts: (usize, usize),
+}
+
+impl PartialEq for MergeCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeCandidate {}
+
+impl PartialOrd for MergeCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MergeCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .distance
+            .total_cmp(&self.distance)
+            .then_with(|| other.segments.cmp(&self.segments))
+    }
+}
+
+/// Merge the given leaves, as region and mean color, into segments. Two
+/// adjacent segments are merged if their mean colors are less than
+/// threshold apart, in weighted squared distance. adjacent holds the pairs
+/// of indices of leaves that share an edge. Segments are in the order of
+/// their first leaf. Leaves without pixels are left out.
+pub(crate) fn segment<P: Pixel>(
+    leaves: &[(Region, P)],
+    adjacent: &[(usize, usize)],
+    threshold: f64,
+) -> Vec<Segment<P>> {
+    let mut partials: Vec<Option<Partial>> = leaves
+        .iter()
+        .map(|(region, mean)| {
+            let pixel_count = region.width * region.height;
+            (pixel_count > 0).then(|| Partial {
+                sums: (0..P::CHANNELS)
+                    .map(|c| mean.channel(c) * pixel_count as f64)
+                    .collect(),
+                pixel_count,
+                neighbors: BTreeSet::new(),
+            })
+        })
+        .collect();
+    // the segment each leaf is in, by the first leaf of a segment that it
+    // was merged into, which may since have been merged itself.
+    let mut merged_into: Vec<usize> = (0..leaves.len()).collect();
+
+    let mut candidates = BinaryHeap::new();
+    let candidate = |partials: &[Option<Partial>], a: usize, b: usize| {
+        let (pa, pb) = (partials[a].as_ref()?, partials[b].as_ref()?);
+        Some(MergeCandidate {
+            distance: pa.distance::<P>(pb),
+            segments: (a.min(b), a.max(b)),
+            pixel_counts: if a < b {
+                (pa.pixel_count, pb.pixel_count)
+            } else {
+                (pb.pixel_count, pa.pixel_count)
+            },
+        })
+    };
+    for &(a, b) in adjacent {
+        if a == b || partials[a].is_none() || partials[b].is_none() {
+            continue;
+        }
+        partials[a].as_mut().unwrap().neighbors.insert(b);
+        partials[b].as_mut().unwrap().neighbors.insert(a);
+    }
+    for a in 0..partials.len() {
+        let neighbors = partials[a].as_ref().map(|p| p.neighbors.clone());
+        for b in neighbors.into_iter().flatten().filter(|&b| a < b) {
+            candidates.extend(candidate(&partials, a, b));
+        }
+    }
+
+    while let Some(MergeCandidate {
+        distance,
+        segments: (a, b),
+        pixel_counts,
+    }) = candidates.pop()
+    {
+        if distance >= threshold {
+            break;
+        }
+        let current = partials[a]
+            .as_ref()
+            .zip(partials[b].as_ref())
+            .map(|(pa, pb)| (pa.pixel_count, pb.pixel_count));
+        if current != Some(pixel_counts) {
+            continue;
+        }
+        // merge b into a, which comes first.
+        let merged = partials[b].take().unwrap();
+        merged_into[b] = a;
+        for &n in &merged.neighbors {
+            let neighbors = &mut partials[n].as_mut().unwrap().neighbors;
+            neighbors.remove(&b);
+            if n != a {
+                neighbors.insert(a);
+            }
+        }
+        let segment = partials[a].as_mut().unwrap();
+        for (sum, other) in segment.sums.iter_mut().zip(&merged.sums) {
+            *sum += other;
+        }
+        segment.pixel_count += merged.pixel_count;
+        segment
+            .neighbors
+            .extend(merged.neighbors.into_iter().filter(|&n| n != a));
+        let neighbors = segment.neighbors.clone();
+        for n in neighbors {
+            candidates.extend(candidate(&partials, a, n));
+        }
+    }
+
+    let mut segments: Vec<Option<Segment<P>>> = (0..leaves.len()).map(|_| None).collect();
+    for (i, (region, _)) in leaves.iter().enumerate() {
+        if region.width * region.height == 0 {
+            continue;
+        }
+        let mut first = i;
+        while merged_into[first] != first {
+            first = merged_into[first];
+        }
+        segments[first]
+            .get_or_insert_with(|| {
+                let partial = partials[first].as_ref().unwrap();
+                let means: Vec<_> = partial
+                    .sums
+                    .iter()
+                    .map(|sum| sum / partial.pixel_count as f64)
+                    .collect();
+                Segment {
+                    regions: vec![],
+                    mean: P::from_channels(&means),
+                }
+            })
+            .regions
+            .push(region.clone());
+    }
+    segments.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(x: usize, value: f32) -> (Region, f32) {
+        let region = Region {
+            x,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        (region, value)
+    }
+
+    #[test]
+    fn closest_first() {
+        // a row of leaves, each adjacent to the next.
+        let leaves = [
+            leaf(0, 0.0),
+            leaf(1, 1.0),
+            leaf(2, 10.0),
+            leaf(3, 11.0),
+            leaf(4, 14.0),
+        ];
+        let adjacent = [(0, 1), (1, 2), (2, 3), (3, 4)];
+        let segments = segment(&leaves, &adjacent, 5.0);
+        let regions: Vec<Vec<usize>> = segments
+            .iter()
+            .map(|s| s.regions.iter().map(|r| r.x).collect())
+            .collect();
+        assert_eq!(regions, [vec![0, 1], vec![2, 3], vec![4]]);
+        assert_eq!(segments[0].mean, 0.5);
+        assert_eq!(segments[1].mean, 10.5);
+
+        // with a larger threshold, 14 is merged with 10 and 11, whose mean
+        // is 12.25 away, but 0 and 1 are still too far from 10 and 11.
+        let segments = segment(&leaves, &adjacent, 13.0);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[1].mean, 35.0 / 3.0);
+
+        assert_eq!(segment(&leaves, &adjacent, 0.0).len(), 5);
+        assert_eq!(segment(&leaves, &adjacent, 1000.0).len(), 1);
+    }
+
+    #[test]
+    fn only_adjacent() {
+        // two leaves of the same color, that are not adjacent.
+        let leaves = [leaf(0, 1.0), leaf(1, 5.0), leaf(2, 1.0)];
+        let segments = segment(&leaves, &[(0, 1), (1, 2)], 1.0);
+        assert_eq!(segments.len(), 3);
+        let segments = segment(&leaves, &[(0, 1), (1, 2), (2, 0)], 1.0);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].regions.len(), 2);
+    }
+}
@@ -0,0 +1,529 @@
+//! A compact, versioned bitstream for region quadtrees.
+//!
+//! Layout, all integers little endian:
+//!
+//! - 4 bytes magic: `QTR` followed by the format version, currently 1.
+//! - width and height of the image, as u32.
+//! - 1 byte: bits per color channel, from 1 to 8. Fewer than 8 quantizes
+//!   the leaf colors, which makes the format lossy.
+//! - 1 byte flags. Bit 0 set means the leaf colors are delta coded. Bit 1
+//!   set means the branches are written with their cuts.
+//! - the tree, as a bitstream in pre-order: per node a single bit, 1 for
+//!   a branch followed by its children, 0 for a leaf followed by its
+//!   color. The regions of the nodes follow from the size of the image.
+//!
+//! Without cuts, every branch has four children, its quadrants. With cuts,
+//! the bit of each branch is followed by 2 bits for how it is cut: 0 into
+//! four quadrants, followed by the width of the left and the height of
+//! the top ones, 1 into a left and a right part, followed by the width of
+//! the left one, and 2 into a top and a bottom part, followed by the height
+//! of the top one. Each of these is written as the difference with half
+//! the width or height of the branch, zigzag mapped and written as an
+//! exponential-Golomb code. Cuts are only written if any branch is not cut
+//! into its quadrants, so a tree that is takes no more space than before.
+//!
+//! A color is four channels, red, green, blue and alpha, each quantized to
+//! the given number of bits. Without delta coding each channel is written
+//! as is. With delta coding each channel is written as the difference with
+//! the same channel of the previous leaf, zigzag mapped to an unsigned
+//! number and written as an exponential-Golomb code, so that similar
+//! neighbouring leaves take only a few bits.
+
+use crate::{image::Rgba, region::Region, region_quad_tree::RegionQuadTree, split_strategy::Cut};
+
+const MAGIC: &[u8; 3] = b"QTR";
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 14;
+const FLAG_DELTA: u8 = 1;
+const FLAG_CUTS: u8 = 2;
+/// The most pixels an encoded image can have, 4096 by 4096, so that
+/// decoding a header that claims a huge size fails instead of running out
+/// of memory.
+const MAX_PIXELS: usize = 1 << 24;
+/// The fewest bits a leaf takes: its node bit, and a bit per channel.
+const MIN_LEAF_BITS: usize = 5;
+
+/// The most levels a decoded tree of an image of the given size can have,
+/// so that a crafted chain of thin cuts can't overflow the stack. Each
+/// level of an encoded tree cuts at least a quarter off the length of an
+/// axis, see `SplitStrategy`, which takes less than three levels per bit.
+fn max_depth(width: usize, height: usize) -> usize {
+    let bits = |length: usize| (usize::BITS - length.leading_zeros()) as usize;
+    3 * (bits(width) + bits(height))
+}
+
+/// Map a signed number to an unsigned one, with small magnitudes to small
+/// numbers: 0, -1, 1, -2, ... to 0, 1, 2, 3, ...
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+/// The inverse of zigzag.
+fn unzigzag(value: u32) -> i32 {
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+/// Writes bits, most significant first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit: u32,
+}
+
+impl BitWriter {
+    fn new(bytes: Vec<u8>) -> BitWriter {
+        BitWriter { bytes, bit: 0 }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.bit == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.bit;
+        }
+        self.bit = (self.bit + 1) % 8;
+    }
+
+    /// Write the lowest count bits of value.
+    fn write_bits(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    /// Write value as an order 0 exponential-Golomb code.
+    fn write_exp_golomb(&mut self, value: u32) {
+        let value = value + 1;
+        let length = 32 - value.leading_zeros();
+        self.write_bits(0, length - 1);
+        self.write_bits(value, length);
+    }
+}
+
+/// Reads bits written by a BitWriter.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.position / 8)?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u32;
+        }
+        Some(value)
+    }
+
+    fn read_exp_golomb(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while !self.read_bit()? {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        let rest = self.read_bits(zeros)?;
+        Some(((1 << zeros) | rest) - 1)
+    }
+
+    /// Whether all the bytes have been read, apart from the padding
+    /// bits in the last one.
+    fn is_done(&self) -> bool {
+        self.position.div_ceil(8) == self.bytes.len()
+    }
+}
+
+/// The quantization and coding of the leaf colors.
+#[derive(Debug, Clone, Copy)]
+struct ColorCoding {
+    bits: u32,
+    delta: bool,
+}
+
+impl ColorCoding {
+    fn channels(color: &Rgba) -> [u8; 4] {
+        [color.red, color.green, color.blue, color.alpha]
+    }
+
+    fn quantize(&self, channel: u8) -> u32 {
+        channel as u32 >> (8 - self.bits)
+    }
+
+    /// The channel value in the middle of the range that quantizes to
+    /// the given value, with 0 and the maximum mapping to 0 and 255.
+    fn dequantize(&self, value: u32) -> u8 {
+        let max = (1 << self.bits) - 1;
+        ((value * 255 + max / 2) / max) as u8
+    }
+
+    fn write(&self, writer: &mut BitWriter, color: &Rgba, previous: &mut [u32; 4]) {
+        for (c, channel) in ColorCoding::channels(color).into_iter().enumerate() {
+            let value = self.quantize(channel);
+            if self.delta {
+                writer.write_exp_golomb(zigzag(value as i32 - previous[c] as i32));
+            } else {
+                writer.write_bits(value, self.bits);
+            }
+            previous[c] = value;
+        }
+    }
+
+    fn read(&self, reader: &mut BitReader, previous: &mut [u32; 4]) -> Option<Rgba> {
+        let mut channels = [0; 4];
+        for c in 0..4 {
+            let value = if self.delta {
+                let value = previous[c] as i32 + unzigzag(reader.read_exp_golomb()?);
+                if !(0..1 << self.bits).contains(&value) {
+                    return None;
+                }
+                value as u32
+            } else {
+                reader.read_bits(self.bits)?
+            };
+            previous[c] = value;
+            channels[c] = self.dequantize(value);
+        }
+        Some(Rgba::from_slice(&channels))
+    }
+}
+
+/// The regions of the children of a branch.
+fn child_regions(children: &[RegionQuadTree]) -> Vec<Region> {
+    children.iter().map(|c| c.region()).collect()
+}
+
+/// Whether any branch of the tree is not cut into its quadrants.
+fn has_cuts(tree: &RegionQuadTree) -> bool {
+    match tree {
+        RegionQuadTree::Leaf(..) => false,
+        RegionQuadTree::Branch(children) => {
+            Cut::of(&child_regions(children)) != Cut::midpoint(&tree.region())
+                || children.iter().any(has_cuts)
+        }
+    }
+}
+
+/// Write a cut length, as the difference with half the given length.
+fn write_length(writer: &mut BitWriter, cut: usize, length: usize) {
+    writer.write_exp_golomb(zigzag(cut as i32 - (length / 2) as i32));
+}
+
+/// Read a cut length written by write_length, which must leave two
+/// non-empty parts of the given length.
+fn read_length(reader: &mut BitReader, length: usize) -> Option<usize> {
+    let cut = (length / 2) as i64 + unzigzag(reader.read_exp_golomb()?) as i64;
+    (1..length as i64).contains(&cut).then_some(cut as usize)
+}
+
+fn write_cut(writer: &mut BitWriter, cut: Cut, region: &Region) {
+    match cut {
+        Cut::Quad {
+            left_width,
+            top_height,
+        } => {
+            writer.write_bits(0, 2);
+            write_length(writer, left_width, region.width);
+            write_length(writer, top_height, region.height);
+        }
+        Cut::Vertical(left_width) => {
+            writer.write_bits(1, 2);
+            write_length(writer, left_width, region.width);
+        }
+        Cut::Horizontal(top_height) => {
+            writer.write_bits(2, 2);
+            write_length(writer, top_height, region.height);
+        }
+    }
+}
+
+fn read_cut(reader: &mut BitReader, region: &Region) -> Option<Cut> {
+    Some(match reader.read_bits(2)? {
+        0 => Cut::Quad {
+            left_width: read_length(reader, region.width)?,
+            top_height: read_length(reader, region.height)?,
+        },
+        1 => Cut::Vertical(read_length(reader, region.width)?),
+        2 => Cut::Horizontal(read_length(reader, region.height)?),
+        _ => return None,
+    })
+}
+
+fn write_node(
+    tree: &RegionQuadTree,
+    writer: &mut BitWriter,
+    coding: ColorCoding,
+    cuts: bool,
+    previous: &mut [u32; 4],
+) {
+    match tree {
+        RegionQuadTree::Leaf(_, color) => {
+            writer.write_bit(false);
+            coding.write(writer, color, previous);
+        }
+        RegionQuadTree::Branch(children) => {
+            writer.write_bit(true);
+            if cuts {
+                write_cut(writer, Cut::of(&child_regions(children)), &tree.region());
+            }
+            for child in children {
+                write_node(child, writer, coding, cuts, previous);
+            }
+        }
+    }
+}
+
+/// Read a node and its children. leaves is the number of leaves that may
+/// still be read and depth the number of levels below this node, and None
+/// is returned if there are more.
+fn read_node(
+    region: Region,
+    reader: &mut BitReader,
+    coding: ColorCoding,
+    cuts: bool,
+    previous: &mut [u32; 4],
+    leaves: &mut usize,
+    depth: usize,
+) -> Option<RegionQuadTree> {
+    if !reader.read_bit()? {
+        *leaves = leaves.checked_sub(1)?;
+        let color = coding.read(reader, previous)?;
+        return Some(RegionQuadTree::Leaf(region, color));
+    }
+    let children = if cuts {
+        read_cut(reader, &region)?.children(&region)
+    } else {
+        // a region this small can't have been split into quadrants.
+        if region.width < 2 || region.height < 2 {
+            return None;
+        }
+        region.quadrants().to_vec()
+    };
+    let depth = depth.checked_sub(1)?;
+    children
+        .into_iter()
+        .map(|child| read_node(child, reader, coding, cuts, previous, leaves, depth))
+        .collect::<Option<_>>()
+        .map(RegionQuadTree::Branch)
+}
+
+/// Encode the quadtree, which covers the whole image.
+pub(crate) fn encode(tree: &RegionQuadTree, color_bits: u8, delta: bool) -> Vec<u8> {
+    assert!(
+        (1..=8).contains(&color_bits),
+        "color bits must be between 1 and 8, not {color_bits}"
+    );
+    let region = tree.region();
+    let mut bytes = Vec::with_capacity(HEADER_LENGTH);
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    bytes.extend_from_slice(&(region.width as u32).to_le_bytes());
+    bytes.extend_from_slice(&(region.height as u32).to_le_bytes());
+    bytes.push(color_bits);
+    let cuts = has_cuts(tree);
+    let mut flags = 0;
+    if delta {
+        flags |= FLAG_DELTA;
+    }
+    if cuts {
+        flags |= FLAG_CUTS;
+    }
+    bytes.push(flags);
+
+    let coding = ColorCoding {
+        bits: color_bits as u32,
+        delta,
+    };
+    let mut writer = BitWriter::new(bytes);
+    write_node(tree, &mut writer, coding, cuts, &mut [0; 4]);
+    writer.bytes
+}
+
+/// Decode a quadtree encoded with `encode`. None if the bytes are not
+/// a valid encoding.
+pub(crate) fn decode(bytes: &[u8]) -> Option<RegionQuadTree> {
+    if bytes.len() < HEADER_LENGTH || &bytes[0..3] != MAGIC || bytes[3] != VERSION {
+        return None;
+    }
+    let width = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+    let height = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+    let color_bits = bytes[12];
+    let flags = bytes[13];
+    if width == 0
+        || height == 0
+        || width
+            .checked_mul(height)
+            .is_none_or(|pixels| pixels > MAX_PIXELS)
+        || !(1..=8).contains(&color_bits)
+        || flags & !(FLAG_DELTA | FLAG_CUTS) != 0
+    {
+        return None;
+    }
+
+    let coding = ColorCoding {
+        bits: color_bits as u32,
+        delta: flags & FLAG_DELTA != 0,
+    };
+    let region = Region {
+        x: 0,
+        y: 0,
+        width,
+        height,
+    };
+    let tree_bytes = &bytes[HEADER_LENGTH..];
+    // every leaf has at least one pixel, and takes some bits.
+    let mut leaves = (width * height).min(tree_bytes.len() * 8 / MIN_LEAF_BITS);
+    let mut reader = BitReader::new(tree_bytes);
+    let cuts = flags & FLAG_CUTS != 0;
+    let depth = max_depth(width, height);
+    let previous = &mut [0; 4];
+    let tree = read_node(
+        region,
+        &mut reader,
+        coding,
+        cuts,
+        previous,
+        &mut leaves,
+        depth,
+    )?;
+    reader.is_done().then_some(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits() {
+        let mut writer = BitWriter::new(vec![]);
+        writer.write_bit(true);
+        writer.write_bits(0b0110, 4);
+        for value in [0, 1, 2, 3, 7, 8, 1000, u32::MAX - 1] {
+            writer.write_exp_golomb(value);
+        }
+        let bytes = writer.bytes;
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read_bit(), Some(true));
+        assert_eq!(reader.read_bits(4), Some(0b0110));
+        for value in [0, 1, 2, 3, 7, 8, 1000, u32::MAX - 1] {
+            assert_eq!(reader.read_exp_golomb(), Some(value));
+        }
+        assert!(reader.is_done());
+    }
+
+    #[test]
+    fn exp_golomb_is_short_for_small_values() {
+        let mut writer = BitWriter::new(vec![]);
+        for _ in 0..8 {
+            writer.write_exp_golomb(0);
+        }
+        assert_eq!(writer.bytes, vec![0xff]);
+    }
+
+    #[test]
+    fn quantization() {
+        let coding = ColorCoding {
+            bits: 4,
+            delta: false,
+        };
+        assert_eq!(coding.quantize(255), 15);
+        assert_eq!(coding.dequantize(15), 255);
+        assert_eq!(coding.dequantize(0), 0);
+        for channel in 0..=255 {
+            let roundtrip = coding.dequantize(coding.quantize(channel));
+            assert!(roundtrip.abs_diff(channel) <= 16);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let tree = RegionQuadTree::Leaf(
+            Region {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1,
+            },
+            Rgba::new(),
+        );
+        let bytes = encode(&tree, 8, false);
+        assert!(decode(&bytes).is_some());
+        assert!(decode(&bytes[..bytes.len() - 1]).is_none());
+        assert!(decode(&[bytes.as_slice(), &[0]].concat()).is_none());
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[3] = 2;
+        assert!(decode(&wrong_version).is_none());
+
+        let mut wrong_bits = bytes.clone();
+        wrong_bits[12] = 9;
+        assert!(decode(&wrong_bits).is_none());
+
+        // a 1x1 region that claims to be split.
+        let mut split = bytes.clone();
+        split[HEADER_LENGTH] |= 0x80;
+        assert!(decode(&split).is_none());
+
+        // more leaves than the bytes can hold: a 4x4 image split in four,
+        // with only room for one leaf.
+        let mut crowded = bytes[..HEADER_LENGTH].to_vec();
+        crowded[4..12].copy_from_slice(&[4, 0, 0, 0, 4, 0, 0, 0]);
+        crowded.extend_from_slice(&[0x80, 0, 0, 0, 0]);
+        let mut leaves = 1;
+        let mut reader = BitReader::new(&crowded[HEADER_LENGTH..]);
+        let region = Region {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 4,
+        };
+        let coding = ColorCoding {
+            bits: 8,
+            delta: false,
+        };
+        let previous = &mut [0; 4];
+        assert!(read_node(region, &mut reader, coding, false, previous, &mut leaves, 4).is_none());
+        assert!(decode(&crowded).is_none());
+
+        // a 2x1 region cut into a left and a right half.
+        let leaf = |x| {
+            let region = Region {
+                x,
+                y: 0,
+                width: 1,
+                height: 1,
+            };
+            RegionQuadTree::Leaf(region, Rgba::new())
+        };
+        let tree = RegionQuadTree::Branch(vec![leaf(0), leaf(1)]);
+        let bytes = encode(&tree, 8, false);
+        assert_eq!(bytes[13], FLAG_CUTS);
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.region(), tree.region());
+        // cut across the height of 1 instead, which leaves an empty half.
+        let mut across = bytes.clone();
+        across[HEADER_LENGTH] ^= 0x60;
+        assert!(decode(&across).is_none());
+
+        // a 64x1 region that cuts off one column at a time, far deeper
+        // than any split strategy goes.
+        let chain = (0..63).rev().fold(leaf(63), |rest, x| {
+            RegionQuadTree::Branch(vec![leaf(x), rest])
+        });
+        assert_eq!(chain.region().width, 64);
+        let bytes = encode(&chain, 8, false);
+        assert!(decode(&bytes).is_none());
+    }
+}
@@ -0,0 +1,293 @@
+//! Where a region quadtree splits a leaf. By default a leaf is split at
+//! its midpoint into four quadrants, but on images with strong horizontal
+//! or vertical structure, like the edges of buildings or the horizon, a
+//! cut that follows the structure needs far fewer leaves for the same
+//! error.
+//!
+//! The non-uniform strategies choose their cuts to minimize the weighted
+//! squared deviation of the children from their mean colors, whatever the
+//! error metric of the tree: from the summed-area table, each candidate
+//! cut takes constant time.
+
+use crate::{pixel::Pixel, region::Region, summed_area_table::SummedAreaTable};
+
+/// How a region quadtree splits a leaf into its children.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SplitStrategy {
+    /// Into four quadrants of (almost) equal size.
+    #[default]
+    Midpoint,
+    /// Into four quadrants, cut at the x where cutting the leaf in a left
+    /// and a right part leaves the least squared deviation, and likewise
+    /// at the y for a top and a bottom part.
+    VarianceCut,
+    /// Into two halves, kd-tree style: cut across the axis along which
+    /// the colors change the most, or the longer one if they change as
+    /// much either way.
+    Binary,
+}
+
+/// The names of the split strategies, as accepted by `SplitStrategy::by_name`.
+pub const SPLIT_STRATEGY_NAMES: [&str; 3] = ["midpoint", "variance_cut", "binary"];
+
+impl SplitStrategy {
+    /// The split strategy with the given name, one of `SPLIT_STRATEGY_NAMES`.
+    pub fn by_name(name: &str) -> Option<SplitStrategy> {
+        match name {
+            "midpoint" => Some(SplitStrategy::Midpoint),
+            "variance_cut" => Some(SplitStrategy::VarianceCut),
+            "binary" => Some(SplitStrategy::Binary),
+            _ => None,
+        }
+    }
+
+    /// The number of children a leaf is split into.
+    pub(crate) fn branching(self) -> usize {
+        match self {
+            SplitStrategy::Midpoint | SplitStrategy::VarianceCut => 4,
+            SplitStrategy::Binary => 2,
+        }
+    }
+
+    /// Whether a region can be split, if it is only split across sides
+    /// longer than the given minimum length, which is at least 1: across
+    /// both sides into quadrants, or across either side into halves.
+    pub(crate) fn can_split(self, region: &Region, min_region_length: usize) -> bool {
+        let (width, height) = (region.width, region.height);
+        match self {
+            SplitStrategy::Midpoint | SplitStrategy::VarianceCut => {
+                width > min_region_length && height > min_region_length
+            }
+            SplitStrategy::Binary => width > min_region_length || height > min_region_length,
+        }
+    }
+
+    /// Where to cut the given region of the image of the table, which
+    /// `can_split` with the given minimum length.
+    pub(crate) fn cut<P: Pixel>(
+        self,
+        table: &SummedAreaTable<P>,
+        region: &Region,
+        min_region_length: usize,
+    ) -> Cut {
+        let Region {
+            x,
+            y,
+            width,
+            height,
+        } = *region;
+        let vertical = |left: usize| {
+            table.squared_deviation(x, y, left, height)
+                + table.squared_deviation(x + left, y, width - left, height)
+        };
+        let horizontal = |top: usize| {
+            table.squared_deviation(x, y, width, top)
+                + table.squared_deviation(x, y + top, width, height - top)
+        };
+        match self {
+            SplitStrategy::Midpoint => Cut::midpoint(region),
+            SplitStrategy::VarianceCut => Cut::Quad {
+                left_width: best_cut(width, vertical),
+                top_height: best_cut(height, horizontal),
+            },
+            SplitStrategy::Binary if height <= min_region_length => Cut::Vertical(width / 2),
+            SplitStrategy::Binary if width <= min_region_length => Cut::Horizontal(height / 2),
+            SplitStrategy::Binary => {
+                let (across_x, across_y) = (vertical(width / 2), horizontal(height / 2));
+                if nearly_equal(across_x, across_y) {
+                    if width >= height {
+                        Cut::Vertical(width / 2)
+                    } else {
+                        Cut::Horizontal(height / 2)
+                    }
+                } else if across_x < across_y {
+                    Cut::Vertical(width / 2)
+                } else {
+                    Cut::Horizontal(height / 2)
+                }
+            }
+        }
+    }
+}
+
+/// Whether two sums of squared deviations are equal, up to rounding.
+fn nearly_equal(a: f64, b: f64) -> bool {
+    (a - b).abs() <= 1e-9 * a.max(b).max(1.0)
+}
+
+/// The length of the first part of the cut of the given length in two
+/// non-empty parts with the least deviation, the cut nearest the middle
+/// if several are as good. The middle if the length can't be cut.
+///
+/// Only cuts in the middle half are considered, so that each part is at
+/// most three quarters of the length, and a tree of variance cuts stays
+/// within a few levels per bit of the length.
+fn best_cut(length: usize, deviation: impl Fn(usize) -> f64) -> usize {
+    let middle = length / 2;
+    if length < 2 {
+        return middle;
+    }
+    let margin = (length / 4).max(1);
+    let deviations: Vec<_> = (margin..=length - margin)
+        .map(|cut| (cut, deviation(cut)))
+        .collect();
+    let least = deviations.iter().map(|(_, d)| *d).fold(f64::MAX, f64::min);
+    deviations
+        .into_iter()
+        .filter(|(_, d)| nearly_equal(*d, least))
+        .min_by_key(|(cut, _)| cut.abs_diff(middle))
+        .map_or(middle, |(cut, _)| cut)
+}
+
+/// Where a branch cuts its region into its children.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Cut {
+    /// Into top left, bottom left, top right and bottom right quadrants,
+    /// with the given width of the left and height of the top ones.
+    Quad {
+        left_width: usize,
+        top_height: usize,
+    },
+    /// Into a left and a right part, with the given width of the left one.
+    Vertical(usize),
+    /// Into a top and a bottom part, with the given height of the top one.
+    Horizontal(usize),
+}
+
+impl Cut {
+    /// The cut of the given region into its quadrants, see
+    /// `Region::quadrants`.
+    pub(crate) fn midpoint(region: &Region) -> Cut {
+        Cut::Quad {
+            left_width: region.width / 2,
+            top_height: region.height / 2,
+        }
+    }
+
+    /// The regions of the children of the given region, in order.
+    pub(crate) fn children(self, region: &Region) -> Vec<Region> {
+        match self {
+            Cut::Quad {
+                left_width,
+                top_height,
+            } => region.split_at(left_width, top_height).to_vec(),
+            Cut::Vertical(left_width) => {
+                let [left, _, right, _] = region.split_at(left_width, region.height);
+                vec![left, right]
+            }
+            Cut::Horizontal(top_height) => {
+                let [top, bottom, _, _] = region.split_at(region.width, top_height);
+                vec![top, bottom]
+            }
+        }
+    }
+
+    /// The cut that gives children with the given regions, which are the
+    /// regions of the children of a branch, in order.
+    pub(crate) fn of(children: &[Region]) -> Cut {
+        match children {
+            [left, right] if left.y == right.y && left.height == right.height => {
+                Cut::Vertical(left.width)
+            }
+            [top, _] => Cut::Horizontal(top.height),
+            [top_left, ..] => Cut::Quad {
+                left_width: top_left.width,
+                top_height: top_left.height,
+            },
+            [] => unreachable!("a branch always has children"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{image::Image, pixel::Luma};
+
+    #[test]
+    fn cuts() {
+        // a bright row at y = 1 and a bright column at x = 4, in a 6x5 image.
+        let pixels = (0..6 * 5)
+            .map(|i| Luma(if i / 6 == 1 || i % 6 == 4 { 200u8 } else { 10 }))
+            .collect();
+        let table = SummedAreaTable::new(&Image::new(6, 5, pixels));
+        let region = Region {
+            x: 0,
+            y: 0,
+            width: 6,
+            height: 5,
+        };
+        assert_eq!(
+            SplitStrategy::Midpoint.cut(&table, &region, 1),
+            Cut::Quad {
+                left_width: 3,
+                top_height: 2
+            }
+        );
+        let Cut::Quad {
+            left_width,
+            top_height,
+        } = SplitStrategy::VarianceCut.cut(&table, &region, 1)
+        else {
+            panic!("a variance cut is a quad cut");
+        };
+        assert!(left_width == 4 || left_width == 5);
+        assert!(top_height == 1 || top_height == 2);
+        assert!(matches!(
+            SplitStrategy::Binary.cut(&table, &region, 1),
+            Cut::Vertical(3) | Cut::Horizontal(2)
+        ));
+
+        // a uniform region is cut in the middle, across the longer axis.
+        let uniform = SummedAreaTable::new(&Image::new(6, 5, vec![Luma(7u8); 30]));
+        assert_eq!(
+            SplitStrategy::VarianceCut.cut(&uniform, &region, 1),
+            Cut::midpoint(&region)
+        );
+        assert_eq!(
+            SplitStrategy::Binary.cut(&uniform, &region, 1),
+            Cut::Vertical(3)
+        );
+
+        // a bright column at the edge is not cut off on its own, so that
+        // each part is at most three quarters of the length.
+        let edge = (0..8).map(|i| Luma(if i == 7 { 200u8 } else { 10 }));
+        let edge = SummedAreaTable::new(&Image::new(8, 1, edge.collect()));
+        assert_eq!(
+            best_cut(8, |cut| edge.squared_deviation(0, 0, cut, 1)
+                + edge.squared_deviation(cut, 0, 8 - cut, 1)),
+            6
+        );
+    }
+
+    #[test]
+    fn children() {
+        let region = Region {
+            x: 2,
+            y: 3,
+            width: 7,
+            height: 4,
+        };
+        for cut in [
+            Cut::midpoint(&region),
+            Cut::Quad {
+                left_width: 1,
+                top_height: 3,
+            },
+            Cut::Vertical(5),
+            Cut::Horizontal(1),
+        ] {
+            let children = cut.children(&region);
+            let pixels: usize = children.iter().map(|c| c.width * c.height).sum();
+            assert_eq!(pixels, region.width * region.height);
+            assert!(children.iter().all(|c| region.contains_region(c)));
+            assert_eq!(Cut::of(&children), cut);
+        }
+        assert_eq!(Cut::midpoint(&region).children(&region), region.quadrants());
+        assert_eq!(
+            SplitStrategy::by_name("binary"),
+            Some(SplitStrategy::Binary)
+        );
+        assert_eq!(SplitStrategy::by_name("kd"), None);
+    }
+}
@@ -0,0 +1,421 @@
+use std::marker::PhantomData;
+
+use crate::{
+    image::{Image, Rgba},
+    linear_fit::LinearFit,
+    pixel::Pixel,
+};
+
+/// A summed-area table, or integral image. For every position, holds
+/// the sum of the values and the sum of the squared values of each
+/// channel in the rectangle from the top left of the image up to,
+/// but not including, that position. With those, the mean and mean
+/// squared error of any rectangle in the image take constant time.
+///
+/// The sums are kept as f64, which is exact for integer channels as long
+/// as the sums of squares stay below 2^53: for images of up to about
+/// 10^11 8 bit pixels, or 2 * 10^6 16 bit pixels.
+///
+/// Optionally, also holds the moments: the sums of the values times their
+/// x and times their y coordinate, with which the least-squares linear fit
+/// of any rectangle takes constant time as well.
+#[derive(Debug, Clone)]
+pub struct SummedAreaTable<P: Pixel = Rgba> {
+    width: usize,
+    height: usize,
+    /// The sums of each position, channel by channel.
+    sums: Vec<f64>,
+    squares: Vec<f64>,
+    /// The sums of x times the values and of y times the values.
+    moments: Option<(Vec<f64>, Vec<f64>)>,
+    pixel: PhantomData<P>,
+}
+
+impl<P: Pixel> SummedAreaTable<P> {
+    /// Build the table for the given image, in one pass.
+    pub fn new(image: &Image<P>) -> SummedAreaTable<P> {
+        SummedAreaTable::build(image, false)
+    }
+
+    /// Build the table for the given image with its moments, for linear
+    /// fits. Takes twice the memory of a table without.
+    pub fn with_moments(image: &Image<P>) -> SummedAreaTable<P> {
+        SummedAreaTable::build(image, true)
+    }
+
+    fn build(image: &Image<P>, with_moments: bool) -> SummedAreaTable<P> {
+        let (width, height) = (image.width(), image.height());
+        let channels = P::CHANNELS;
+        let len = if with_moments { 4 } else { 2 };
+        // the sum, square, x moment and y moment tables.
+        let mut tables = vec![vec![0.0; (width + 1) * (height + 1) * channels]; len];
+        let mut row = vec![vec![0.0; channels]; len];
+        for y in 0..height {
+            row.iter_mut().for_each(|r| r.fill(0.0));
+            for x in 0..width {
+                let pixel = image.get_pixel(x, y);
+                let above = (y * (width + 1) + x + 1) * channels;
+                let index = above + (width + 1) * channels;
+                for c in 0..channels {
+                    let value = pixel.channel(c);
+                    let terms = [value, value * value, x as f64 * value, y as f64 * value];
+                    for (t, term) in terms.into_iter().take(len).enumerate() {
+                        row[t][c] += term;
+                        tables[t][index + c] = tables[t][above + c] + row[t][c];
+                    }
+                }
+            }
+        }
+        let mut tables = tables.into_iter();
+        let (sums, squares) = (tables.next().unwrap(), tables.next().unwrap());
+        SummedAreaTable {
+            width,
+            height,
+            sums,
+            squares,
+            moments: tables.next().zip(tables.next()),
+            pixel: PhantomData,
+        }
+    }
+
+    /// Whether the table has moments, see `with_moments`.
+    pub fn has_moments(&self) -> bool {
+        self.moments.is_some()
+    }
+
+    /// The sum of the given channel of the given table, over the given rectangle.
+    fn rect_sum(
+        &self,
+        table: &[f64],
+        channel: usize,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> f64 {
+        let stride = self.width + 1;
+        let at = |x, y| table[(y * stride + x) * P::CHANNELS + channel];
+        at(x + width, y + height) + at(x, y) - at(x + width, y) - at(x, y + height)
+    }
+
+    /// The mean squared error of the given channel of the pixels in the given
+    /// rectangle, with respect to the given value.
+    fn rect_mse(
+        &self,
+        channel: usize,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        value: f64,
+    ) -> f32 {
+        let sum = self.rect_sum(&self.sums, channel, x, y, width, height);
+        let square = self.rect_sum(&self.squares, channel, x, y, width, height);
+        let len = (width * height) as f64;
+        // sum of (p - m)^2 = sum of p^2 - 2 m sum of p + n m^2
+        let squared_error = square - 2.0 * value * sum + len * value * value;
+        (squared_error.max(0.0) / len) as f32
+    }
+
+    /// The mean color of the given rectangle. The same as `Pixel::mean`
+    /// of all the pixels in the rectangle.
+    pub fn mean(&self, x: usize, y: usize, width: usize, height: usize) -> P {
+        debug_assert!(x + width <= self.width && y + height <= self.height);
+        let len = (width * height) as f64;
+        let channels: Vec<_> = (0..P::CHANNELS)
+            .map(|c| self.rect_sum(&self.sums, c, x, y, width, height) / len)
+            .collect();
+        P::from_channels(&channels)
+    }
+
+    /// The mean squared error of each channel of the pixels in the given
+    /// rectangle with respect to the given color, in channel order.
+    pub fn channel_mse(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        color: &P,
+    ) -> Vec<f32> {
+        (0..P::CHANNELS)
+            .map(|c| self.rect_mse(c, x, y, width, height, color.channel(c)))
+            .collect()
+    }
+
+    /// The mean squared error of the pixels in the given rectangle with
+    /// respect to the given color, with the channels weighted as in
+    /// `Pixel::squared_error`. For Rgba the same as `Rgba::mse` of all the
+    /// pixels in the rectangle, up to rounding.
+    pub fn mse(&self, x: usize, y: usize, width: usize, height: usize, color: &P) -> f32 {
+        (0..P::CHANNELS)
+            .map(|c| P::WEIGHTS[c] as f32 * self.rect_mse(c, x, y, width, height, color.channel(c)))
+            .sum()
+    }
+
+    /// The weighted sum of the squared deviations of the pixels in the given
+    /// rectangle from their mean, weighted as in `mse`. 0 for an empty
+    /// rectangle.
+    pub fn squared_deviation(&self, x: usize, y: usize, width: usize, height: usize) -> f64 {
+        let len = (width * height) as f64;
+        if len == 0.0 {
+            return 0.0;
+        }
+        (0..P::CHANNELS)
+            .map(|c| {
+                let sum = self.rect_sum(&self.sums, c, x, y, width, height);
+                let square = self.rect_sum(&self.squares, c, x, y, width, height);
+                P::WEIGHTS[c] * (square - sum * sum / len).max(0.0)
+            })
+            .sum()
+    }
+
+    /// The least-squares linear fit of each channel of the pixels in the
+    /// given rectangle, or None if the table has no moments.
+    pub fn linear_fit(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Option<LinearFit<P>> {
+        let (x_moments, y_moments) = self.moments.as_ref()?;
+        let len = (width * height) as f64;
+        let center = (
+            x as f64 + (width as f64 - 1.0) / 2.0,
+            y as f64 + (height as f64 - 1.0) / 2.0,
+        );
+        let (xx, yy) = centered_squares(width, height);
+        let channels = (0..P::CHANNELS)
+            .map(|c| {
+                if len == 0.0 {
+                    return [0.0; 3];
+                }
+                let sum = self.rect_sum(&self.sums, c, x, y, width, height);
+                let xv = self.rect_sum(x_moments, c, x, y, width, height) - center.0 * sum;
+                let yv = self.rect_sum(y_moments, c, x, y, width, height) - center.1 * sum;
+                let slope = |moment: f64, squares: f64| {
+                    if squares > 0.0 {
+                        moment / squares
+                    } else {
+                        0.0
+                    }
+                };
+                [sum / len, slope(xv, xx), slope(yv, yy)]
+            })
+            .collect();
+        Some(LinearFit::new(center, channels))
+    }
+
+    /// The mean squared error of each channel of the pixels in the given
+    /// rectangle with respect to the given linear fit, in channel order.
+    /// None if the table has no moments.
+    pub fn fit_channel_mse(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        fit: &LinearFit<P>,
+    ) -> Option<Vec<f32>> {
+        let (x_moments, y_moments) = self.moments.as_ref()?;
+        let len = (width * height) as f64;
+        let center = (
+            x as f64 + (width as f64 - 1.0) / 2.0,
+            y as f64 + (height as f64 - 1.0) / 2.0,
+        );
+        let (xx, yy) = centered_squares(width, height);
+        let (fit_x, fit_y) = fit.center();
+        let channels = (0..P::CHANNELS)
+            .map(|c| {
+                // the fit as f = value + a dx + b dy from the center of the
+                // rectangle. Over a rectangle dx, dy and dx dy sum to 0, so
+                // the sum of f^2 and of p f have no cross terms.
+                let [value, a, b] = fit.coefficients(c);
+                let value = value + a * (center.0 - fit_x) + b * (center.1 - fit_y);
+                let sum = self.rect_sum(&self.sums, c, x, y, width, height);
+                let square = self.rect_sum(&self.squares, c, x, y, width, height);
+                let xv = self.rect_sum(x_moments, c, x, y, width, height) - center.0 * sum;
+                let yv = self.rect_sum(y_moments, c, x, y, width, height) - center.1 * sum;
+                let fit_squares = len * value * value + a * a * xx + b * b * yy;
+                let product = value * sum + a * xv + b * yv;
+                let squared_error = square - 2.0 * product + fit_squares;
+                (squared_error.max(0.0) / len) as f32
+            })
+            .collect();
+        Some(channels)
+    }
+}
+
+/// The sums over a rectangle of the squared distances along x and along y
+/// of each pixel from the center.
+fn centered_squares(width: usize, height: usize) -> (f64, f64) {
+    let (w, h) = (width as f64, height as f64);
+    (h * w * (w * w - 1.0) / 12.0, w * h * (h * h - 1.0) / 12.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel::Luma;
+
+    fn test_image(width: usize, height: usize) -> Image {
+        let mut pixels = vec![];
+        for i in 0..width * height {
+            pixels.push(Rgba {
+                red: (i * 7 % 256) as u8,
+                green: (i * 13 % 256) as u8,
+                blue: (i * i % 256) as u8,
+                alpha: (255 - i % 256) as u8,
+            });
+        }
+
+        Image::new(width, height, pixels)
+    }
+
+    fn rect_pixels(image: &Image, x: usize, y: usize, width: usize, height: usize) -> Vec<Rgba> {
+        let mut pixels = vec![];
+        for i in x..x + width {
+            for j in y..y + height {
+                pixels.push(image.get_pixel(i, j));
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn same_as_pixels() {
+        let image = test_image(13, 9);
+        let table = SummedAreaTable::new(&image);
+        for (x, y, width, height) in [(0, 0, 13, 9), (0, 0, 1, 1), (12, 8, 1, 1), (3, 2, 7, 5)] {
+            let pixels = rect_pixels(&image, x, y, width, height);
+            let mean = table.mean(x, y, width, height);
+            assert_eq!(Ok(mean), Pixel::mean(&pixels));
+
+            let expected = mean.mse(&pixels);
+            let actual = table.mse(x, y, width, height, &mean);
+            assert!((expected - actual).abs() <= 1e-3 * expected.max(1.0));
+        }
+    }
+
+    #[test]
+    fn squared_deviation() {
+        let image = test_image(5, 4);
+        let table = SummedAreaTable::new(&image);
+        for (x, y, width, height) in [(0, 0, 5, 4), (1, 2, 3, 2), (4, 0, 1, 4)] {
+            let pixels = rect_pixels(&image, x, y, width, height);
+            let len = pixels.len() as f64;
+            let expected: f64 = (0..Rgba::CHANNELS)
+                .map(|c| {
+                    let mean = pixels.iter().map(|p| p.channel(c)).sum::<f64>() / len;
+                    let deviation: f64 = pixels.iter().map(|p| (p.channel(c) - mean).powi(2)).sum();
+                    Rgba::WEIGHTS[c] * deviation
+                })
+                .sum();
+            let actual = table.squared_deviation(x, y, width, height);
+            assert!((expected - actual).abs() <= 1e-6 * expected.max(1.0));
+        }
+        assert_eq!(table.squared_deviation(2, 2, 0, 1), 0.0);
+    }
+
+    #[test]
+    fn linear_fits() {
+        // a plane in red, and a plane with noise in green.
+        let mut pixels = vec![];
+        for y in 0..9 {
+            for x in 0..13 {
+                pixels.push(Rgba {
+                    red: (10 + 7 * x + 3 * y) as u8,
+                    green: (100 + 2 * x + (x * y) % 5) as u8,
+                    blue: 50,
+                    alpha: 255,
+                });
+            }
+        }
+        let image = Image::new(13, 9, pixels);
+        assert!(SummedAreaTable::new(&image)
+            .linear_fit(0, 0, 2, 2)
+            .is_none());
+        let table = SummedAreaTable::with_moments(&image);
+        assert!(table.has_moments());
+        assert_eq!(
+            table.mean(0, 0, 13, 9),
+            SummedAreaTable::new(&image).mean(0, 0, 13, 9)
+        );
+
+        for (x, y, width, height) in [(0, 0, 13, 9), (3, 2, 7, 5), (4, 4, 1, 3), (12, 8, 1, 1)] {
+            let fit = table.linear_fit(x, y, width, height).unwrap();
+            assert_eq!(fit.mean(), table.mean(x, y, width, height));
+            let mse = table.fit_channel_mse(x, y, width, height, &fit).unwrap();
+            // the red plane fits exactly, blue and alpha are flat.
+            assert!(mse[0] < 1e-6 && mse[2] < 1e-6 && mse[3] < 1e-6, "{mse:?}");
+            for j in y..y + height {
+                for i in x..x + width {
+                    let expected = (10 + 7 * i + 3 * j) as f64;
+                    assert!((fit.value(0, i, j) - expected).abs() < 1e-9);
+                }
+            }
+
+            // the fit is least-squares: nudging it only makes it worse, and
+            // the error is the same as computed pixel by pixel.
+            let mut expected = 0.0;
+            for j in y..y + height {
+                for i in x..x + width {
+                    let green = image.get_pixel(i, j).green as f64;
+                    expected += (green - fit.value(1, i, j)).powi(2);
+                }
+            }
+            expected /= (width * height) as f64;
+            assert!((mse[1] as f64 - expected).abs() < 1e-3 * expected.max(1.0));
+            let [value, a, b] = fit.coefficients(1);
+            let nudged = LinearFit::new(
+                fit.center(),
+                vec![
+                    [0.0; 3],
+                    [value + 0.1, a - 0.1, b + 0.1],
+                    [0.0; 3],
+                    [0.0; 3],
+                ],
+            );
+            let nudged_mse = table.fit_channel_mse(x, y, width, height, &nudged).unwrap();
+            assert!(nudged_mse[1] > mse[1]);
+        }
+
+        // a fit of another rectangle, with another center.
+        let fit = table.linear_fit(0, 0, 13, 9).unwrap();
+        let mse = table.fit_channel_mse(3, 2, 7, 5, &fit).unwrap();
+        assert!(mse[0] < 1e-6);
+    }
+
+    #[test]
+    fn uniform_region() {
+        let image = Image::new(
+            2,
+            2,
+            vec![
+                Rgba {
+                    red: 255,
+                    green: 255,
+                    blue: 255,
+                    alpha: 255
+                };
+                4
+            ],
+        );
+        let table = SummedAreaTable::new(&image);
+        let mean = table.mean(0, 0, 2, 2);
+        assert_eq!(mean, image.get_pixel(0, 0));
+        assert_eq!(table.mse(0, 0, 2, 2, &mean), 0.0);
+    }
+
+    #[test]
+    fn grayscale_16_bit() {
+        let pixels: Vec<_> = (0..12u16).map(|i| Luma(i * 5000)).collect();
+        let image = Image::new(4, 3, pixels.clone());
+        let table = SummedAreaTable::new(&image);
+        let mean = table.mean(0, 0, 4, 3);
+        assert_eq!(Ok(mean), Pixel::mean(&pixels));
+        let expected: f64 = pixels.iter().map(|p| p.squared_error(&mean)).sum::<f64>() / 12.0;
+        assert!((table.mse(0, 0, 4, 3, &mean) as f64 - expected).abs() < 1e-3 * expected);
+    }
+}
@@ -0,0 +1,141 @@
+//! Rendering the partition of a region quadtree as SVG: one rectangle per
+//! leaf, filled with its mean color. Unlike the result image, it scales to
+//! any size, and opens in any browser or vector editor.
+
+use std::fmt::Write;
+
+use crate::{image::Rgba, region::Region};
+
+/// An outline around every leaf.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stroke {
+    pub color: Rgba,
+    /// The width of the outline, in pixels of the image.
+    pub width: f64,
+}
+
+/// Options for `RegionQuadTreeImage::to_svg`. The default has no outlines
+/// and leaves out no leaves.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SvgOptions {
+    pub stroke: Option<Stroke>,
+    /// Leave out the leaves whose width and height are both less than this,
+    /// for a smaller file. The mean color of the whole image shows instead.
+    pub min_size: usize,
+}
+
+/// The color as an SVG fill or stroke attribute, with its opacity if it is
+/// not opaque.
+fn color_attributes(name: &str, color: &Rgba) -> String {
+    let Rgba {
+        red,
+        green,
+        blue,
+        alpha,
+    } = color;
+    let mut attributes = format!("{name}=\"#{red:02x}{green:02x}{blue:02x}\"");
+    if *alpha != 255 {
+        write!(
+            attributes,
+            " {name}-opacity=\"{:.3}\"",
+            *alpha as f64 / 255.0
+        )
+        .unwrap();
+    }
+    attributes
+}
+
+/// An SVG document of the given size with a rectangle for each leaf, on
+/// top of a rectangle with the background color if any leaves are left out.
+pub(crate) fn render<'a>(
+    width: usize,
+    height: usize,
+    background: &Rgba,
+    leaves: impl IntoIterator<Item = (&'a Region, &'a Rgba)>,
+    options: &SvgOptions,
+) -> String {
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+         viewBox=\"0 0 {width} {height}\" shape-rendering=\"crispEdges\">\n"
+    );
+    if options.min_size > 1 {
+        writeln!(
+            svg,
+            "<rect width=\"{width}\" height=\"{height}\" {}/>",
+            color_attributes("fill", background)
+        )
+        .unwrap();
+    }
+    match &options.stroke {
+        Some(stroke) => writeln!(
+            svg,
+            "<g {} stroke-width=\"{}\">",
+            color_attributes("stroke", &stroke.color),
+            stroke.width
+        ),
+        None => writeln!(svg, "<g>"),
+    }
+    .unwrap();
+    for (region, color) in leaves {
+        if region.width < options.min_size && region.height < options.min_size {
+            continue;
+        }
+        writeln!(
+            svg,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" {}/>",
+            region.x,
+            region.y,
+            region.width,
+            region.height,
+            color_attributes("fill", color)
+        )
+        .unwrap();
+    }
+    svg.push_str("</g>\n</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: usize, y: usize, width: usize, height: usize) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn rects() {
+        let red = Rgba::from_slice(&[255, 0, 0, 255]);
+        let translucent = Rgba::from_slice(&[0, 16, 255, 51]);
+        let leaves = [(region(0, 0, 2, 4), red), (region(2, 0, 1, 1), translucent)];
+        let leaves = leaves.iter().map(|(r, c)| (r, c));
+
+        let svg = render(4, 4, &red, leaves.clone(), &SvgOptions::default());
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"4\""));
+        assert!(
+            svg.contains("<g>\n<rect x=\"0\" y=\"0\" width=\"2\" height=\"4\" fill=\"#ff0000\"/>")
+        );
+        assert!(svg.contains(
+            "<rect x=\"2\" y=\"0\" width=\"1\" height=\"1\" fill=\"#0010ff\" fill-opacity=\"0.200\"/>"
+        ));
+        assert!(svg.ends_with("</g>\n</svg>\n"));
+
+        let options = SvgOptions {
+            stroke: Some(Stroke {
+                color: Rgba::from_slice(&[0, 0, 0, 255]),
+                width: 0.5,
+            }),
+            min_size: 2,
+        };
+        let svg = render(4, 4, &red, leaves, &options);
+        assert!(svg.contains("<g stroke=\"#000000\" stroke-width=\"0.5\">"));
+        // the small leaf is left out, and the background shows instead.
+        assert_eq!(svg.matches("<rect").count(), 2);
+        assert!(svg.contains("<rect width=\"4\" height=\"4\" fill=\"#ff0000\"/>"));
+    }
+}
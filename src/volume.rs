@@ -0,0 +1,160 @@
+use crate::{
+    error::QuadtreeError,
+    image::Image,
+    pixel::{Luma, Pixel},
+};
+
+/// A 3D grid of voxels, for example a stack of CT slices. Voxels are
+/// stored slice by slice from front to back, each slice row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Volume<P: Pixel = Luma<u16>> {
+    width: usize,
+    height: usize,
+    depth: usize,
+    voxels: Vec<P>,
+}
+
+impl<P: Pixel> Volume<P> {
+    pub(crate) fn empty(width: usize, height: usize, depth: usize) -> Volume<P> {
+        Volume {
+            width,
+            height,
+            depth,
+            voxels: vec![P::zero(); width * height * depth],
+        }
+    }
+
+    fn get_index(&self, x: usize, y: usize, z: usize) -> usize {
+        (z * self.height + y) * self.width + x
+    }
+
+    pub(crate) fn set_index(&mut self, x: usize, y: usize, z: usize, voxel: P) {
+        let index = self.get_index(x, y, z);
+        self.voxels[index] = voxel;
+    }
+
+    /// Create a volume from its voxels, slice by slice. An error if the
+    /// volume is empty or there are fewer voxels than width times height
+    /// times depth.
+    pub fn from_voxels(
+        width: usize,
+        height: usize,
+        depth: usize,
+        mut voxels: Vec<P>,
+    ) -> Result<Volume<P>, QuadtreeError> {
+        if width == 0 || height == 0 || depth == 0 {
+            return Err(QuadtreeError::EmptyVolume);
+        }
+        let len = width.saturating_mul(height).saturating_mul(depth);
+        if voxels.len() < len {
+            return Err(QuadtreeError::BufferTooSmall {
+                expected: len,
+                actual: voxels.len(),
+            });
+        }
+        voxels.truncate(len);
+        Ok(Volume {
+            width,
+            height,
+            depth,
+            voxels,
+        })
+    }
+
+    /// Stack the given slices from front to back into a volume. An error if
+    /// there are none, or they are not all the same size.
+    pub fn from_slices(slices: &[Image<P>]) -> Result<Volume<P>, QuadtreeError> {
+        let first = slices.first().ok_or(QuadtreeError::EmptyVolume)?;
+        let (width, height) = (first.width(), first.height());
+        let mut voxels = Vec::with_capacity(width * height * slices.len());
+        for (index, slice) in slices.iter().enumerate() {
+            if slice.width() != width || slice.height() != height {
+                return Err(QuadtreeError::SliceSizeMismatch { index });
+            }
+            voxels.extend_from_slice(slice.pixels());
+        }
+        Volume::from_voxels(width, height, slices.len(), voxels)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The voxels, slice by slice.
+    pub fn voxels(&self) -> &[P] {
+        &self.voxels
+    }
+
+    /// The voxel at (x, y, z), which must be inside the volume.
+    pub fn get_voxel(&self, x: usize, y: usize, z: usize) -> P {
+        self.voxels[self.get_index(x, y, z)]
+    }
+
+    /// The slice at the given z, as an image. An error if z is past the
+    /// back of the volume.
+    pub fn slice(&self, z: usize) -> Result<Image<P>, QuadtreeError> {
+        if z >= self.depth {
+            return Err(QuadtreeError::SliceOutOfRange {
+                z,
+                depth: self.depth,
+            });
+        }
+        let start = self.get_index(0, 0, z);
+        let pixels = self.voxels[start..start + self.width * self.height].to_vec();
+        Ok(Image::new(self.width, self.height, pixels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slices_roundtrip() {
+        let voxels: Vec<_> = (0..24u16).map(Luma).collect();
+        let volume = Volume::from_voxels(4, 3, 2, voxels).unwrap();
+        assert_eq!(volume.get_voxel(1, 2, 1), Luma(21));
+        let slices = [volume.slice(0).unwrap(), volume.slice(1).unwrap()];
+        assert_eq!(slices[1].get_pixel(1, 2), Luma(21));
+        assert_eq!(Volume::from_slices(&slices), Ok(volume.clone()));
+        assert_eq!(
+            volume.slice(2),
+            Err(QuadtreeError::SliceOutOfRange { z: 2, depth: 2 })
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            Volume::<f32>::from_voxels(2, 2, 0, vec![]),
+            Err(QuadtreeError::EmptyVolume)
+        );
+        assert_eq!(
+            Volume::from_voxels(2, 2, 2, vec![0.0f32; 7]),
+            Err(QuadtreeError::BufferTooSmall {
+                expected: 8,
+                actual: 7
+            })
+        );
+        assert_eq!(
+            Volume::<f32>::from_slices(&[]),
+            Err(QuadtreeError::EmptyVolume)
+        );
+        let slices = [
+            Image::new(2, 2, vec![0.0f32; 4]),
+            Image::new(2, 1, vec![0.0; 2]),
+        ];
+        assert_eq!(
+            Volume::from_slices(&slices),
+            Err(QuadtreeError::SliceSizeMismatch { index: 1 })
+        );
+    }
+}
@@ -6,6 +6,8 @@ const HAL_IMAGE = "hal.jpg";
 const canvas = document.getElementById("image-canvas");
 const ctx = canvas.getContext('2d');
 const img = document.getElementById("original");
+const overlay = document.getElementById("overlay");
+const BORDER_RGBA = new Uint8Array([0, 0, 0, 255]);
 
 
 const getOriginalImageData = (img, sw, sh) => {
@@ -25,42 +27,34 @@ const drawQuadTree = (error, length) => {
     const image = qt.Image.from_image_data(imageData.data, imageData.width, imageData.height);
     const tree = qt.RegionQuadTreeImage.new(image);
     tree.subdivide_until(error, length);
-    const subdivided = tree.get_result_image();
+    const subdivided = overlay.value === "result" || overlay.value === "outlines"
+        ? tree.get_result_image()
+        : tree.get_overlay_image(overlay.value, BORDER_RGBA, 1);
     const subdividedImageData = ctx.createImageData(imageData);
     subdivided.to_image_data(subdividedImageData.data);
     ctx.putImageData(subdividedImageData, 0, 0);
-}
-
-const nextLowerPowerOf4 = (n) => {
-    let start = 1;
-    while (start < n) {
-        start *= 4;
+    if (overlay.value === "outlines") {
+        // x, y, width, height, color and depth of each leaf.
+        const leaves = tree.leaves();
+        ctx.strokeStyle = "black";
+        ctx.lineWidth = 1;
+        for (let i = 0; i < leaves.length; i += 6) {
+            ctx.strokeRect(leaves[i] + 0.5, leaves[i + 1] + 0.5, leaves[i + 2], leaves[i + 3]);
+        }
     }
-    return start;
-}
-
-const cropSize = (width, height) => nextLowerPowerOf4(width*height);
-
-const adjustmentLeftRight = (length, size) => {
-    const target = Math.sqrt(size);
-    const diff = target - length;
-    return [diff, target];
 }
 
 const drawCompleteQuadTree = (level) => {
     ctx.clearRect(0, 0, canvas.width, canvas.height);
-    const cSize = cropSize(img.width, img.height);
-    let [sx, sw] = adjustmentLeftRight(img.width, cSize);
-    let [sy, sh] = adjustmentLeftRight(img.height, cSize);
-    canvas.width = sw;
-    canvas.height = sh;
-    const imageData = getOriginalImageData(img, sw, sh);
+    canvas.width = img.width;
+    canvas.height = img.height;
+    const imageData = getOriginalImageData(img, img.width, img.height);
     const image = qt.Image.from_image_data(imageData.data, imageData.width, imageData.height);
     const tree = qt.QuadTree.new(image);
     const blurry = tree.image_at_level(level);
     const blurryImageData = ctx.createImageData(imageData);
     blurry.to_image_data(blurryImageData.data);
-    ctx.putImageData(blurryImageData, sx,sy);
+    ctx.putImageData(blurryImageData, 0, 0);
 }
 
 const onRegionQuadTree = () => {
@@ -91,6 +85,11 @@ const onRegionQuadTree = () => {
         errorOutput.innerHTML = "error: " + this.value;
         drawQuadTree(this.value, lengthSlider.value);
     }
+
+    overlay.style.display = '';
+    overlay.onchange = function() {
+        drawQuadTree(errorSlider.value, lengthSlider.value);
+    }
 }
 
 const onCompleteQuadTree = () => {
@@ -117,10 +116,106 @@ const onCompleteQuadTree = () => {
     }
 }
 
+// A disk of bodies orbiting a heavy one in the middle, each simulation step
+// builds a Barnes-Hut tree and moves the bodies by its accelerations.
+const NBODY_SIZE = 600;
+const NBODY_COUNT = 2000;
+const NBODY_STEP = 0.05;
+const NBODY_SOFTENING = 2;
+let nbodyFrame = null;
+
+const createBodies = () => {
+    const center = NBODY_SIZE / 2;
+    const centralMass = 20000;
+    const positions = new Float64Array(NBODY_COUNT * 2);
+    const velocities = new Float64Array(NBODY_COUNT * 2);
+    const masses = new Float64Array(NBODY_COUNT).fill(1);
+    masses[0] = centralMass;
+    positions[0] = positions[1] = center;
+    for (let i = 1; i < NBODY_COUNT; i++) {
+        const angle = Math.random() * 2 * Math.PI;
+        const radius = 20 + Math.random() * (center - 60);
+        positions[2 * i] = center + radius * Math.cos(angle);
+        positions[2 * i + 1] = center + radius * Math.sin(angle);
+        // a circular orbit around the central body.
+        const speed = Math.sqrt(centralMass / radius);
+        velocities[2 * i] = -speed * Math.sin(angle);
+        velocities[2 * i + 1] = speed * Math.cos(angle);
+    }
+    return { positions, velocities, masses };
+}
+
+const stepBodies = (bodies, theta) => {
+    const tree = qt.BarnesHut.new(bodies.positions, bodies.masses);
+    const accelerations = tree.accelerations(theta, NBODY_SOFTENING);
+    for (let i = 0; i < bodies.positions.length; i++) {
+        bodies.velocities[i] += accelerations[i] * NBODY_STEP;
+        bodies.positions[i] += bodies.velocities[i] * NBODY_STEP;
+    }
+    return tree;
+}
+
+const drawBodies = (bodies, tree) => {
+    ctx.fillStyle = "black";
+    ctx.fillRect(0, 0, canvas.width, canvas.height);
+    ctx.strokeStyle = "rgba(0, 128, 255, 0.3)";
+    const regions = tree.leaf_regions();
+    for (let i = 0; i < regions.length; i += 4) {
+        ctx.strokeRect(regions[i], regions[i + 1], regions[i + 2], regions[i + 3]);
+    }
+    ctx.fillStyle = "white";
+    for (let i = 0; i < bodies.positions.length; i += 2) {
+        ctx.fillRect(bodies.positions[i], bodies.positions[i + 1], 1.5, 1.5);
+    }
+}
+
+const onBarnesHut = () => {
+    img.style.display = 'none';
+    canvas.width = NBODY_SIZE;
+    canvas.height = NBODY_SIZE;
+
+    var thetaSlider = document.getElementById("minLength");
+    thetaSlider.style.display = 'block';
+    thetaSlider.min = 0;
+    thetaSlider.max = 150;
+    thetaSlider.value = 50;
+    var thetaOutput = document.getElementById("minlengthval");
+    thetaOutput.innerHTML = "theta: " + thetaSlider.value / 100;
+    var errorSlider = document.getElementById("error");
+    errorSlider.style.display = 'none';
+    var errorOutput = document.getElementById("errorval");
+    errorOutput.innerHTML = "";
+
+    thetaSlider.oninput = function() {
+        thetaOutput.innerHTML = "theta: " + this.value / 100;
+    }
+
+    const bodies = createBodies();
+    const animate = () => {
+        const start = performance.now();
+        const tree = stepBodies(bodies, thetaSlider.value / 100);
+        drawBodies(bodies, tree);
+        tree.free();
+        const millis = (performance.now() - start).toFixed(1);
+        errorOutput.innerHTML = NBODY_COUNT + " bodies, " + millis + " ms per step";
+        nbodyFrame = requestAnimationFrame(animate);
+    };
+    nbodyFrame = requestAnimationFrame(animate);
+}
+
 const reactToHash = () => {
+    if (nbodyFrame !== null) {
+        cancelAnimationFrame(nbodyFrame);
+        nbodyFrame = null;
+    }
+    img.style.display = '';
+    overlay.style.display = 'none';
     if (window.location.hash === "#complete") {
         onCompleteQuadTree();
     }
+    else if (window.location.hash === "#nbody") {
+        onBarnesHut();
+    }
     else {
         onRegionQuadTree(); 
     }